@@ -0,0 +1,9 @@
+-- Add roles to users
+CREATE TYPE user_role AS ENUM ('listener', 'artist', 'admin');
+
+ALTER TABLE users ADD COLUMN role user_role NOT NULL DEFAULT 'listener';
+
+-- Promote the first account ever registered so there is an initial admin.
+-- Fresh installs can instead set ADMIN_EMAIL, which is applied at startup.
+UPDATE users SET role = 'admin'
+WHERE id = (SELECT id FROM users ORDER BY created_at ASC LIMIT 1);
@@ -0,0 +1,17 @@
+-- TOTP two-factor authentication
+-- The secret is AES-256-GCM encrypted; it exists but is not enforced until
+-- enrollment is confirmed and mfa_enabled_at is set.
+ALTER TABLE users ADD COLUMN mfa_secret_encrypted TEXT;
+ALTER TABLE users ADD COLUMN mfa_enabled_at TIMESTAMPTZ;
+-- Last accepted TOTP time step, so a code can't be replayed within its window
+ALTER TABLE users ADD COLUMN mfa_last_used_step BIGINT;
+
+CREATE TABLE mfa_recovery_codes (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR(64) NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_mfa_recovery_codes_user_id ON mfa_recovery_codes(user_id);
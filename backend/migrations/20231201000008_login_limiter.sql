@@ -0,0 +1,30 @@
+-- Failed login attempts inside the current sliding window, per limiter key
+-- (e.g. `email:someone@example.com` or `ip:203.0.113.7`)
+CREATE TABLE login_attempts (
+    key VARCHAR(320) NOT NULL,
+    attempted_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_login_attempts_key ON login_attempts(key, attempted_at);
+
+CREATE TABLE login_lockouts (
+    key VARCHAR(320) PRIMARY KEY,
+    locked_until TIMESTAMPTZ NOT NULL,
+    lockouts INTEGER NOT NULL DEFAULT 0,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Security-relevant events such as failed logins and lockouts
+CREATE TABLE security_events (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    event_type VARCHAR(50) NOT NULL,
+    user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    email VARCHAR(100),
+    ip_address VARCHAR(45),
+    user_agent TEXT,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_security_events_user_id ON security_events(user_id);
+CREATE INDEX idx_security_events_created_at ON security_events(created_at);
@@ -0,0 +1,4 @@
+-- Editable profile fields. avatar_path is relative to UPLOAD_DIR.
+ALTER TABLE users ADD COLUMN display_name VARCHAR(100);
+ALTER TABLE users ADD COLUMN bio TEXT;
+ALTER TABLE users ADD COLUMN avatar_path VARCHAR(255);
@@ -0,0 +1,31 @@
+-- Usernames become unique regardless of case. Existing clashes keep the
+-- oldest account's name; later ones get a suffix from their id.
+UPDATE users u SET username = LEFT(u.username, 41) || '_' || LEFT(u.id::text, 8)
+WHERE EXISTS (
+    SELECT 1 FROM users other
+    WHERE LOWER(other.username) = LOWER(u.username)
+      AND (other.created_at, other.id) < (u.created_at, u.id)
+);
+
+CREATE UNIQUE INDEX idx_users_username_lower ON users(LOWER(username));
+
+CREATE TABLE follows (
+    follower_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    followee_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (follower_id, followee_id),
+    CHECK (follower_id <> followee_id)
+);
+
+CREATE INDEX idx_follows_followee_id ON follows(followee_id);
+
+-- A block also removes any follow in either direction (done by the app)
+CREATE TABLE blocks (
+    blocker_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    blocked_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (blocker_id, blocked_id),
+    CHECK (blocker_id <> blocked_id)
+);
+
+CREATE INDEX idx_blocks_blocked_id ON blocks(blocked_id);
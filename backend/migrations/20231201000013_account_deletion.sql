@@ -0,0 +1,12 @@
+-- Self-service account deletion. deleted_at starts the grace period; once
+-- purge_after has passed the purge job removes the account for good.
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN purge_after TIMESTAMPTZ;
+
+CREATE INDEX idx_users_purge_after ON users(purge_after) WHERE purge_after IS NOT NULL;
+
+-- Who uploaded each song, so exports and purges can find them. Songs
+-- uploaded before this column existed have no known uploader.
+ALTER TABLE songs ADD COLUMN uploaded_by UUID REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE INDEX idx_songs_uploaded_by ON songs(uploaded_by);
@@ -0,0 +1,41 @@
+-- Append-only audit log of who did what. It replaces security_events,
+-- whose rows are carried over. actor_id and target_id are deliberately not
+-- foreign keys: entries must outlive the rows they mention.
+CREATE TABLE audit_events (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    actor_id UUID,
+    actor_email VARCHAR(100),
+    action VARCHAR(50) NOT NULL,
+    target_type VARCHAR(50),
+    target_id UUID,
+    ip_address VARCHAR(45),
+    user_agent TEXT,
+    metadata JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO audit_events (id, actor_id, actor_email, action, ip_address, user_agent, metadata, created_at)
+SELECT id, user_id, email, event_type, ip_address, user_agent, details, created_at
+FROM security_events;
+
+DROP TABLE security_events;
+
+CREATE INDEX idx_audit_events_created_at ON audit_events(created_at);
+CREATE INDEX idx_audit_events_actor_id ON audit_events(actor_id, created_at);
+CREATE INDEX idx_audit_events_action ON audit_events(action, created_at);
+CREATE INDEX idx_audit_events_target ON audit_events(target_type, target_id);
+
+-- Rows can never be changed. They can only be deleted by the account purge,
+-- which opts in with SET LOCAL app.allow_audit_purge = 'on'.
+CREATE FUNCTION audit_events_append_only() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND current_setting('app.allow_audit_purge', true) = 'on' THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_append_only
+    BEFORE UPDATE OR DELETE ON audit_events
+    FOR EACH ROW EXECUTE FUNCTION audit_events_append_only();
@@ -0,0 +1,7 @@
+-- Filled from the uploaded file's embedded tags unless the uploader set them
+ALTER TABLE songs
+    ADD COLUMN track_number INTEGER,
+    ADD COLUMN year INTEGER,
+    ADD COLUMN genre VARCHAR(100);
+
+CREATE INDEX idx_songs_genre ON songs(genre);
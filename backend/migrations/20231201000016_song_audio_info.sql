@@ -0,0 +1,8 @@
+-- Measured by decoding the uploaded file. NULL only for rows that predate
+-- probing and haven't been backfilled yet (`backfill-audio-info`).
+ALTER TABLE songs
+    ADD COLUMN duration_ms INTEGER,
+    ADD COLUMN codec VARCHAR(32),
+    ADD COLUMN sample_rate INTEGER,
+    ADD COLUMN channels INTEGER,
+    ADD COLUMN bitrate INTEGER;
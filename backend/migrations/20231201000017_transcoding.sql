@@ -0,0 +1,20 @@
+-- Lower-bitrate renditions of each upload, produced in the background
+CREATE TYPE stream_quality AS ENUM ('low', 'normal', 'high', 'original');
+CREATE TYPE transcode_status AS ENUM ('pending', 'processing', 'ready', 'failed');
+
+-- Existing songs start out pending and are picked up by the worker
+ALTER TABLE songs
+    ADD COLUMN transcode_status transcode_status NOT NULL DEFAULT 'pending',
+    ADD COLUMN transcode_error TEXT,
+    ADD COLUMN transcode_started_at TIMESTAMPTZ,
+    -- Qualities that finished; a rendition is skipped when the original
+    -- isn't any better than it
+    ADD COLUMN renditions stream_quality[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX idx_songs_transcode_queue ON songs(created_at) WHERE transcode_status IN ('pending', 'processing');
+
+CREATE TABLE user_preferences (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    stream_quality stream_quality NOT NULL DEFAULT 'original',
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
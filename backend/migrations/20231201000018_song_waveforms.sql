@@ -0,0 +1,3 @@
+-- Precomputed min/max peaks for waveform scrubbers, stored as a JSON file
+-- beside the audio. NULL until the song has been analysed.
+ALTER TABLE songs ADD COLUMN waveform_path TEXT;
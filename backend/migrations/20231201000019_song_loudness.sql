@@ -0,0 +1,13 @@
+-- EBU R128 measurements for volume normalization. NULL until the song has
+-- been analysed; integrated loudness stays NULL for silence.
+ALTER TABLE songs
+    ADD COLUMN loudness_lufs DOUBLE PRECISION,
+    ADD COLUMN loudness_range_lu DOUBLE PRECISION,
+    ADD COLUMN true_peak_dbtp DOUBLE PRECISION,
+    -- Gated block powers, merged across an album's songs to measure the album
+    ADD COLUMN loudness_histogram JSONB,
+    ADD COLUMN album_loudness_lufs DOUBLE PRECISION,
+    ADD COLUMN album_true_peak_dbtp DOUBLE PRECISION;
+
+-- An album is one uploader's songs sharing an album name
+CREATE INDEX idx_songs_uploader_album ON songs(uploaded_by, album);
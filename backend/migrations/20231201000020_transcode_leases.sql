@@ -0,0 +1,7 @@
+-- A worker holds a lease on the song it transcodes and renews it while
+-- ffmpeg runs, so a dead worker's job can be told apart from a slow one
+ALTER TABLE songs
+    ADD COLUMN transcode_lease UUID,
+    ADD COLUMN transcode_heartbeat_at TIMESTAMPTZ,
+    -- Reset by a requeue; a job that keeps killing its worker is given up on
+    ADD COLUMN transcode_attempts INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- An album cover is stored once and shared by the album's songs; this
+-- finds the songs still using a cover before it is deleted
+CREATE INDEX idx_songs_cover_art ON songs(cover_art) WHERE cover_art IS NOT NULL;
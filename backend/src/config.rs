@@ -1,21 +1,64 @@
+use ipnet::IpNet;
+use sha2::{Digest, Sha256};
 use std::env;
+use std::sync::Arc;
+
+use crate::jwt_keys::{JwtKeys, PurposeKeys};
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub database_url: String,
-    pub jwt_secret: String,
+    pub jwt_keys: Arc<JwtKeys>,
+    pub purpose_keys: Arc<PurposeKeys>,
     pub host: String,
     pub port: u16,
     pub upload_dir: String,
+    pub app_url: String,
+    pub mail_dir: String,
+    pub mail_from: String,
+    pub require_email_verification: bool,
+    pub admin_email: Option<String>,
+    pub mfa_issuer: String,
+    pub mfa_encryption_key: Vec<u8>,
+    pub login_limiter: String,
+    pub login_max_attempts: u32,
+    pub login_max_attempts_per_ip: u32,
+    pub login_window_seconds: i64,
+    pub login_lockout_seconds: i64,
+    pub login_max_lockout_seconds: i64,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub oidc_providers: Vec<OidcProviderConfig>,
+    pub oidc_redirect_url: String,
+    pub account_deletion_grace_days: i64,
+    pub ffmpeg_path: String,
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+// One OpenID Connect identity provider, configured as OIDC_<NAME>_*
+#[derive(Clone, Debug)]
+pub struct OidcProviderConfig {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: String,
 }
 
 impl Config {
     pub fn from_env() -> Self {
+        let jwt_secret = env::var("JWT_SECRET")
+            .expect("JWT_SECRET must be set");
+        let app_url = env::var("APP_URL")
+            .unwrap_or_else(|_| "http://localhost:3000".to_string());
+
         Self {
             database_url: env::var("DATABASE_URL")
                 .expect("DATABASE_URL must be set"),
-            jwt_secret: env::var("JWT_SECRET")
-                .expect("JWT_SECRET must be set"),
+            mfa_encryption_key: Self::mfa_encryption_key(&jwt_secret),
+            jwt_keys: Arc::new(Self::jwt_keys(&jwt_secret)),
+            purpose_keys: Arc::new(PurposeKeys::derive(&jwt_secret)),
             host: env::var("HOST")
                 .unwrap_or_else(|_| "127.0.0.1".to_string()),
             port: env::var("PORT")
@@ -24,6 +67,131 @@ impl Config {
                 .expect("PORT must be a valid number"),
             upload_dir: env::var("UPLOAD_DIR")
                 .unwrap_or_else(|_| "./uploads".to_string()),
+            oidc_redirect_url: env::var("OIDC_REDIRECT_URL")
+                .unwrap_or_else(|_| format!("{}/oidc/callback", app_url)),
+            app_url,
+            mail_dir: env::var("MAIL_DIR")
+                .unwrap_or_else(|_| "./mail".to_string()),
+            mail_from: env::var("MAIL_FROM")
+                .unwrap_or_else(|_| "no-reply@spotify-clone.local".to_string()),
+            require_email_verification: env::var("REQUIRE_EMAIL_VERIFICATION")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
+            admin_email: env::var("ADMIN_EMAIL").ok(),
+            mfa_issuer: env::var("MFA_ISSUER")
+                .unwrap_or_else(|_| "Spotify Clone".to_string()),
+            // "memory" for a single instance, "postgres" to share state between instances
+            login_limiter: env::var("LOGIN_LIMITER")
+                .unwrap_or_else(|_| "memory".to_string()),
+            login_max_attempts: env::var("LOGIN_MAX_ATTEMPTS")
+                .unwrap_or_else(|_| "5".to_string())
+                .parse()
+                .expect("LOGIN_MAX_ATTEMPTS must be a valid number"),
+            login_max_attempts_per_ip: env::var("LOGIN_MAX_ATTEMPTS_PER_IP")
+                .unwrap_or_else(|_| "20".to_string())
+                .parse()
+                .expect("LOGIN_MAX_ATTEMPTS_PER_IP must be a valid number"),
+            login_window_seconds: env::var("LOGIN_WINDOW_SECONDS")
+                .unwrap_or_else(|_| "900".to_string())
+                .parse()
+                .expect("LOGIN_WINDOW_SECONDS must be a valid number"),
+            login_lockout_seconds: env::var("LOGIN_LOCKOUT_SECONDS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .expect("LOGIN_LOCKOUT_SECONDS must be a valid number"),
+            login_max_lockout_seconds: env::var("LOGIN_MAX_LOCKOUT_SECONDS")
+                .unwrap_or_else(|_| "3600".to_string())
+                .parse()
+                .expect("LOGIN_MAX_LOCKOUT_SECONDS must be a valid number"),
+            // Defaults follow the OWASP argon2id baseline (19 MiB, 2 passes, 1 lane)
+            argon2_memory_kib: env::var("ARGON2_MEMORY_KIB")
+                .unwrap_or_else(|_| "19456".to_string())
+                .parse()
+                .expect("ARGON2_MEMORY_KIB must be a valid number"),
+            argon2_iterations: env::var("ARGON2_ITERATIONS")
+                .unwrap_or_else(|_| "2".to_string())
+                .parse()
+                .expect("ARGON2_ITERATIONS must be a valid number"),
+            argon2_parallelism: env::var("ARGON2_PARALLELISM")
+                .unwrap_or_else(|_| "1".to_string())
+                .parse()
+                .expect("ARGON2_PARALLELISM must be a valid number"),
+            oidc_providers: Self::oidc_providers(),
+            account_deletion_grace_days: env::var("ACCOUNT_DELETION_GRACE_DAYS")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse()
+                .expect("ACCOUNT_DELETION_GRACE_DAYS must be a valid number"),
+            // Transcoding is skipped (songs stay pending) when this can't be run
+            ffmpeg_path: env::var("FFMPEG_PATH")
+                .unwrap_or_else(|_| "ffmpeg".to_string()),
+            trusted_proxies: Self::trusted_proxies(),
+        }
+    }
+
+    // JWT_SECRET still signs access tokens unless JWT_ALGORITHM selects a key pair
+    fn jwt_keys(jwt_secret: &str) -> JwtKeys {
+        let algorithm = env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string());
+        let key_dir = env::var("JWT_KEY_DIR").unwrap_or_else(|_| "./keys".to_string());
+        let signing_kid = env::var("JWT_SIGNING_KID").ok();
+
+        JwtKeys::load(&algorithm, jwt_secret, &key_dir, signing_kid.as_deref())
+            .unwrap_or_else(|e| panic!("Failed to load JWT keys: {}", e))
+    }
+
+    // OIDC_PROVIDERS=corp,google reads OIDC_CORP_ISSUER, OIDC_CORP_CLIENT_ID, ...
+    fn oidc_providers() -> Vec<OidcProviderConfig> {
+        let names = env::var("OIDC_PROVIDERS").unwrap_or_default();
+
+        names
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let prefix = format!("OIDC_{}", name.to_uppercase());
+                let var = |key: &str| env::var(format!("{}_{}", prefix, key));
+
+                OidcProviderConfig {
+                    issuer: var("ISSUER")
+                        .unwrap_or_else(|_| panic!("{}_ISSUER must be set", prefix)),
+                    client_id: var("CLIENT_ID")
+                        .unwrap_or_else(|_| panic!("{}_CLIENT_ID must be set", prefix)),
+                    client_secret: var("CLIENT_SECRET").ok(),
+                    scopes: var("SCOPES")
+                        .unwrap_or_else(|_| "openid email profile".to_string()),
+                    name,
+                }
+            })
+            .collect()
+    }
+
+    // TRUSTED_PROXIES=10.0.0.0/8,192.168.1.5 lists the proxies whose
+    // X-Forwarded-For / Forwarded headers are believed. Empty by default, so
+    // the client address is the peer of the TCP connection.
+    fn trusted_proxies() -> Vec<IpNet> {
+        env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<std::net::IpAddr>().map(IpNet::from))
+                    .unwrap_or_else(|_| panic!("TRUSTED_PROXIES entry {:?} is not an IP address or CIDR range", entry))
+            })
+            .collect()
+    }
+
+    // MFA_ENCRYPTION_KEY is 32 bytes in hex. Without it a key is derived from
+    // JWT_SECRET, which means rotating that secret makes enrolled TOTP unusable.
+    fn mfa_encryption_key(jwt_secret: &str) -> Vec<u8> {
+        match env::var("MFA_ENCRYPTION_KEY") {
+            Ok(key) => {
+                let key = hex::decode(key.trim()).expect("MFA_ENCRYPTION_KEY must be hex");
+                assert_eq!(key.len(), 32, "MFA_ENCRYPTION_KEY must be 32 bytes");
+                key
+            }
+            Err(_) => Sha256::digest(format!("{}:mfa-encryption", jwt_secret).as_bytes()).to_vec(),
         }
     }
 }
@@ -0,0 +1,316 @@
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits, Rgb, RgbImage};
+use uuid::Uuid;
+
+use crate::utils::image_extension;
+
+/// The square renditions made of every cover, by edge length in pixels.
+pub const COVER_SIZES: [u32; 3] = [64, 300, 640];
+// Avatars are only ever shown small, so one rendition is enough
+const AVATAR_MAX_SIZE: u32 = 512;
+// Checked before decoding so a small file can't claim a huge canvas
+const MAX_COVER_DIMENSION: u32 = 8192;
+const JPEG_QUALITY: u8 = 85;
+
+/// A cover's encoded renditions, ready to be written out. Only these are
+/// ever stored; the uploaded bytes, and any EXIF in them, are discarded.
+pub struct CoverRenditions {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl CoverRenditions {
+    /// Writes the renditions to a new directory and returns its path
+    /// relative to the upload directory, e.g. `covers/{id}`. A fresh
+    /// directory per cover lets clients cache every file forever.
+    pub fn save(&self, upload_dir: &str) -> Result<String, String> {
+        let cover_path = format!("covers/{}", Uuid::new_v4());
+        let full_path = format!("{}/{}", upload_dir, cover_path);
+        std::fs::create_dir_all(&full_path).map_err(|e| format!("Failed to create {}: {}", full_path, e))?;
+
+        for (name, data) in &self.files {
+            if let Err(e) = std::fs::write(format!("{}/{}", full_path, name), data) {
+                std::fs::remove_dir_all(&full_path).ok();
+                return Err(format!("Failed to write cover {}/{}: {}", full_path, name, e));
+            }
+        }
+
+        Ok(cover_path)
+    }
+}
+
+/// Decodes an uploaded or embedded image and renders every size as JPEG and
+/// WebP. The type is taken from the content, never the filename, and EXIF
+/// orientation is applied before the metadata is dropped.
+pub fn render(data: &[u8]) -> Result<CoverRenditions, String> {
+    let image = decode(data)?;
+
+    let mut files = Vec::new();
+    for size in COVER_SIZES {
+        // Cropped to the centre: covers are square almost everywhere they're shown
+        let thumbnail = image.resize_to_fill(size, size, FilterType::Lanczos3);
+        files.push((format!("{}.jpg", size), encode_jpeg(&thumbnail)?));
+
+        // The pure-Rust WebP encoder is lossless only
+        let mut webp = Vec::new();
+        thumbnail
+            .write_with_encoder(WebPEncoder::new_lossless(&mut webp))
+            .map_err(|e| format!("Failed to encode WebP: {}", e))?;
+        files.push((format!("{}.webp", size), webp));
+    }
+
+    Ok(CoverRenditions { files })
+}
+
+/// Re-encodes an avatar as a JPEG at most `AVATAR_MAX_SIZE` pixels on each
+/// side, keeping its aspect ratio. As with covers, only this output is
+/// stored, so nothing from the upload's metadata survives.
+pub fn render_avatar(data: &[u8]) -> Result<Vec<u8>, String> {
+    let image = decode(data)?;
+    let image = if image.width() > AVATAR_MAX_SIZE || image.height() > AVATAR_MAX_SIZE {
+        image.resize(AVATAR_MAX_SIZE, AVATAR_MAX_SIZE, FilterType::Lanczos3)
+    } else {
+        image
+    };
+
+    encode_jpeg(&image)
+}
+
+/// Writes a rendered avatar under a fresh name, so clients never see a
+/// cached old one, and returns its path relative to the upload directory.
+pub fn save_avatar(upload_dir: &str, jpeg: &[u8]) -> Result<String, String> {
+    let avatar_path = format!("avatars/{}.jpg", Uuid::new_v4());
+    let full_path = format!("{}/{}", upload_dir, avatar_path);
+    std::fs::create_dir_all(format!("{}/avatars", upload_dir))
+        .and_then(|_| std::fs::write(&full_path, jpeg))
+        .map_err(|e| format!("Failed to save avatar {}: {}", full_path, e))?;
+    Ok(avatar_path)
+}
+
+fn decode(data: &[u8]) -> Result<DynamicImage, String> {
+    let format = image_extension(data)
+        .and_then(ImageFormat::from_extension)
+        .ok_or("Image must be a PNG, JPEG, GIF or WebP image")?;
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_COVER_DIMENSION);
+    limits.max_image_height = Some(MAX_COVER_DIMENSION);
+
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().map_err(|e| format!("Unreadable image: {}", e))?;
+    let orientation = decoder.orientation().map_err(|e| format!("Unreadable image: {}", e))?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| format!("Unreadable image: {}", e))?;
+    image.apply_orientation(orientation);
+
+    Ok(DynamicImage::ImageRgb8(flatten(&image)))
+}
+
+fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>, String> {
+    let mut jpeg = Vec::new();
+    image
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY))
+        .map_err(|e| format!("Failed to encode JPEG: {}", e))?;
+    Ok(jpeg)
+}
+
+// JPEG has no alpha channel, so transparency is laid over white
+fn flatten(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |channel: u8| ((channel as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+/// The id in a stored cover's path. Covers stored before renditions
+/// existed were single files and have none.
+pub fn cover_id(cover_path: &str) -> Option<Uuid> {
+    cover_path.strip_prefix("covers/")?.parse().ok()
+}
+
+/// The `Content-Type` of a rendition file name, or `None` if no rendition
+/// has that name. Anything else under `covers/` is refused, which also
+/// rules out traversal.
+pub fn rendition_content_type(file: &str) -> Option<&'static str> {
+    let (size, extension) = file.split_once('.')?;
+    if !COVER_SIZES.iter().any(|known| known.to_string() == size) {
+        return None;
+    }
+    match extension {
+        "jpg" => Some("image/jpeg"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    const SECRET: &str = "GPS 51.5007N 0.1246W";
+
+    const RED: Rgb<u8> = Rgb([200, 40, 40]);
+    const BLUE: Rgb<u8> = Rgb([40, 40, 200]);
+
+    // Left half red, right half blue, so a rotation shows up in the output
+    fn two_tone(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, _| if x < width / 2 { RED } else { BLUE })
+    }
+
+    // A JPEG carrying an EXIF block with a description and an orientation
+    fn jpeg_with_exif(image: RgbImage, orientation: u16) -> Vec<u8> {
+        let plain = encode_jpeg(&DynamicImage::ImageRgb8(image)).unwrap();
+
+        let description = format!("{}\0", SECRET);
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        // ImageDescription, ASCII, stored after the IFD
+        tiff.extend_from_slice(&0x010Eu16.to_le_bytes());
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        tiff.extend_from_slice(&(description.len() as u32).to_le_bytes());
+        tiff.extend_from_slice(&38u32.to_le_bytes());
+        // Orientation, SHORT, stored inline
+        tiff.extend_from_slice(&0x0112u16.to_le_bytes());
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+        tiff.extend_from_slice(&orientation.to_le_bytes());
+        tiff.extend_from_slice(&[0, 0]);
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(description.as_bytes());
+
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&((2 + 6 + tiff.len()) as u16).to_be_bytes());
+        segment.extend_from_slice(b"Exif\0\0");
+        segment.extend_from_slice(&tiff);
+
+        let mut jpeg = plain[..2].to_vec();
+        jpeg.extend_from_slice(&segment);
+        jpeg.extend_from_slice(&plain[2..]);
+        jpeg
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    fn file<'a>(renditions: &'a CoverRenditions, name: &str) -> &'a [u8] {
+        &renditions.files.iter().find(|(file, _)| file == name).unwrap().1
+    }
+
+    fn is_red(pixel: Rgb<u8>) -> bool {
+        let [r, _, b] = pixel.0;
+        r > 150 && b < 100
+    }
+
+    #[test]
+    fn covers_come_in_every_size_and_format() {
+        let renditions = render(&encode_jpeg(&DynamicImage::ImageRgb8(two_tone(1000, 600))).unwrap()).unwrap();
+
+        let names: Vec<&str> = renditions.files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["64.jpg", "64.webp", "300.jpg", "300.webp", "640.jpg", "640.webp"]);
+        for (name, data) in &renditions.files {
+            let size: u32 = name.split_once('.').unwrap().0.parse().unwrap();
+            let format = image::guess_format(data).unwrap();
+            assert_eq!(format, if name.ends_with(".jpg") { ImageFormat::Jpeg } else { ImageFormat::WebP });
+            assert_eq!(image::load_from_memory(data).unwrap().dimensions(), (size, size));
+        }
+    }
+
+    #[test]
+    fn covers_drop_exif_and_apply_orientation() {
+        let upload = jpeg_with_exif(two_tone(40, 20), 6);
+        let renditions = render(&upload).unwrap();
+
+        for (name, data) in &renditions.files {
+            assert!(!contains(data, SECRET.as_bytes()), "{} kept the description", name);
+            assert!(!contains(data, b"Exif"), "{} kept an EXIF block", name);
+        }
+
+        // A quarter turn clockwise takes the left (red) half to the top
+        let cover = image::load_from_memory(file(&renditions, "64.webp")).unwrap().to_rgb8();
+        assert!(is_red(*cover.get_pixel(32, 4)));
+        assert!(!is_red(*cover.get_pixel(32, 59)));
+    }
+
+    #[test]
+    fn transparency_is_flattened_to_white() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::new(100, 100));
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+        let renditions = render(&png).unwrap();
+        let cover = image::load_from_memory(file(&renditions, "64.webp")).unwrap().to_rgb8();
+        assert!(cover.pixels().all(|pixel| *pixel == Rgb([255, 255, 255])));
+    }
+
+    #[test]
+    fn renditions_are_saved_under_a_fresh_id() {
+        let upload_dir = std::env::temp_dir().join(format!("covers-{}", Uuid::new_v4()));
+        let upload_dir = upload_dir.to_str().unwrap();
+        let renditions = render(&encode_jpeg(&DynamicImage::ImageRgb8(two_tone(80, 80))).unwrap()).unwrap();
+
+        let first = renditions.save(upload_dir).unwrap();
+        let second = renditions.save(upload_dir).unwrap();
+        assert_ne!(first, second);
+        assert!(cover_id(&first).is_some());
+        for (name, data) in &renditions.files {
+            assert_eq!(&std::fs::read(format!("{}/{}/{}", upload_dir, first, name)).unwrap(), data);
+        }
+
+        std::fs::remove_dir_all(upload_dir).unwrap();
+    }
+
+    #[test]
+    fn cover_ids() {
+        let id = Uuid::new_v4();
+        assert_eq!(cover_id(&format!("covers/{}", id)), Some(id));
+        assert_eq!(cover_id("covers/legacy.jpg"), None);
+        assert_eq!(cover_id(&format!("songs/{}", id)), None);
+    }
+
+    #[test]
+    fn only_rendition_names_have_a_content_type() {
+        assert_eq!(rendition_content_type("64.jpg"), Some("image/jpeg"));
+        assert_eq!(rendition_content_type("640.webp"), Some("image/webp"));
+
+        for file in ["65.jpg", "64.png", "64", "64.jpg.webp", "../64.jpg", ".jpg", "064.jpg"] {
+            assert_eq!(rendition_content_type(file), None, "{}", file);
+        }
+    }
+
+    #[test]
+    fn avatar_drops_exif_and_applies_orientation() {
+        let upload = jpeg_with_exif(two_tone(40, 20), 6);
+        assert!(contains(&upload, SECRET.as_bytes()));
+
+        let avatar = render_avatar(&upload).unwrap();
+        assert!(!contains(&avatar, SECRET.as_bytes()));
+        assert!(!contains(&avatar, b"Exif"));
+
+        // Orientation 6 means the stored pixels are rotated a quarter turn
+        let decoded = image::load_from_memory(&avatar).unwrap();
+        assert_eq!(decoded.dimensions(), (20, 40));
+    }
+
+    #[test]
+    fn avatar_is_scaled_down_to_fit() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(2048, 1024));
+        let avatar = render_avatar(&encode_jpeg(&image).unwrap()).unwrap();
+
+        let decoded = image::load_from_memory(&avatar).unwrap();
+        assert_eq!(decoded.dimensions(), (AVATAR_MAX_SIZE, AVATAR_MAX_SIZE / 2));
+    }
+
+    #[test]
+    fn non_images_are_rejected() {
+        assert!(render_avatar(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>").is_err());
+        assert!(render_avatar(&[0xFF, 0xD8, 0xFF, 0x00, 0x01]).is_err());
+    }
+}
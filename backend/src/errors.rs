@@ -0,0 +1,364 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+/// Stable, machine-readable error codes. Clients should branch on these,
+/// never on the human-readable message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    ValidationFailed,
+    BadRequest,
+    Unauthenticated,
+    InvalidCredentials,
+    InvalidToken,
+    EmailNotVerified,
+    Forbidden,
+    InsufficientScope,
+    NotFound,
+    UserExists,
+    UsernameTaken,
+    AlreadyExists,
+    LastAdmin,
+    RateLimited,
+    PayloadTooLarge,
+    UpstreamError,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::ValidationFailed => "VALIDATION_FAILED",
+            ErrorCode::BadRequest => "BAD_REQUEST",
+            ErrorCode::Unauthenticated => "UNAUTHENTICATED",
+            ErrorCode::InvalidCredentials => "INVALID_CREDENTIALS",
+            ErrorCode::InvalidToken => "INVALID_TOKEN",
+            ErrorCode::EmailNotVerified => "EMAIL_NOT_VERIFIED",
+            ErrorCode::Forbidden => "FORBIDDEN",
+            ErrorCode::InsufficientScope => "INSUFFICIENT_SCOPE",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::UserExists => "USER_EXISTS",
+            ErrorCode::UsernameTaken => "USERNAME_TAKEN",
+            ErrorCode::AlreadyExists => "ALREADY_EXISTS",
+            ErrorCode::LastAdmin => "LAST_ADMIN",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+            ErrorCode::PayloadTooLarge => "PAYLOAD_TOO_LARGE",
+            ErrorCode::UpstreamError => "UPSTREAM_ERROR",
+            ErrorCode::Internal => "INTERNAL",
+        }
+    }
+}
+
+/// One invalid field in a `VALIDATION_FAILED` response.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// The error type of every service and handler. Each variant fixes the HTTP
+/// status; the body is `{"error": message, "code": CODE}` plus `details` for
+/// validation failures.
+#[derive(Debug)]
+pub enum AppError {
+    Validation(Vec<FieldError>),
+    BadRequest(ErrorCode, String),
+    Unauthorized(ErrorCode, String),
+    Forbidden(ErrorCode, String),
+    NotFound(String),
+    Conflict(ErrorCode, String),
+    PayloadTooLarge(String),
+    RateLimited { retry_after_secs: u64 },
+    Upstream(String),
+    Internal(String),
+}
+
+impl AppError {
+    /// A single invalid field.
+    pub fn validation(field: &str, message: impl Into<String>) -> Self {
+        AppError::Validation(vec![FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        }])
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        AppError::BadRequest(ErrorCode::BadRequest, message.into())
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        AppError::Forbidden(ErrorCode::Forbidden, message.into())
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        AppError::NotFound(message.into())
+    }
+
+    pub fn internal(error: impl fmt::Display) -> Self {
+        AppError::Internal(error.to_string())
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AppError::Validation(_) => ErrorCode::ValidationFailed,
+            AppError::BadRequest(code, _)
+            | AppError::Unauthorized(code, _)
+            | AppError::Forbidden(code, _)
+            | AppError::Conflict(code, _) => *code,
+            AppError::NotFound(_) => ErrorCode::NotFound,
+            AppError::PayloadTooLarge(_) => ErrorCode::PayloadTooLarge,
+            AppError::RateLimited { .. } => ErrorCode::RateLimited,
+            AppError::Upstream(_) => ErrorCode::UpstreamError,
+            AppError::Internal(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Validation(errors) => match errors.as_slice() {
+                [error] => write!(f, "{}", error.message),
+                _ => write!(f, "Validation failed"),
+            },
+            AppError::BadRequest(_, message)
+            | AppError::Unauthorized(_, message)
+            | AppError::Forbidden(_, message)
+            | AppError::Conflict(_, message)
+            | AppError::NotFound(message)
+            | AppError::PayloadTooLarge(message)
+            | AppError::Upstream(message)
+            | AppError::Internal(message) => write!(f, "{}", message),
+            AppError::RateLimited { .. } => write!(f, "Too many failed attempts, try again later"),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::BadRequest(..) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(..) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(..) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(..) => StatusCode::CONFLICT,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        let code = self.code().as_str();
+
+        match self {
+            AppError::Validation(errors) => response.json(serde_json::json!({
+                "error": self.to_string(),
+                "code": code,
+                "details": errors
+            })),
+            AppError::RateLimited { retry_after_secs } => response
+                .insert_header((header::RETRY_AFTER, retry_after_secs.to_string()))
+                .json(serde_json::json!({
+                    "error": self.to_string(),
+                    "code": code,
+                    "retry_after": retry_after_secs
+                })),
+            // Internal details (SQL, file paths) go to the log, not the client
+            AppError::Internal(message) => {
+                log::error!("{}", message);
+                response.json(serde_json::json!({
+                    "error": "Internal server error",
+                    "code": code
+                }))
+            }
+            AppError::Upstream(message) => {
+                log::warn!("{}", message);
+                response.json(serde_json::json!({
+                    "error": "An external service failed, try again later",
+                    "code": code
+                }))
+            }
+            _ => response.json(serde_json::json!({
+                "error": self.to_string(),
+                "code": code
+            })),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(error: sqlx::Error) -> Self {
+        match &error {
+            sqlx::Error::RowNotFound => AppError::not_found("Not found"),
+            sqlx::Error::Database(db_error) => match db_error.code().as_deref() {
+                // unique_violation
+                Some("23505") => constraint_conflict(db_error.constraint()),
+                // foreign_key_violation: the row being pointed at doesn't exist
+                Some("23503") => AppError::not_found("A referenced resource does not exist"),
+                _ => AppError::Internal(format!("Database error: {}", error)),
+            },
+            _ => AppError::Internal(format!("Database error: {}", error)),
+        }
+    }
+}
+
+fn constraint_conflict(constraint: Option<&str>) -> AppError {
+    match constraint {
+        Some("users_email_key") => {
+            AppError::Conflict(ErrorCode::UserExists, "User with this email already exists".to_string())
+        }
+        Some("idx_users_username_lower") => {
+            AppError::Conflict(ErrorCode::UsernameTaken, "Username is already taken".to_string())
+        }
+        Some("playlist_songs_pkey") => {
+            AppError::Conflict(ErrorCode::AlreadyExists, "Song is already in this playlist".to_string())
+        }
+        _ => AppError::Conflict(ErrorCode::AlreadyExists, "Resource already exists".to_string()),
+    }
+}
+
+impl From<validator::ValidationErrors> for AppError {
+    fn from(errors: validator::ValidationErrors) -> Self {
+        let mut fields: Vec<FieldError> = errors
+            .field_errors()
+            .into_iter()
+            .flat_map(|(field, errors)| {
+                errors.iter().map(move |error| FieldError {
+                    field: field.to_string(),
+                    message: error
+                        .message
+                        .as_ref()
+                        .map(|message| message.to_string())
+                        .unwrap_or_else(|| format!("{} is invalid", field)),
+                })
+            })
+            .collect();
+
+        // HashMap order is random; keep responses stable
+        fields.sort_by(|a, b| a.field.cmp(&b.field));
+        AppError::Validation(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use std::error::Error;
+    use validator::Validate;
+
+    #[derive(Debug)]
+    struct FakeDatabaseError {
+        code: &'static str,
+        constraint: Option<&'static str>,
+    }
+
+    impl fmt::Display for FakeDatabaseError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "error {}", self.code)
+        }
+    }
+
+    impl Error for FakeDatabaseError {}
+
+    impl sqlx::error::DatabaseError for FakeDatabaseError {
+        fn message(&self) -> &str {
+            "fake"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.code))
+        }
+
+        fn as_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn constraint(&self) -> Option<&str> {
+            self.constraint
+        }
+    }
+
+    fn database_error(code: &'static str, constraint: Option<&'static str>) -> AppError {
+        sqlx::Error::Database(Box::new(FakeDatabaseError { code, constraint })).into()
+    }
+
+    #[test]
+    fn unique_violations_map_to_stable_codes() {
+        for (constraint, code) in [
+            (Some("users_email_key"), ErrorCode::UserExists),
+            (Some("idx_users_username_lower"), ErrorCode::UsernameTaken),
+            (Some("playlist_songs_pkey"), ErrorCode::AlreadyExists),
+            (Some("some_other_key"), ErrorCode::AlreadyExists),
+            (None, ErrorCode::AlreadyExists),
+        ] {
+            let error = database_error("23505", constraint);
+            assert_eq!(error.code(), code, "{:?}", constraint);
+            assert_eq!(error.status_code(), StatusCode::CONFLICT);
+        }
+    }
+
+    #[test]
+    fn other_database_errors() {
+        let error = database_error("23503", Some("songs_uploaded_by_fkey"));
+        assert_eq!(error.code(), ErrorCode::NotFound);
+        assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
+
+        let error = AppError::from(sqlx::Error::RowNotFound);
+        assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
+
+        let error = database_error("40001", None);
+        assert_eq!(error.code(), ErrorCode::Internal);
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[derive(Validate)]
+    struct Form {
+        #[validate(length(min = 1, message = "Zeta is required"))]
+        zeta: String,
+        #[validate(length(min = 1))]
+        alpha: String,
+        #[validate(range(min = 1))]
+        middle: i32,
+    }
+
+    #[actix_web::test]
+    async fn validation_errors_list_fields_in_order() {
+        let form = Form { zeta: String::new(), alpha: String::new(), middle: 0 };
+        let error = AppError::from(form.validate().unwrap_err());
+        assert_eq!(error.code(), ErrorCode::ValidationFailed);
+        assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = actix_web::body::to_bytes(error.error_response().into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "VALIDATION_FAILED");
+        assert_eq!(body["error"], "Validation failed");
+        assert_eq!(
+            body["details"],
+            serde_json::json!([
+                { "field": "alpha", "message": "alpha is invalid" },
+                { "field": "middle", "message": "middle is invalid" },
+                { "field": "zeta", "message": "Zeta is required" }
+            ])
+        );
+    }
+
+    #[test]
+    fn a_single_invalid_field_is_the_message() {
+        let error = AppError::validation("code", "Invalid authentication code");
+        assert_eq!(error.to_string(), "Invalid authentication code");
+    }
+}
@@ -0,0 +1,53 @@
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::middleware::{AuthUser, ClientInfo};
+use crate::models::{AuditEventQuery, Role};
+use crate::services::{AuditService, SongService};
+
+async fn get_audit_events(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    query: web::Query<AuditEventQuery>,
+) -> impl Responder {
+    if let Err(e) = user.require_role(Role::Admin) {
+        return e.error_response();
+    }
+
+    match AuditService::query(&pool, &query).await {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn requeue_transcode(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    client: ClientInfo,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    if let Err(e) = user.require_role(Role::Admin) {
+        return e.error_response();
+    }
+
+    let song_id = path.into_inner();
+
+    match SongService::requeue_transcode(&pool, song_id).await {
+        Ok(true) => {
+            AuditService::record(&pool, "song_transcode_requeued", Some(user.id), None, &client, Some(("song", song_id)), serde_json::json!({})).await;
+            HttpResponse::Accepted().finish()
+        }
+        Ok(false) => AppError::not_found("Song not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .route("/audit-events", web::get().to(get_audit_events))
+            .route("/songs/{id}/transcode", web::post().to(requeue_transcode)),
+    );
+}
@@ -1,43 +1,266 @@
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpResponse, Responder, ResponseError};
 use sqlx::PgPool;
 
-use crate::models::{AuthResponse, LoginRequest, RegisterRequest};
-use crate::services::AuthService;
+use crate::config::Config;
+use crate::mailer::Mailer;
+use crate::middleware::{AuthUser, ClientInfo};
+use crate::models::{
+    AuthResponse, ForgotPasswordRequest, LoginOutcome, LoginRequest, MfaChallengeResponse,
+    MfaCodeRequest, MfaDisableRequest, MfaVerifyRequest, OidcAuthorizationResponse, OidcCallbackRequest,
+    RecoveryCodesResponse, RefreshRequest, RegisterRequest, ResendVerificationRequest,
+    ResetPasswordRequest, VerifyEmailRequest,
+};
+use crate::oidc::OidcClient;
+use crate::rate_limit::LoginLimiter;
+use crate::services::{AuthService, MfaService, OidcService};
+use crate::validation::ValidatedJson;
 
 async fn register(
     pool: web::Data<PgPool>,
-    register_data: web::Json<RegisterRequest>,
+    config: web::Data<Config>,
+    mailer: web::Data<dyn Mailer>,
+    client: ClientInfo,
+    register_data: ValidatedJson<RegisterRequest>,
 ) -> impl Responder {
     match AuthService::register(
         &pool,
+        mailer.get_ref(),
+        &config,
+        &client,
         &register_data.username,
         &register_data.email,
         &register_data.password,
     )
     .await
     {
-        Ok((token, user)) => HttpResponse::Created().json(AuthResponse {
-            token,
+        Ok((tokens, user)) => HttpResponse::Created().json(AuthResponse {
+            token: tokens.token,
+            refresh_token: tokens.refresh_token,
             user: user.into(),
         }),
-        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({
-            "error": e
-        })),
+        Err(e) => e.error_response(),
     }
 }
 
 async fn login(
     pool: web::Data<PgPool>,
-    login_data: web::Json<LoginRequest>,
+    config: web::Data<Config>,
+    limiter: web::Data<dyn LoginLimiter>,
+    client: ClientInfo,
+    login_data: ValidatedJson<LoginRequest>,
 ) -> impl Responder {
-    match AuthService::login(&pool, &login_data.email, &login_data.password).await {
-        Ok((token, user)) => HttpResponse::Ok().json(AuthResponse {
-            token,
-            user: user.into(),
+    match AuthService::login(
+        &pool,
+        limiter.get_ref(),
+        &config,
+        &client,
+        &login_data.email,
+        &login_data.password,
+    )
+    .await
+    {
+        Ok(LoginOutcome::Authenticated(tokens, user)) => HttpResponse::Ok().json(AuthResponse {
+            token: tokens.token,
+            refresh_token: tokens.refresh_token,
+            user: (*user).into(),
         }),
-        Err(e) => HttpResponse::Unauthorized().json(serde_json::json!({
-            "error": e
+        Ok(LoginOutcome::MfaRequired(mfa_token)) => HttpResponse::Ok().json(MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token,
+        }),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn refresh(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    refresh_data: ValidatedJson<RefreshRequest>,
+) -> impl Responder {
+    match AuthService::refresh(&pool, &config, &client, &refresh_data.refresh_token).await {
+        Ok(tokens) => HttpResponse::Ok().json(tokens),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn logout(
+    pool: web::Data<PgPool>,
+    client: ClientInfo,
+    refresh_data: ValidatedJson<RefreshRequest>,
+) -> impl Responder {
+    match AuthService::logout(&pool, &client, &refresh_data.refresh_token).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn forgot_password(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    mailer: web::Data<dyn Mailer>,
+    forgot_data: ValidatedJson<ForgotPasswordRequest>,
+) -> impl Responder {
+    match AuthService::request_password_reset(&pool, mailer.get_ref(), &config, &forgot_data.email).await {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+            "message": "If an account exists for that email, a reset link has been sent"
         })),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn reset_password(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    reset_data: ValidatedJson<ResetPasswordRequest>,
+) -> impl Responder {
+    match AuthService::reset_password(&pool, &config, &client, &reset_data.token, &reset_data.password).await {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+            "message": "Password has been reset"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn verify_email(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    verify_data: ValidatedJson<VerifyEmailRequest>,
+) -> impl Responder {
+    match AuthService::verify_email(&pool, &config, &verify_data.token).await {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+            "message": "Email address verified"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn resend_verification(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    mailer: web::Data<dyn Mailer>,
+    resend_data: ValidatedJson<ResendVerificationRequest>,
+) -> impl Responder {
+    match AuthService::resend_verification(&pool, mailer.get_ref(), &config, &resend_data.email).await {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+            "message": "If that email still needs verifying, a new link has been sent"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn mfa_setup(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    match MfaService::begin_enrollment(&pool, &config, user.id).await {
+        Ok(setup) => HttpResponse::Ok().json(setup),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn mfa_confirm(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    limiter: web::Data<dyn LoginLimiter>,
+    client: ClientInfo,
+    code_data: ValidatedJson<MfaCodeRequest>,
+) -> impl Responder {
+    match MfaService::confirm_enrollment(&pool, limiter.get_ref(), &config, &client, user.id, &code_data.code).await {
+        Ok(recovery_codes) => HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes }),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn mfa_verify(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    limiter: web::Data<dyn LoginLimiter>,
+    client: ClientInfo,
+    verify_data: ValidatedJson<MfaVerifyRequest>,
+) -> impl Responder {
+    match AuthService::complete_mfa_login(
+        &pool,
+        limiter.get_ref(),
+        &config,
+        &client,
+        &verify_data.mfa_token,
+        &verify_data.code,
+    )
+    .await
+    {
+        Ok((tokens, user)) => HttpResponse::Ok().json(AuthResponse {
+            token: tokens.token,
+            refresh_token: tokens.refresh_token,
+            user: user.into(),
+        }),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn mfa_disable(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    limiter: web::Data<dyn LoginLimiter>,
+    client: ClientInfo,
+    disable_data: ValidatedJson<MfaDisableRequest>,
+) -> impl Responder {
+    match MfaService::disable(
+        &pool,
+        limiter.get_ref(),
+        &config,
+        &client,
+        user.id,
+        &disable_data.password,
+        &disable_data.code,
+    )
+    .await
+    {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn oidc_providers(oidc: web::Data<OidcClient>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "providers": oidc.provider_names()
+    }))
+}
+
+async fn oidc_authorize(
+    pool: web::Data<PgPool>,
+    oidc: web::Data<OidcClient>,
+    path: web::Path<String>,
+) -> impl Responder {
+    match OidcService::begin(&pool, &oidc, &path.into_inner()).await {
+        Ok(authorization_url) => HttpResponse::Ok().json(OidcAuthorizationResponse { authorization_url }),
+        Err(e) => e.error_response(),
+    }
+}
+
+// The frontend receives the provider's redirect and posts state and code here
+async fn oidc_callback(
+    pool: web::Data<PgPool>,
+    oidc: web::Data<OidcClient>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    callback_data: ValidatedJson<OidcCallbackRequest>,
+) -> impl Responder {
+    match OidcService::complete(&pool, &oidc, &config, &client, &callback_data.state, &callback_data.code).await {
+        Ok(LoginOutcome::Authenticated(tokens, user)) => HttpResponse::Ok().json(AuthResponse {
+            token: tokens.token,
+            refresh_token: tokens.refresh_token,
+            user: (*user).into(),
+        }),
+        Ok(LoginOutcome::MfaRequired(mfa_token)) => HttpResponse::Ok().json(MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token,
+        }),
+        Err(e) => e.error_response(),
     }
 }
 
@@ -45,6 +268,25 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/auth")
             .route("/register", web::post().to(register))
-            .route("/login", web::post().to(login)),
+            .route("/login", web::post().to(login))
+            .route("/refresh", web::post().to(refresh))
+            .route("/logout", web::post().to(logout))
+            .route("/forgot-password", web::post().to(forgot_password))
+            .route("/reset-password", web::post().to(reset_password))
+            .route("/verify-email", web::post().to(verify_email))
+            .route("/resend-verification", web::post().to(resend_verification))
+            .service(
+                web::scope("/mfa")
+                    .route("/setup", web::post().to(mfa_setup))
+                    .route("/confirm", web::post().to(mfa_confirm))
+                    .route("/verify", web::post().to(mfa_verify))
+                    .route("/disable", web::post().to(mfa_disable)),
+            )
+            .service(
+                web::scope("/oidc")
+                    .route("/providers", web::get().to(oidc_providers))
+                    .route("/callback", web::post().to(oidc_callback))
+                    .route("/{provider}/authorize", web::post().to(oidc_authorize)),
+            ),
     );
 }
@@ -0,0 +1,81 @@
+use actix_files::NamedFile;
+use actix_multipart::{Multipart, MultipartError};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, Responder, ResponseError};
+use futures_util::TryStreamExt;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::covers::{self, CoverRenditions};
+use crate::errors::AppError;
+use crate::utils::MAX_COVER_BYTES;
+
+/// Reads the `cover` field of a multipart upload and renders its sizes.
+/// Shared by every endpoint that accepts a cover.
+pub async fn receive_cover(mut payload: Multipart) -> Result<CoverRenditions, AppError> {
+    let mut data = Vec::new();
+
+    while let Some(mut field) = payload.try_next().await.map_err(invalid_multipart)? {
+        if field.content_disposition().get_name() != Some("cover") {
+            continue;
+        }
+
+        while let Some(chunk) = field.try_next().await.map_err(invalid_multipart)? {
+            if data.len() + chunk.len() > MAX_COVER_BYTES {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "Cover must be at most {} MB",
+                    MAX_COVER_BYTES / 1024 / 1024
+                )));
+            }
+            data.extend_from_slice(&chunk);
+        }
+    }
+
+    if data.is_empty() {
+        return Err(AppError::bad_request("Missing cover image"));
+    }
+
+    match web::block(move || covers::render(&data)).await {
+        Ok(Ok(renditions)) => Ok(renditions),
+        Ok(Err(e)) => Err(AppError::validation("cover", e)),
+        Err(e) => Err(AppError::internal(e)),
+    }
+}
+
+fn invalid_multipart(e: MultipartError) -> AppError {
+    AppError::bad_request(format!("Invalid multipart body: {}", e))
+}
+
+// Every cover lives at a fresh id and never changes, so caches may keep it
+// for good
+async fn get_cover(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    path: web::Path<(Uuid, String)>,
+) -> impl Responder {
+    let (cover_id, file) = path.into_inner();
+
+    let content_type = match covers::rendition_content_type(&file) {
+        Some(content_type) => content_type,
+        None => return AppError::not_found("Cover not found").error_response(),
+    };
+
+    let full_path = format!("{}/covers/{}/{}", config.upload_dir, cover_id, file);
+    match NamedFile::open(&full_path) {
+        Ok(file) => {
+            let mut response = file
+                .set_content_type(content_type.parse().expect("valid MIME type"))
+                .into_response(&req);
+            response.headers_mut().insert(
+                header::CACHE_CONTROL,
+                header::HeaderValue::from_static("public, max-age=31536000, immutable"),
+            );
+            response
+        }
+        Err(_) => AppError::not_found("Cover not found").error_response(),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/covers").route("/{id}/{file}", web::get().to(get_cover)));
+}
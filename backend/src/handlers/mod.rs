@@ -1,4 +1,7 @@
+pub mod admin;
 pub mod auth;
+pub mod covers;
 pub mod songs;
 pub mod playlists;
 pub mod users;
+pub mod well_known;
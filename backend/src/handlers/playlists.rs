@@ -1,98 +1,211 @@
-use actix_web::{web, HttpResponse, Responder, HttpRequest, HttpMessage};
+use actix_multipart::Multipart;
+use actix_web::{web, HttpResponse, Responder, ResponseError};
 use sqlx::PgPool;
 use uuid::Uuid;
 
+use crate::config::Config;
+use crate::errors::{AppError, ErrorCode};
+use crate::handlers::covers::receive_cover;
+use crate::middleware::{scope, ClientInfo, MaybeAuthUser, Scoped};
 use crate::models::{CreatePlaylistRequest, AddSongToPlaylistRequest};
-use crate::services::PlaylistService;
-
-// Extract user_id from Authorization header
-fn get_user_id_from_request(req: &HttpRequest) -> Result<Uuid, String> {
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| "Missing Authorization header".to_string())?;
-
-    if !auth_header.starts_with("Bearer ") {
-        return Err("Invalid Authorization header format".to_string());
-    }
-
-    let token = &auth_header[7..]; // Remove "Bearer " prefix
-    crate::middleware::validate_jwt(token)
-}
+use crate::services::{AuditService, AuthService, PlaylistService};
+use crate::utils::remove_upload;
+use crate::validation::ValidatedJson;
 
 async fn create_playlist(
-    req: HttpRequest,
+    user: Scoped<scope::PlaylistsWrite>,
     pool: web::Data<PgPool>,
-    playlist_data: web::Json<CreatePlaylistRequest>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    playlist_data: ValidatedJson<CreatePlaylistRequest>,
 ) -> impl Responder {
-    let user_id = match get_user_id_from_request(&req) {
-        Ok(id) => id,
-        Err(e) => return HttpResponse::Unauthorized().json(serde_json::json!({"error": e})),
-    };
+    let user_id = user.id;
+
+    if config.require_email_verification && playlist_data.is_public {
+        match AuthService::is_email_verified(&pool, user_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return AppError::Forbidden(ErrorCode::EmailNotVerified, "Verify your email address before publishing playlists".to_string())
+                    .error_response()
+            }
+            Err(e) => return e.error_response(),
+        }
+    }
 
     match PlaylistService::create_playlist(
         &pool,
         user_id,
         &playlist_data.name,
         playlist_data.description.as_deref(),
+        playlist_data.is_public,
     )
     .await
     {
-        Ok(playlist) => HttpResponse::Created().json(playlist),
-        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e
-        })),
+        Ok(playlist) => {
+            AuditService::record(&pool, "playlist_created", Some(user_id), None, &client, Some(("playlist", playlist.id)), serde_json::json!({
+                "name": playlist.name,
+                "is_public": playlist.is_public
+            })).await;
+            HttpResponse::Created().json(playlist)
+        }
+        Err(e) => e.error_response(),
     }
 }
 
 async fn get_user_playlists(
-    req: HttpRequest,
+    user: Scoped<scope::PlaylistsRead>,
     pool: web::Data<PgPool>,
 ) -> impl Responder {
-    let user_id = match get_user_id_from_request(&req) {
-        Ok(id) => id,
-        Err(e) => return HttpResponse::Unauthorized().json(serde_json::json!({"error": e})),
-    };
-
-    match PlaylistService::get_user_playlists(&pool, user_id).await {
+    match PlaylistService::get_user_playlists(&pool, user.id).await {
         Ok(playlists) => HttpResponse::Ok().json(playlists),
-        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e
-        })),
+        Err(e) => e.error_response(),
     }
 }
 
 async fn get_playlist(
+    viewer: MaybeAuthUser,
     pool: web::Data<PgPool>,
     path: web::Path<Uuid>,
 ) -> impl Responder {
     let playlist_id = path.into_inner();
+    let viewer_id = viewer.0.map(|user| user.id);
 
     match PlaylistService::get_playlist_with_songs(&pool, playlist_id).await {
-        Ok(Some(playlist)) => HttpResponse::Ok().json(playlist),
-        Ok(None) => HttpResponse::NotFound().json(serde_json::json!({
-            "error": "Playlist not found"
-        })),
-        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e
-        })),
+        // Private playlists are only visible to their owner
+        Ok(Some(playlist)) if playlist.is_public || viewer_id == Some(playlist.user_id) => {
+            HttpResponse::Ok().json(playlist)
+        }
+        Ok(_) => AppError::not_found("Playlist not found").error_response(),
+        Err(e) => e.error_response(),
     }
 }
 
 async fn add_song_to_playlist(
+    user: Scoped<scope::PlaylistsWrite>,
     pool: web::Data<PgPool>,
+    client: ClientInfo,
     path: web::Path<Uuid>,
     song_data: web::Json<AddSongToPlaylistRequest>,
 ) -> impl Responder {
     let playlist_id = path.into_inner();
 
+    match PlaylistService::get_playlist_owner(&pool, playlist_id).await {
+        Ok(Some(owner_id)) if owner_id == user.id => {}
+        Ok(_) => return AppError::not_found("Playlist not found").error_response(),
+        Err(e) => return e.error_response(),
+    }
+
     match PlaylistService::add_song_to_playlist(&pool, playlist_id, song_data.song_id).await {
-        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
-            "message": "Song added to playlist successfully"
-        })),
-        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e
-        })),
+        Ok(_) => {
+            AuditService::record(&pool, "playlist_song_added", Some(user.id), None, &client, Some(("playlist", playlist_id)), serde_json::json!({
+                "song_id": song_data.song_id
+            })).await;
+            HttpResponse::Ok().json(serde_json::json!({
+                "message": "Song added to playlist successfully"
+            }))
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn delete_playlist(
+    user: Scoped<scope::PlaylistsWrite>,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    let playlist_id = path.into_inner();
+
+    match PlaylistService::get_playlist_owner(&pool, playlist_id).await {
+        Ok(Some(owner_id)) if owner_id == user.id => {}
+        Ok(_) => return AppError::not_found("Playlist not found").error_response(),
+        Err(e) => return e.error_response(),
+    }
+
+    match PlaylistService::delete_playlist(&pool, playlist_id).await {
+        Ok(Some(cover_path)) => {
+            AuditService::record(&pool, "playlist_deleted", Some(user.id), None, &client, Some(("playlist", playlist_id)), serde_json::json!({})).await;
+            if let Some(cover_path) = cover_path {
+                remove_upload(&config.upload_dir, &cover_path);
+            }
+            HttpResponse::NoContent().finish()
+        }
+        Ok(None) => AppError::not_found("Playlist not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn upload_playlist_cover(
+    user: Scoped<scope::PlaylistsWrite>,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    path: web::Path<Uuid>,
+    payload: Multipart,
+) -> impl Responder {
+    let playlist_id = path.into_inner();
+
+    match PlaylistService::get_playlist_owner(&pool, playlist_id).await {
+        Ok(Some(owner_id)) if owner_id == user.id => {}
+        Ok(_) => return AppError::not_found("Playlist not found").error_response(),
+        Err(e) => return e.error_response(),
+    }
+
+    let renditions = match receive_cover(payload).await {
+        Ok(renditions) => renditions,
+        Err(e) => return e.error_response(),
+    };
+    let cover_path = match renditions.save(&config.upload_dir) {
+        Ok(cover_path) => cover_path,
+        Err(e) => return AppError::internal(e).error_response(),
+    };
+
+    match PlaylistService::set_cover(&pool, playlist_id, Some(&cover_path)).await {
+        Ok(old_path) => {
+            if let Some(old_path) = old_path {
+                remove_upload(&config.upload_dir, &old_path);
+            }
+        }
+        Err(e) => {
+            remove_upload(&config.upload_dir, &cover_path);
+            return e.error_response();
+        }
+    }
+
+    AuditService::record(&pool, "playlist_cover_updated", Some(user.id), None, &client, Some(("playlist", playlist_id)), serde_json::json!({})).await;
+
+    match PlaylistService::get_playlist_with_songs(&pool, playlist_id).await {
+        Ok(Some(playlist)) => HttpResponse::Ok().json(playlist),
+        Ok(None) => AppError::not_found("Playlist not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn delete_playlist_cover(
+    user: Scoped<scope::PlaylistsWrite>,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    let playlist_id = path.into_inner();
+
+    match PlaylistService::get_playlist_owner(&pool, playlist_id).await {
+        Ok(Some(owner_id)) if owner_id == user.id => {}
+        Ok(_) => return AppError::not_found("Playlist not found").error_response(),
+        Err(e) => return e.error_response(),
+    }
+
+    match PlaylistService::set_cover(&pool, playlist_id, None).await {
+        Ok(old_path) => {
+            AuditService::record(&pool, "playlist_cover_removed", Some(user.id), None, &client, Some(("playlist", playlist_id)), serde_json::json!({})).await;
+            if let Some(old_path) = old_path {
+                remove_upload(&config.upload_dir, &old_path);
+            }
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => e.error_response(),
     }
 }
 
@@ -102,6 +215,9 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
             .route("", web::post().to(create_playlist))
             .route("", web::get().to(get_user_playlists))
             .route("/{id}", web::get().to(get_playlist))
-            .route("/{id}/songs", web::post().to(add_song_to_playlist)),
+            .route("/{id}", web::delete().to(delete_playlist))
+            .route("/{id}/songs", web::post().to(add_song_to_playlist))
+            .route("/{id}/cover", web::post().to(upload_playlist_cover))
+            .route("/{id}/cover", web::delete().to(delete_playlist_cover)),
     );
 }
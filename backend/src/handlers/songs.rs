@@ -1,37 +1,76 @@
-use actix_multipart::Multipart;
-use actix_web::{web, HttpResponse, Responder};
+use actix_multipart::{Field, Multipart, MultipartError};
+use actix_files::NamedFile;
+use actix_web::body::SizedStream;
+use actix_web::http::header::{self, Header};
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, Responder, ResponseError};
 use futures_util::TryStreamExt;
 use sqlx::PgPool;
+use std::fs;
 use std::io::Write;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
 use uuid::Uuid;
+use validator::{Validate, ValidationError};
 
 use crate::config::Config;
-use crate::services::SongService;
+use crate::covers::{self, CoverRenditions};
+use crate::handlers::covers::receive_cover;
+use crate::errors::{AppError, ErrorCode};
+use crate::loudness::replaygain_db;
+use crate::media::{self, AudioInfo, EmbeddedTags};
+use crate::middleware::{scope, AuthError, ClientInfo, MaybeAuthUser, Scoped};
+use crate::models::{
+    cover_images, Role, StreamQuality, StreamQuery, StreamTokenResponse, TranscodeStatus, UploadSongForm, WaveformQuery,
+    WaveformResponse,
+};
+use crate::validation::validation_error;
+use crate::services::{AuditService, AuthService, SongService, UserService};
+use crate::transcode::{self, HLS_MASTER_PLAYLIST, HLS_MEDIA_PLAYLIST};
+use crate::utils::{create_stream_token, decode_stream_token, remove_upload, MAX_SONG_BYTES, STREAM_TOKEN_TTL_HOURS};
 
-async fn get_all_songs(pool: web::Data<PgPool>) -> impl Responder {
+// Roughly one point per pixel column of a full-width player
+const DEFAULT_WAVEFORM_POINTS: usize = 1024;
+// Far more than any title or album name; the form's own rules apply after
+const MAX_TEXT_FIELD_BYTES: usize = 64 * 1024;
+
+// Text fields stay `None` unless the client sent a non-blank value, so only
+// explicitly provided fields override the file's own tags
+#[derive(Default)]
+struct SongUpload {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    track_number: Option<String>,
+    year: Option<String>,
+    genre: Option<String>,
+    file_path: Option<String>,
+}
+
+// The catalog is public; `MaybeAuthUser` still rejects malformed or expired tokens
+async fn get_all_songs(_viewer: MaybeAuthUser, pool: web::Data<PgPool>) -> impl Responder {
     match SongService::get_all_songs(&pool).await {
         Ok(songs) => HttpResponse::Ok().json(songs),
-        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e
-        })),
+        Err(e) => e.error_response(),
     }
 }
 
-async fn get_song(pool: web::Data<PgPool>, path: web::Path<Uuid>) -> impl Responder {
+async fn get_song(
+    _viewer: MaybeAuthUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<Uuid>,
+) -> impl Responder {
     let song_id = path.into_inner();
     
     match SongService::get_song_by_id(&pool, song_id).await {
         Ok(Some(song)) => HttpResponse::Ok().json(song),
-        Ok(None) => HttpResponse::NotFound().json(serde_json::json!({
-            "error": "Song not found"
-        })),
-        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e
-        })),
+        Ok(None) => AppError::not_found("Song not found").error_response(),
+        Err(e) => e.error_response(),
     }
 }
 
 async fn search_songs(
+    _viewer: MaybeAuthUser,
     pool: web::Data<PgPool>,
     query: web::Query<serde_json::Value>,
 ) -> impl Responder {
@@ -41,136 +80,741 @@ async fn search_songs(
         .unwrap_or("");
 
     if search_query.is_empty() {
-        return HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "Search query is required"
-        }));
+        return AppError::bad_request("Search query is required").error_response();
     }
 
     match SongService::search_songs(&pool, search_query).await {
         Ok(songs) => HttpResponse::Ok().json(songs),
-        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": e
-        })),
+        Err(e) => e.error_response(),
     }
 }
 
-async fn upload_song(
+async fn issue_stream_token(
+    user: Scoped<scope::SongsRead>,
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
-    mut payload: Multipart,
+    path: web::Path<Uuid>,
 ) -> impl Responder {
-    let mut title = String::new();
-    let mut artist = String::new();
-    let mut album = String::new();
-    let mut duration = 0i32;
-    let mut file_path = String::new();
-
-    while let Some(mut field) = payload.try_next().await.unwrap_or(None) {
-        let content_disposition = field.content_disposition();
-        let field_name = content_disposition.get_name().unwrap_or("");
-
-        match field_name {
-            "title" => {
-                let mut data = Vec::new();
-                while let Some(chunk) = field.try_next().await.unwrap_or(None) {
-                    data.extend_from_slice(&chunk);
-                }
-                title = String::from_utf8(data).unwrap_or_default();
+    let song_id = path.into_inner();
+
+    match SongService::get_song_by_id(&pool, song_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return AppError::not_found("Song not found").error_response(),
+        Err(e) => return e.error_response(),
+    }
+
+    match create_stream_token(&config.purpose_keys, user.id, song_id) {
+        Ok(token) => HttpResponse::Ok().json(StreamTokenResponse {
+            url: format!("/api/songs/{}/stream?token={}", song_id, token),
+            token,
+            expires_in: STREAM_TOKEN_TTL_HOURS * 3600,
+        }),
+        Err(e) => AppError::internal(format!("Failed to create stream token: {}", e)).error_response(),
+    }
+}
+
+// A stream token for this song, or the usual Authorization header.
+// Returns the listener's id.
+async fn authorize_stream(
+    req: &HttpRequest,
+    config: &Config,
+    token: Option<&str>,
+    song_id: Uuid,
+) -> Result<Uuid, AuthError> {
+    match token {
+        Some(token) => decode_stream_token(&config.purpose_keys, token, song_id).map_err(AuthError::InvalidToken),
+        None => Scoped::<scope::SongsRead>::extract(req).await.map(|user| user.id),
+    }
+}
+
+// If-Range asks for the range only while the file is unchanged. A validator
+// that doesn't match (or can't be read) means the client needs the whole file.
+fn if_range_is_stale(req: &HttpRequest, response: &HttpResponse) -> bool {
+    if !req.headers().contains_key(header::IF_RANGE) {
+        return false;
+    }
+    let response_header = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+    match header::IfRange::parse(req) {
+        Ok(header::IfRange::EntityTag(tag)) => response_header(header::ETAG)
+            .and_then(|etag| etag.parse::<header::EntityTag>().ok())
+            .is_none_or(|etag| !etag.strong_eq(&tag)),
+        Ok(header::IfRange::Date(date)) => response_header(header::LAST_MODIFIED)
+            .and_then(|modified| modified.parse::<header::HttpDate>().ok())
+            .is_none_or(|modified| modified != date),
+        Err(_) => true,
+    }
+}
+
+// The whole file with the validators of `partial`, for a stale If-Range
+fn whole_file(full_path: &str, partial: &HttpResponse) -> std::io::Result<HttpResponse> {
+    let file = fs::File::open(full_path)?;
+    let len = file.metadata()?.len();
+    let file = tokio::fs::File::from_std(file);
+    let chunks = futures_util::stream::try_unfold(file, |mut file| async move {
+        let mut chunk = vec![0; 64 * 1024];
+        let read = file.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok::<_, std::io::Error>(None);
+        }
+        chunk.truncate(read);
+        Ok(Some((web::Bytes::from(chunk), file)))
+    });
+
+    let mut response = HttpResponse::Ok();
+    for (name, value) in partial.headers() {
+        if name != header::CONTENT_RANGE && name != header::CONTENT_LENGTH {
+            response.append_header((name.clone(), value.clone()));
+        }
+    }
+    Ok(response.body(SizedStream::new(len, chunks)))
+}
+
+// Range, ETag and 206 responses are handled by `NamedFile`; If-Range isn't
+async fn stream_song(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    path: web::Path<Uuid>,
+    query: web::Query<StreamQuery>,
+) -> impl Responder {
+    let song_id = path.into_inner();
+
+    let user_id = match authorize_stream(&req, &config, query.token.as_deref(), song_id).await {
+        Ok(user_id) => user_id,
+        Err(e) => return e.error_response(),
+    };
+
+    let song = match SongService::get_song_by_id(&pool, song_id).await {
+        Ok(Some(song)) => song,
+        Ok(None) => return AppError::not_found("Song not found").error_response(),
+        Err(e) => return e.error_response(),
+    };
+
+    let quality = match query.quality {
+        Some(quality) => quality,
+        None => match UserService::get_preferences(&pool, user_id).await {
+            Ok(preferences) => preferences.stream_quality,
+            Err(e) => return e.error_response(),
+        },
+    };
+
+    // A rendition that isn't there (not transcoded yet, or skipped because
+    // the original is no better) falls back to the original
+    let (served, file_path, content_type) = if song.renditions.contains(&quality) {
+        let rendition = transcode::rendition_path(song_id, quality);
+        let content_type = media::content_type(Some("aac"), &rendition);
+        (quality, rendition, content_type)
+    } else {
+        let content_type = media::content_type(song.codec.as_deref(), &song.file_path);
+        (StreamQuality::Original, song.file_path, content_type)
+    };
+
+    let full_path = format!("{}/{}", config.upload_dir, file_path);
+    match NamedFile::open(&full_path) {
+        Ok(file) => {
+            let mut response = file.set_content_type(content_type).into_response(&req);
+            if response.status() == StatusCode::PARTIAL_CONTENT && if_range_is_stale(&req, &response) {
+                response = match whole_file(&full_path, &response) {
+                    Ok(response) => response,
+                    Err(e) => return AppError::internal(format!("Failed to reopen audio file: {}", e)).error_response(),
+                };
             }
-            "artist" => {
-                let mut data = Vec::new();
-                while let Some(chunk) = field.try_next().await.unwrap_or(None) {
-                    data.extend_from_slice(&chunk);
-                }
-                artist = String::from_utf8(data).unwrap_or_default();
+            let headers = response.headers_mut();
+            // The URL may carry a personal stream token
+            headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("private"));
+            headers.insert(
+                header::HeaderName::from_static("x-stream-quality"),
+                header::HeaderValue::from_static(served.as_str()),
+            );
+            response
+        }
+        Err(e) => {
+            log::warn!("Audio for song {} is missing at {}: {}", song_id, full_path, e);
+            AppError::not_found("Audio file not found").error_response()
+        }
+    }
+}
+
+// Peaks are part of the public catalog, like the song itself, and never
+// change once computed
+async fn get_waveform(
+    _viewer: MaybeAuthUser,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    path: web::Path<Uuid>,
+    query: web::Query<WaveformQuery>,
+) -> impl Responder {
+    let song_id = path.into_inner();
+    let points = query.points.unwrap_or(DEFAULT_WAVEFORM_POINTS).clamp(1, media::WAVEFORM_RESOLUTIONS[0]);
+
+    let song = match SongService::get_song_by_id(&pool, song_id).await {
+        Ok(Some(song)) => song,
+        Ok(None) => return AppError::not_found("Song not found").error_response(),
+        Err(e) => return e.error_response(),
+    };
+    let waveform_path = match song.waveform_path {
+        Some(waveform_path) => waveform_path,
+        None => return AppError::not_found("Waveform not available for this song").error_response(),
+    };
+
+    let upload_dir = config.upload_dir.clone();
+    let peaks = match web::block(move || media::load_waveform(&upload_dir, &waveform_path)).await {
+        Ok(Ok(waveform)) => waveform.peaks(points),
+        Ok(Err(e)) => {
+            log::warn!("Waveform for song {} is unreadable: {}", song_id, e);
+            None
+        }
+        Err(e) => return AppError::internal(e).error_response(),
+    };
+    let peaks = match peaks {
+        Some(peaks) => peaks,
+        None => return AppError::not_found("Waveform not available for this song").error_response(),
+    };
+
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=86400"))
+        .json(WaveformResponse {
+            song_id,
+            duration_ms: song.duration_ms,
+            points: peaks.points(),
+            min: peaks.min,
+            max: peaks.max,
+        })
+}
+
+async fn hls_master_playlist(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    path: web::Path<Uuid>,
+    query: web::Query<StreamQuery>,
+) -> impl Responder {
+    serve_hls(&req, &pool, &config, path.into_inner(), query.token.as_deref(), None, HLS_MASTER_PLAYLIST).await
+}
+
+async fn hls_media_file(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    path: web::Path<(Uuid, String, String)>,
+    query: web::Query<StreamQuery>,
+) -> impl Responder {
+    let (song_id, quality, file) = path.into_inner();
+
+    let quality = match StreamQuality::parse(&quality) {
+        Some(quality) if quality != StreamQuality::Original => quality,
+        _ => return AppError::not_found("Unknown quality").error_response(),
+    };
+    if file != HLS_MEDIA_PLAYLIST && !transcode::is_segment_name(&file) {
+        return AppError::not_found("Not found").error_response();
+    }
+
+    serve_hls(&req, &pool, &config, song_id, query.token.as_deref(), Some(quality), &file).await
+}
+
+async fn serve_hls(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    song_id: Uuid,
+    token: Option<&str>,
+    quality: Option<StreamQuality>,
+    file: &str,
+) -> HttpResponse {
+    if let Err(e) = authorize_stream(req, config, token, song_id).await {
+        return e.error_response();
+    }
+
+    let song = match SongService::get_song_by_id(pool, song_id).await {
+        Ok(Some(song)) => song,
+        Ok(None) => return AppError::not_found("Song not found").error_response(),
+        Err(e) => return e.error_response(),
+    };
+
+    if song.transcode_status != TranscodeStatus::Ready {
+        return AppError::not_found("Song has not been transcoded yet").error_response();
+    }
+
+    let relative = match quality {
+        Some(quality) if song.renditions.contains(&quality) => format!("{}/{}", quality.as_str(), file),
+        Some(_) => return AppError::not_found("Rendition not found").error_response(),
+        None => file.to_string(),
+    };
+    let full_path = format!("{}/{}/{}", config.upload_dir, transcode::song_dir(song_id), relative);
+
+    let mut response = if file.ends_with(".m3u8") {
+        match tokio::fs::read_to_string(&full_path).await {
+            Ok(playlist) => HttpResponse::Ok()
+                .content_type("application/vnd.apple.mpegurl")
+                .body(with_stream_token(&playlist, token)),
+            Err(e) => {
+                log::warn!("HLS playlist for song {} is missing at {}: {}", song_id, full_path, e);
+                return AppError::not_found("Playlist not found").error_response();
             }
-            "album" => {
-                let mut data = Vec::new();
-                while let Some(chunk) = field.try_next().await.unwrap_or(None) {
-                    data.extend_from_slice(&chunk);
-                }
-                album = String::from_utf8(data).unwrap_or_default();
+        }
+    } else {
+        match NamedFile::open(&full_path) {
+            Ok(segment) => segment
+                .set_content_type("video/mp2t".parse().expect("valid MIME type"))
+                .into_response(req),
+            Err(_) => return AppError::not_found("Segment not found").error_response(),
+        }
+    };
+
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, header::HeaderValue::from_static("private"));
+    response
+}
+
+// Players resolve playlist entries relative to the playlist URL but don't
+// carry its query string over, so a stream token is appended to each entry
+fn with_stream_token(playlist: &str, token: Option<&str>) -> String {
+    let token = match token {
+        Some(token) => token,
+        None => return playlist.to_string(),
+    };
+
+    playlist
+        .lines()
+        .map(|line| {
+            if line.is_empty() || line.starts_with('#') {
+                line.to_string()
+            } else {
+                format!("{}?token={}", line, token)
             }
-            "duration" => {
-                let mut data = Vec::new();
-                while let Some(chunk) = field.try_next().await.unwrap_or(None) {
-                    data.extend_from_slice(&chunk);
-                }
-                duration = String::from_utf8(data)
-                    .unwrap_or_default()
-                    .parse()
-                    .unwrap_or(0);
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+        + "\n"
+}
+
+async fn upload_song(
+    user: Scoped<scope::SongsWrite>,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    mut payload: Multipart,
+) -> impl Responder {
+    if let Err(e) = user.require_role(Role::Artist) {
+        return e.error_response();
+    }
+
+    // Only verified accounts may upload when verification is enforced
+    if config.require_email_verification {
+        match AuthService::is_email_verified(&pool, user.id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return AppError::Forbidden(ErrorCode::EmailNotVerified, "Verify your email address before uploading songs".to_string())
+                    .error_response()
             }
-            "audio" => {
-                let filename = content_disposition
-                    .get_filename()
-                    .unwrap_or("unknown.mp3");
-                
-                let file_id = Uuid::new_v4();
-                let file_extension = std::path::Path::new(filename)
-                    .extension()
-                    .and_then(|ext| ext.to_str())
-                    .unwrap_or("mp3");
-                
-                let new_filename = format!("{}.{}", file_id, file_extension);
-                file_path = format!("songs/{}", new_filename);
-                
-                let full_path = format!("{}/{}", config.upload_dir, file_path);
-                
-                // Create songs directory if it doesn't exist
-                if let Some(parent) = std::path::Path::new(&full_path).parent() {
-                    std::fs::create_dir_all(parent).ok();
-                }
+            Err(e) => return e.error_response(),
+        }
+    }
+
+    let mut upload = SongUpload::default();
+    if let Err(e) = receive_song_upload(&mut payload, &config.upload_dir, &mut upload).await {
+        remove_song_files(&config, upload.file_path);
+        return e.error_response();
+    }
+    let SongUpload { title, artist, album, track_number, year, genre, file_path } = upload;
 
-                let mut file = std::fs::File::create(&full_path).unwrap();
-                
-                while let Some(chunk) = field.try_next().await.unwrap_or(None) {
-                    file.write_all(&chunk).unwrap();
+    // The file is decoded in full before anything is stored: that both
+    // rejects non-audio and gives the true duration and format
+    let mut analysed = None;
+    let mut file_path = file_path.unwrap_or_default();
+    if !file_path.is_empty() {
+        let full_path = Path::new(&config.upload_dir).join(&file_path);
+        match web::block(move || analyse_upload(&full_path)).await {
+            Ok(Ok((info, tags, cover, extension))) => {
+                let stored_path = Path::new(&file_path).with_extension(extension).display().to_string();
+                if let Err(e) = fs::rename(
+                    Path::new(&config.upload_dir).join(&file_path),
+                    Path::new(&config.upload_dir).join(&stored_path),
+                ) {
+                    remove_song_files(&config, [file_path]);
+                    return AppError::internal(format!("Failed to store upload: {}", e)).error_response();
                 }
+                file_path = stored_path;
+                analysed = Some((info, tags, cover));
+            }
+            Ok(Err(e)) => log::info!("Rejected upload {}: {}", file_path, e),
+            Err(e) => {
+                remove_song_files(&config, [file_path]);
+                return AppError::internal(e).error_response();
             }
-            _ => {}
         }
     }
+    let (audio_info, tags, embedded_cover) = match analysed {
+        Some((info, tags, cover)) => (Some(info), tags, cover),
+        None => (None, EmbeddedTags::default(), None),
+    };
+
+    let mut parse_errors = Vec::new();
+    let form = UploadSongForm {
+        title: title.or(tags.title).unwrap_or_default(),
+        artist: artist.or(tags.artist).unwrap_or_default(),
+        album: album.or(tags.album).unwrap_or_default(),
+        duration: audio_info.as_ref().map_or(0, AudioInfo::duration_secs),
+        track_number: parse_number_field(track_number, "track_number", "Track number must be a whole number", &mut parse_errors)
+            .or(tags.track_number),
+        year: parse_number_field(year, "year", "Year must be a whole number", &mut parse_errors).or(tags.year),
+        genre: genre.or(tags.genre),
+    };
 
-    if title.is_empty() || artist.is_empty() || file_path.is_empty() {
-        return HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "Missing required fields: title, artist, and audio file"
-        }));
+    let mut errors = form.validate().err().unwrap_or_default();
+    for (field, error) in parse_errors {
+        errors.add(field, error);
     }
+    if file_path.is_empty() {
+        errors.add("audio", validation_error("required", "An audio file is required"));
+    } else if audio_info.is_none() {
+        errors.add("audio", validation_error("format", "File is not a supported audio format"));
+    }
+    let audio_info = match audio_info {
+        Some(audio_info) if errors.is_empty() => audio_info,
+        _ => {
+            remove_song_files(&config, [file_path]);
+            return AppError::from(errors).error_response();
+        }
+    };
+
+    let UploadSongForm { title, artist, album, duration, track_number, year, genre } = form;
 
     let song_id = Uuid::new_v4();
     let now = chrono::Utc::now();
 
+    let cover_art = embedded_cover.and_then(|cover| {
+        cover
+            .save(&config.upload_dir)
+            .map_err(|e| log::warn!("Failed to save embedded cover for {}: {}", file_path, e))
+            .ok()
+    });
+
+    // A song without peaks still plays; the backfill command can retry
+    let waveform_path = media::save_waveform(&config.upload_dir, &file_path, &audio_info.waveform)
+        .map_err(|e| log::warn!("Failed to save waveform for {}: {}", file_path, e))
+        .ok();
+
     match sqlx::query(
-        "INSERT INTO songs (id, title, artist, album, duration, file_path, created_at)
-         VALUES ($1, $2, $3, $4, $5, $6, $7)"
+        "INSERT INTO songs (id, title, artist, album, duration, duration_ms, codec, sample_rate, channels, bitrate,
+                            track_number, year, genre, file_path, cover_art, waveform_path,
+                            loudness_lufs, loudness_range_lu, true_peak_dbtp, loudness_histogram, uploaded_by, created_at)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22)"
     )
     .bind(song_id)
     .bind(&title)
     .bind(&artist)
     .bind(&album)
     .bind(duration)
+    .bind(audio_info.duration_ms)
+    .bind(&audio_info.codec)
+    .bind(audio_info.sample_rate)
+    .bind(audio_info.channels)
+    .bind(audio_info.bitrate)
+    .bind(track_number)
+    .bind(year)
+    .bind(&genre)
     .bind(&file_path)
+    .bind(&cover_art)
+    .bind(&waveform_path)
+    .bind(audio_info.loudness.integrated_lufs)
+    .bind(audio_info.loudness.range_lu)
+    .bind(audio_info.loudness.true_peak_dbtp)
+    .bind(sqlx::types::Json(&audio_info.loudness.histogram))
+    .bind(user.id)
     .bind(now)
     .execute(pool.get_ref())
     .await
     {
         Ok(_) => {
+            AuditService::record(&pool, "song_uploaded", Some(user.id), None, &client, Some(("song", song_id)), serde_json::json!({
+                "title": title,
+                "artist": artist,
+                "file_path": file_path
+            })).await;
+
+            // The song is stored either way; the album is measured again on
+            // its next upload or delete
+            let (album_loudness_lufs, album_true_peak_dbtp) = SongService::update_album_loudness(&pool, Some(user.id), &album)
+                .await
+                .unwrap_or_else(|e| {
+                    log::warn!("Failed to measure album {:?} after upload: {}", album, e);
+                    (None, None)
+                });
+
+            let loudness = audio_info.loudness;
             let song = crate::models::Song {
                 id: song_id,
                 title,
                 artist,
                 album,
                 duration,
+                duration_ms: Some(audio_info.duration_ms),
+                codec: Some(audio_info.codec),
+                sample_rate: Some(audio_info.sample_rate),
+                channels: Some(audio_info.channels),
+                bitrate: Some(audio_info.bitrate),
+                track_number,
+                year,
+                genre,
+                // The transcoding worker picks it up from here
+                transcode_status: TranscodeStatus::Pending,
+                renditions: Vec::new(),
                 file_path,
-                cover_art: None,
+                cover: cover_images(&cover_art),
+                cover_art,
+                waveform_path,
+                loudness_lufs: loudness.integrated_lufs,
+                loudness_range_lu: loudness.range_lu,
+                true_peak_dbtp: loudness.true_peak_dbtp,
+                track_gain_db: loudness.integrated_lufs.map(replaygain_db),
+                album_loudness_lufs,
+                album_true_peak_dbtp,
+                album_gain_db: album_loudness_lufs.map(replaygain_db),
                 created_at: now,
             };
             HttpResponse::Created().json(song)
         }
-        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": format!("Failed to save song: {}", e)
-        })),
+        Err(e) => {
+            remove_song_files(&config, std::iter::once(file_path).chain(cover_art).chain(waveform_path));
+            AppError::from(e).error_response()
+        }
+    }
+}
+
+// The audio is written under a `.upload` name until it has been probed
+async fn receive_song_upload(payload: &mut Multipart, upload_dir: &str, upload: &mut SongUpload) -> Result<(), AppError> {
+    while let Some(mut field) = payload.try_next().await.map_err(invalid_multipart)? {
+        let field_name = field.content_disposition().get_name().unwrap_or("").to_string();
+
+        match field_name.as_str() {
+            "title" => upload.title = read_text_field(&mut field).await?,
+            "artist" => upload.artist = read_text_field(&mut field).await?,
+            "album" => upload.album = read_text_field(&mut field).await?,
+            "track_number" => upload.track_number = read_text_field(&mut field).await?,
+            "year" => upload.year = read_text_field(&mut field).await?,
+            "genre" => upload.genre = read_text_field(&mut field).await?,
+            "audio" => {
+                if upload.file_path.is_some() {
+                    return Err(AppError::bad_request("Only one audio file may be uploaded"));
+                }
+
+                let file_path = format!("songs/{}.upload", Uuid::new_v4());
+                let full_path = Path::new(upload_dir).join(&file_path);
+                if let Some(parent) = full_path.parent() {
+                    fs::create_dir_all(parent)
+                        .map_err(|e| AppError::internal(format!("Failed to create {}: {}", parent.display(), e)))?;
+                }
+                let mut file = fs::File::create(&full_path)
+                    .map_err(|e| AppError::internal(format!("Failed to create {}: {}", full_path.display(), e)))?;
+                upload.file_path = Some(file_path);
+
+                let mut size = 0;
+                while let Some(chunk) = field.try_next().await.map_err(invalid_multipart)? {
+                    size += chunk.len();
+                    if size > MAX_SONG_BYTES {
+                        return Err(AppError::PayloadTooLarge(format!(
+                            "Audio file must be at most {} MB",
+                            MAX_SONG_BYTES / 1024 / 1024
+                        )));
+                    }
+                    file.write_all(&chunk)
+                        .map_err(|e| AppError::internal(format!("Failed to write {}: {}", full_path.display(), e)))?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn invalid_multipart(e: MultipartError) -> AppError {
+    AppError::bad_request(format!("Invalid multipart body: {}", e))
+}
+
+// Runs on the blocking pool. Unreadable tags or artwork aren't fatal;
+// undecodable audio is. The extension returned is that of the format the
+// file turned out to be.
+fn analyse_upload(path: &Path) -> Result<(AudioInfo, EmbeddedTags, Option<CoverRenditions>, &'static str), String> {
+    let info = media::probe_audio(path)?;
+    let extension = media::audio_extension(path, &info.codec)?;
+    let mut tags = media::read_tags(path).unwrap_or_else(|e| {
+        log::warn!("No embedded tags read from {}: {}", path.display(), e);
+        EmbeddedTags::default()
+    });
+    let cover = tags.cover.take().and_then(|data| {
+        covers::render(&data)
+            .map_err(|e| log::info!("Ignoring embedded cover in {}: {}", path.display(), e))
+            .ok()
+    });
+    Ok((info, tags, cover, extension))
+}
+
+// Blank values are treated the same as a missing field
+async fn read_text_field(field: &mut Field) -> Result<Option<String>, AppError> {
+    let mut data = Vec::new();
+    while let Some(chunk) = field.try_next().await.map_err(invalid_multipart)? {
+        if data.len() + chunk.len() > MAX_TEXT_FIELD_BYTES {
+            return Err(AppError::PayloadTooLarge("Form field is too long".to_string()));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    let text = String::from_utf8(data).unwrap_or_default();
+    let text = text.trim();
+    Ok((!text.is_empty()).then(|| text.to_string()))
+}
+
+fn parse_number_field(
+    text: Option<String>,
+    field: &'static str,
+    message: &'static str,
+    errors: &mut Vec<(&'static str, ValidationError)>,
+) -> Option<i32> {
+    let text = text?;
+    match text.parse() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            errors.push((field, validation_error("number", message)));
+            None
+        }
+    }
+}
+
+fn remove_song_files(config: &Config, paths: impl IntoIterator<Item = String>) {
+    for path in paths.into_iter().filter(|path| !path.is_empty()) {
+        remove_upload(&config.upload_dir, &path);
+    }
+}
+
+// Uploaders manage their own songs' covers; admins manage every song's
+async fn authorize_song_owner(pool: &PgPool, user: &Scoped<scope::SongsWrite>, song_id: Uuid) -> Result<(), HttpResponse> {
+    match SongService::get_uploader(pool, song_id).await {
+        Ok(Some(Some(uploader))) if uploader == user.id => Ok(()),
+        Ok(Some(_)) => user.require_role(Role::Admin).map_err(|e| e.error_response()),
+        Ok(None) => Err(AppError::not_found("Song not found").error_response()),
+        Err(e) => Err(e.error_response()),
+    }
+}
+
+async fn upload_song_cover(
+    user: Scoped<scope::SongsWrite>,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    path: web::Path<Uuid>,
+    payload: Multipart,
+) -> impl Responder {
+    let song_id = path.into_inner();
+    if let Err(response) = authorize_song_owner(&pool, &user, song_id).await {
+        return response;
+    }
+
+    let renditions = match receive_cover(payload).await {
+        Ok(renditions) => renditions,
+        Err(e) => return e.error_response(),
+    };
+    let cover_path = match renditions.save(&config.upload_dir) {
+        Ok(cover_path) => cover_path,
+        Err(e) => return AppError::internal(e).error_response(),
+    };
+
+    match SongService::set_cover(&pool, song_id, Some(&cover_path)).await {
+        Ok(old_path) => remove_song_files(&config, old_path),
+        Err(e) => {
+            remove_song_files(&config, [cover_path]);
+            return e.error_response();
+        }
+    }
+
+    AuditService::record(&pool, "song_cover_updated", Some(user.id), None, &client, Some(("song", song_id)), serde_json::json!({})).await;
+
+    match SongService::get_song_by_id(&pool, song_id).await {
+        Ok(Some(song)) => HttpResponse::Ok().json(song),
+        Ok(None) => AppError::not_found("Song not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn delete_song_cover(
+    user: Scoped<scope::SongsWrite>,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    let song_id = path.into_inner();
+    if let Err(response) = authorize_song_owner(&pool, &user, song_id).await {
+        return response;
+    }
+
+    match SongService::set_cover(&pool, song_id, None).await {
+        Ok(old_path) => {
+            AuditService::record(&pool, "song_cover_removed", Some(user.id), None, &client, Some(("song", song_id)), serde_json::json!({})).await;
+            remove_song_files(&config, old_path);
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+// An album is the caller's own songs with that album name
+async fn upload_album_cover(
+    user: Scoped<scope::SongsWrite>,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    path: web::Path<String>,
+    payload: Multipart,
+) -> impl Responder {
+    if let Err(e) = user.require_role(Role::Artist) {
+        return e.error_response();
+    }
+
+    let album = path.into_inner();
+    let renditions = match receive_cover(payload).await {
+        Ok(renditions) => renditions,
+        Err(e) => return e.error_response(),
+    };
+
+    match SongService::set_album_cover(&pool, &config, user.id, &album, &renditions).await {
+        Ok(songs) if songs.is_empty() => AppError::not_found("Album not found").error_response(),
+        Ok(songs) => {
+            AuditService::record(&pool, "album_cover_updated", Some(user.id), None, &client, None, serde_json::json!({
+                "album": album,
+                "songs": songs.len()
+            })).await;
+            HttpResponse::Ok().json(songs)
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn delete_song(
+    user: Scoped<scope::SongsWrite>,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    client: ClientInfo,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    if let Err(e) = user.require_role(Role::Admin) {
+        return e.error_response();
+    }
+
+    let song_id = path.into_inner();
+
+    match SongService::delete_song(&pool, song_id).await {
+        Ok(Some(files)) => {
+            AuditService::record(&pool, "song_deleted", Some(user.id), None, &client, Some(("song", song_id)), serde_json::json!({
+                "file_path": files.first()
+            })).await;
+
+            remove_song_files(&config, files);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(None) => AppError::not_found("Song not found").error_response(),
+        Err(e) => e.error_response(),
     }
 }
 
@@ -180,6 +824,109 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
             .route("", web::get().to(get_all_songs))
             .route("/search", web::get().to(search_songs))
             .route("/upload", web::post().to(upload_song))
-            .route("/{id}", web::get().to(get_song)),
+            .route("/albums/{album}/cover", web::post().to(upload_album_cover))
+            .route("/{id}", web::get().to(get_song))
+            .route("/{id}/stream", web::get().to(stream_song))
+            .route("/{id}/stream-token", web::post().to(issue_stream_token))
+            .route("/{id}/waveform", web::get().to(get_waveform))
+            .route("/{id}/cover", web::post().to(upload_song_cover))
+            .route("/{id}/cover", web::delete().to(delete_song_cover))
+            .route("/{id}/hls/master.m3u8", web::get().to(hls_master_playlist))
+            .route("/{id}/hls/{quality}/{file}", web::get().to(hls_media_file))
+            .route("/{id}", web::delete().to(delete_song)),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+
+    use crate::test_support;
+
+    // A 1000 byte "song" on disk, streamable with the returned token
+    async fn stream_fixture() -> (PgPool, Config, Uuid, String) {
+        let pool = test_support::pool().await;
+        let mut config = test_support::config();
+        config.upload_dir = std::env::temp_dir().join(format!("stream-{}", Uuid::new_v4())).to_string_lossy().into_owned();
+
+        let user_id = test_support::create_user(&pool).await;
+        let song_id = test_support::create_song(&pool, user_id, "").await;
+        fs::create_dir_all(format!("{}/songs", config.upload_dir)).unwrap();
+        let audio: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        fs::write(format!("{}/songs/{}.mp3", config.upload_dir, song_id), audio).unwrap();
+
+        let token = create_stream_token(&config.purpose_keys, user_id, song_id).unwrap();
+        (pool, config, song_id, token)
+    }
+
+    async fn get(
+        pool: &PgPool,
+        config: &Config,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> (StatusCode, header::HeaderMap, Vec<u8>) {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .route("/api/songs/{id}/stream", web::get().to(stream_song)),
+        )
+        .await;
+
+        let mut req = TestRequest::get().uri(uri);
+        for &header in headers {
+            req = req.insert_header(header);
+        }
+        let res = call_service(&app, req.to_request()).await;
+        let status = res.status();
+        let headers = res.headers().clone();
+        (status, headers, read_body(res).await.to_vec())
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn ranges_are_served_as_partial_content() {
+        let (pool, config, song_id, token) = stream_fixture().await;
+        let uri = format!("/api/songs/{}/stream?token={}", song_id, token);
+
+        let (status, headers, body) = get(&pool, &config, &uri, &[("range", "bytes=0-99")]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers.get(header::CONTENT_RANGE).unwrap(), "bytes 0-99/1000");
+        assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "audio/mpeg");
+        assert_eq!(headers.get(header::CACHE_CONTROL).unwrap(), "private");
+        assert_eq!(headers.get("x-stream-quality").unwrap(), "original");
+        assert_eq!(body, (0..100).map(|i| i as u8).collect::<Vec<_>>());
+
+        // A range is only honoured while the file still matches the client's copy
+        let etag = headers.get(header::ETAG).unwrap().to_str().unwrap();
+        let (status, _, body) = get(&pool, &config, &uri, &[("range", "bytes=0-99"), ("if-range", etag)]).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body.len(), 100);
+
+        let (status, stale, body) = get(&pool, &config, &uri, &[("range", "bytes=0-99"), ("if-range", "\"stale\"")]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(stale.get(header::CONTENT_RANGE).is_none());
+        assert_eq!(stale.get(header::ETAG), headers.get(header::ETAG));
+        assert_eq!(stale.get("x-stream-quality").unwrap(), "original");
+        assert_eq!(body, (0..1000).map(|i| i as u8).collect::<Vec<_>>());
+
+        fs::remove_dir_all(&config.upload_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn streams_need_a_token_for_that_song() {
+        let (pool, config, song_id, token) = stream_fixture().await;
+        let other_song = test_support::create_song(&pool, test_support::create_user(&pool).await, "").await;
+
+        let (status, _, _) = get(&pool, &config, &format!("/api/songs/{}/stream?token={}", other_song, token), &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _, _) = get(&pool, &config, &format!("/api/songs/{}/stream", song_id), &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        fs::remove_dir_all(&config.upload_dir).unwrap();
+    }
+}
@@ -1,61 +1,511 @@
-use actix_web::{web, HttpResponse, Responder, HttpRequest, HttpMessage};
-use sqlx::{PgPool, Row};
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_web::{web, HttpRequest, HttpResponse, Responder, ResponseError};
+use futures_util::TryStreamExt;
+use sqlx::PgPool;
 use uuid::Uuid;
 
-use crate::models::UserResponse;
+use crate::config::Config;
+use crate::covers;
+use crate::errors::AppError;
+use crate::mailer::Mailer;
+use crate::middleware::{scope, AuthUser, ClientInfo, MaybeAuthUser, Scoped};
+use crate::models::{
+    ChangePasswordRequest, CreateAccessTokenRequest, DeleteAccountRequest, Role, UpdateProfileRequest,
+    UpdateRoleRequest, UserPreferences, UserResponse,
+};
+use crate::rate_limit::LoginLimiter;
+use crate::services::{AccessTokenService, AccountService, SessionService, SocialService, UserService};
+use crate::utils::{remove_upload, MAX_AVATAR_BYTES};
+use crate::validation::ValidatedJson;
 
-fn get_user_id_from_request(req: &HttpRequest) -> Result<Uuid, String> {
-    let auth_header = req.headers()
-        .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| "Missing Authorization header".to_string())?;
+async fn get_current_user(
+    user: Scoped<scope::ProfileRead>,
+    pool: web::Data<PgPool>,
+) -> impl Responder {
+    match UserService::get_by_id(&pool, user.id).await {
+        Ok(Some(user)) => HttpResponse::Ok().json(UserResponse::from(user)),
+        Ok(None) => AppError::not_found("User not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
 
-    if !auth_header.starts_with("Bearer ") {
-        return Err("Invalid Authorization header format".to_string());
+async fn get_preferences(
+    user: Scoped<scope::ProfileRead>,
+    pool: web::Data<PgPool>,
+) -> impl Responder {
+    match UserService::get_preferences(&pool, user.id).await {
+        Ok(preferences) => HttpResponse::Ok().json(preferences),
+        Err(e) => e.error_response(),
     }
+}
 
-    let token = &auth_header[7..]; // Remove "Bearer " prefix
-    crate::middleware::validate_jwt(token)
+async fn update_preferences(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    preferences: web::Json<UserPreferences>,
+) -> impl Responder {
+    match UserService::set_preferences(&pool, user.id, &preferences).await {
+        Ok(preferences) => HttpResponse::Ok().json(preferences),
+        Err(e) => e.error_response(),
+    }
 }
 
-async fn get_current_user(
-    req: HttpRequest,
+async fn delete_account(
+    user: AuthUser,
     pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    limiter: web::Data<dyn LoginLimiter>,
+    client: ClientInfo,
+    delete_data: web::Json<DeleteAccountRequest>,
 ) -> impl Responder {
-    let user_id = match get_user_id_from_request(&req) {
-        Ok(id) => id,
-        Err(e) => return HttpResponse::Unauthorized().json(serde_json::json!({"error": e})),
-    };
+    match AccountService::request_deletion(
+        &pool,
+        limiter.get_ref(),
+        &config,
+        &client,
+        user.id,
+        &delete_data.password,
+    )
+    .await
+    {
+        Ok(purge_after) => HttpResponse::Accepted().json(serde_json::json!({
+            "message": "Your account will be deleted. Log in again before then to cancel.",
+            "purge_after": purge_after
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn export_account(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+) -> impl Responder {
+    match AccountService::export(&pool, user.id, user.session_id).await {
+        Ok(Some(export)) => HttpResponse::Ok()
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"account-export-{}.json\"", export.exported_at.format("%Y%m%d")),
+            ))
+            .json(export),
+        Ok(None) => AppError::not_found("User not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn update_profile(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    mailer: web::Data<dyn Mailer>,
+    client: ClientInfo,
+    profile_data: ValidatedJson<UpdateProfileRequest>,
+) -> impl Responder {
+    match UserService::update_profile(&pool, mailer.get_ref(), &config, &client, user.id, &profile_data).await {
+        Ok(Some(user)) => HttpResponse::Ok().json(UserResponse::from(user)),
+        Ok(None) => AppError::not_found("User not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
 
-    match sqlx::query("SELECT id, username, email, password_hash, created_at, updated_at FROM users WHERE id = $1")
-        .bind(user_id)
-        .fetch_optional(pool.get_ref())
-        .await
+async fn change_password(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    limiter: web::Data<dyn LoginLimiter>,
+    client: ClientInfo,
+    password_data: ValidatedJson<ChangePasswordRequest>,
+) -> impl Responder {
+    match UserService::change_password(
+        &pool,
+        limiter.get_ref(),
+        &config,
+        &client,
+        user.id,
+        user.session_id,
+        &password_data,
+    )
+    .await
     {
-        Ok(Some(row)) => {
-            let user = crate::models::User {
-                id: row.get("id"),
-                username: row.get("username"),
-                email: row.get("email"),
-                password_hash: row.get("password_hash"),
-                created_at: row.get("created_at"),
-                updated_at: row.get("updated_at"),
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "message": "Password has been changed"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn upload_avatar(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    mut payload: Multipart,
+) -> impl Responder {
+    let mut data = Vec::new();
+
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return AppError::bad_request(format!("Invalid multipart body: {}", e)).error_response(),
+        };
+        if field.content_disposition().get_name() != Some("avatar") {
+            continue;
+        }
+
+        loop {
+            let chunk = match field.try_next().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => return AppError::bad_request(format!("Invalid multipart body: {}", e)).error_response(),
             };
-            let user_response: UserResponse = user.into();
-            HttpResponse::Ok().json(user_response)
+            if data.len() + chunk.len() > MAX_AVATAR_BYTES {
+                return AppError::PayloadTooLarge(format!(
+                    "Avatar must be at most {} MB",
+                    MAX_AVATAR_BYTES / 1024 / 1024
+                ))
+                .error_response();
+            }
+            data.extend_from_slice(&chunk);
         }
-        Ok(None) => HttpResponse::NotFound().json(serde_json::json!({
-            "error": "User not found"
-        })),
-        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": format!("Database error: {}", e)
+    }
+
+    if data.is_empty() {
+        return AppError::bad_request("Missing avatar image").error_response();
+    }
+
+    // Only the re-encoded image is kept, so EXIF (GPS position, camera
+    // serial numbers) in the upload is never served back
+    let upload_dir = config.upload_dir.clone();
+    let rendered = web::block(move || {
+        let jpeg = covers::render_avatar(&data).map_err(|e| AppError::validation("avatar", e))?;
+        covers::save_avatar(&upload_dir, &jpeg).map_err(AppError::internal)
+    });
+    let avatar_path = match rendered.await {
+        Ok(Ok(avatar_path)) => avatar_path,
+        Ok(Err(e)) => return e.error_response(),
+        Err(e) => return AppError::internal(e).error_response(),
+    };
+
+    match UserService::set_avatar(&pool, user.id, Some(&avatar_path)).await {
+        Ok(old_path) => {
+            if let Some(old_path) = old_path {
+                remove_upload(&config.upload_dir, &old_path);
+            }
+        }
+        Err(e) => {
+            remove_upload(&config.upload_dir, &avatar_path);
+            return e.error_response();
+        }
+    }
+
+    match UserService::get_by_id(&pool, user.id).await {
+        Ok(Some(user)) => HttpResponse::Ok().json(UserResponse::from(user)),
+        Ok(None) => AppError::not_found("User not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn delete_avatar(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    match UserService::set_avatar(&pool, user.id, None).await {
+        Ok(old_path) => {
+            if let Some(old_path) = old_path {
+                remove_upload(&config.upload_dir, &old_path);
+            }
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn get_avatar(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    match UserService::get_avatar_path(&pool, path.into_inner()).await {
+        Ok(Some(avatar_path)) => {
+            let full_path = format!("{}/{}", config.upload_dir, avatar_path);
+            match NamedFile::open(&full_path) {
+                Ok(file) => file.into_response(&req),
+                Err(_) => AppError::not_found("Avatar not found").error_response(),
+            }
+        }
+        Ok(None) => AppError::not_found("Avatar not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn get_profile(
+    viewer: MaybeAuthUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let viewer_id = viewer.0.map(|user| user.id);
+
+    match UserService::get_public_profile(&pool, &path.into_inner(), viewer_id).await {
+        Ok(Some(profile)) => HttpResponse::Ok().json(profile),
+        Ok(None) => AppError::not_found("User not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
+
+// Resolves a username from the path; users who blocked the viewer don't exist
+async fn find_visible_user(
+    pool: &PgPool,
+    username: &str,
+    viewer_id: Option<Uuid>,
+) -> Result<Uuid, AppError> {
+    let user = UserService::find_by_username(pool, username)
+        .await?
+        .ok_or_else(|| AppError::not_found("User not found"))?;
+
+    if let Some(viewer_id) = viewer_id {
+        if SocialService::has_blocked(pool, user.id, viewer_id).await? {
+            return Err(AppError::not_found("User not found"));
+        }
+    }
+
+    Ok(user.id)
+}
+
+async fn get_followers(
+    viewer: MaybeAuthUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let user_id = match find_visible_user(&pool, &path, viewer.0.map(|user| user.id)).await {
+        Ok(user_id) => user_id,
+        Err(e) => return e.error_response(),
+    };
+
+    match SocialService::list_followers(&pool, user_id).await {
+        Ok(followers) => HttpResponse::Ok().json(followers),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn get_following(
+    viewer: MaybeAuthUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let user_id = match find_visible_user(&pool, &path, viewer.0.map(|user| user.id)).await {
+        Ok(user_id) => user_id,
+        Err(e) => return e.error_response(),
+    };
+
+    match SocialService::list_following(&pool, user_id).await {
+        Ok(following) => HttpResponse::Ok().json(following),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn follow_user(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let followee_id = match find_visible_user(&pool, &path, Some(user.id)).await {
+        Ok(followee_id) => followee_id,
+        Err(e) => return e.error_response(),
+    };
+
+    match SocialService::follow(&pool, user.id, followee_id).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn unfollow_user(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let followee_id = match find_visible_user(&pool, &path, None).await {
+        Ok(followee_id) => followee_id,
+        Err(e) => return e.error_response(),
+    };
+
+    match SocialService::unfollow(&pool, user.id, followee_id).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn block_user(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let blocked_id = match find_visible_user(&pool, &path, None).await {
+        Ok(blocked_id) => blocked_id,
+        Err(e) => return e.error_response(),
+    };
+
+    match SocialService::block(&pool, user.id, blocked_id).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn unblock_user(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let blocked_id = match find_visible_user(&pool, &path, None).await {
+        Ok(blocked_id) => blocked_id,
+        Err(e) => return e.error_response(),
+    };
+
+    match SocialService::unblock(&pool, user.id, blocked_id).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn get_blocked_users(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+) -> impl Responder {
+    match SocialService::list_blocked(&pool, user.id).await {
+        Ok(blocked) => HttpResponse::Ok().json(blocked),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn update_user_role(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    client: ClientInfo,
+    path: web::Path<Uuid>,
+    role_data: web::Json<UpdateRoleRequest>,
+) -> impl Responder {
+    if let Err(e) = user.require_role(Role::Admin) {
+        return e.error_response();
+    }
+
+    match UserService::update_role(&pool, &client, user.id, path.into_inner(), role_data.role).await {
+        Ok(Some(user)) => HttpResponse::Ok().json(UserResponse::from(user)),
+        Ok(None) => AppError::not_found("User not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn get_sessions(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+) -> impl Responder {
+    match SessionService::list_active(&pool, user.id, user.session_id).await {
+        Ok(sessions) => HttpResponse::Ok().json(sessions),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn revoke_session(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    client: ClientInfo,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    match SessionService::revoke(&pool, &client, user.id, path.into_inner()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => AppError::not_found("Session not found").error_response(),
+        Err(e) => e.error_response(),
+    }
+}
+
+// Log out everywhere else
+async fn revoke_other_sessions(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    client: ClientInfo,
+) -> impl Responder {
+    match SessionService::revoke_others(&pool, &client, user.id, user.session_id).await {
+        Ok(revoked) => HttpResponse::Ok().json(serde_json::json!({
+            "revoked": revoked
         })),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn get_access_tokens(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+) -> impl Responder {
+    match AccessTokenService::list(&pool, user.id).await {
+        Ok(tokens) => HttpResponse::Ok().json(tokens),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn create_access_token(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    client: ClientInfo,
+    token_data: ValidatedJson<CreateAccessTokenRequest>,
+) -> impl Responder {
+    match AccessTokenService::create(
+        &pool,
+        &client,
+        user.id,
+        &token_data.name,
+        &token_data.scopes,
+        token_data.expires_in_days,
+    )
+    .await
+    {
+        Ok(token) => HttpResponse::Created().json(token),
+        Err(e) => e.error_response(),
+    }
+}
+
+async fn revoke_access_token(
+    user: AuthUser,
+    pool: web::Data<PgPool>,
+    client: ClientInfo,
+    path: web::Path<Uuid>,
+) -> impl Responder {
+    match AccessTokenService::revoke(&pool, &client, user.id, path.into_inner()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => AppError::not_found("Access token not found").error_response(),
+        Err(e) => e.error_response(),
     }
 }
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/users")
-            .route("/me", web::get().to(get_current_user)),
+            .route("/me", web::get().to(get_current_user))
+            .route("/me", web::patch().to(update_profile))
+            .route("/me", web::delete().to(delete_account))
+            .route("/me/export", web::get().to(export_account))
+            .route("/me/password", web::post().to(change_password))
+            .route("/me/preferences", web::get().to(get_preferences))
+            .route("/me/preferences", web::put().to(update_preferences))
+            .route("/me/avatar", web::post().to(upload_avatar))
+            .route("/me/avatar", web::delete().to(delete_avatar))
+            .route("/me/sessions", web::get().to(get_sessions))
+            .route("/me/sessions", web::delete().to(revoke_other_sessions))
+            .route("/me/sessions/{id}", web::delete().to(revoke_session))
+            .route("/me/tokens", web::get().to(get_access_tokens))
+            .route("/me/tokens", web::post().to(create_access_token))
+            .route("/me/tokens/{id}", web::delete().to(revoke_access_token))
+            .route("/me/blocks", web::get().to(get_blocked_users))
+            .route("/{id}/avatar", web::get().to(get_avatar))
+            .route("/{id}/role", web::put().to(update_user_role))
+            // Registered last so the /me routes above take precedence
+            .route("/{username}", web::get().to(get_profile))
+            .route("/{username}/followers", web::get().to(get_followers))
+            .route("/{username}/following", web::get().to(get_following))
+            .route("/{username}/follow", web::post().to(follow_user))
+            .route("/{username}/follow", web::delete().to(unfollow_user))
+            .route("/{username}/block", web::post().to(block_user))
+            .route("/{username}/block", web::delete().to(unblock_user)),
     );
 }
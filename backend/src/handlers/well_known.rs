@@ -0,0 +1,14 @@
+use actix_web::{web, HttpResponse, Responder};
+
+use crate::config::Config;
+
+// Public keys other services use to verify our access tokens
+async fn jwks(config: web::Data<Config>) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "public, max-age=300"))
+        .json(config.jwt_keys.jwks())
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/.well-known/jwks.json", web::get().to(jwks));
+}
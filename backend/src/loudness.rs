@@ -0,0 +1,494 @@
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::{Channels, SignalSpec};
+
+/// ReplayGain 2.0 plays everything back at this loudness.
+pub const REPLAYGAIN_REFERENCE_LUFS: f64 = -18.0;
+
+// BS.1770 gating: blocks below the absolute gate are silence; the relative
+// gates drop quiet passages from the integrated loudness and range
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const INTEGRATED_RELATIVE_GATE_LU: f64 = -10.0;
+const RANGE_RELATIVE_GATE_LU: f64 = -20.0;
+const RANGE_LOW_PERCENTILE: f64 = 0.10;
+const RANGE_HIGH_PERCENTILE: f64 = 0.95;
+
+// Loudness is measured over 100 ms steps: momentary blocks span 4 of them
+// (400 ms, 75% overlap) and short-term blocks 30 (3 s)
+const STEPS_PER_SECOND: u32 = 10;
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+
+// Histogram bins are 0.1 LU wide, fine enough that gating a whole album
+// from the merged histograms is indistinguishable from gating every block
+const HISTOGRAM_BINS_PER_LU: f64 = 10.0;
+
+// True peak is read from a 4x oversampled signal (BS.1770 Annex 2)
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+/// An EBU R128 measurement of one song.
+#[derive(Debug, Clone, Default)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS; `None` for silence.
+    pub integrated_lufs: Option<f64>,
+    /// Loudness range in LU; `None` when too short or quiet to tell.
+    pub range_lu: Option<f64>,
+    /// True peak in dBTP; `None` for digital silence.
+    pub true_peak_dbtp: Option<f64>,
+    /// The gated momentary blocks, kept so an album's loudness can be
+    /// measured across its songs without decoding them again.
+    pub histogram: LoudnessHistogram,
+}
+
+/// Momentary block powers bucketed by loudness. Each bin holds the number
+/// of blocks and the sum of their powers, so merged histograms still give
+/// an exact mean.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoudnessHistogram {
+    bins: BTreeMap<i32, (u64, f64)>,
+}
+
+impl LoudnessHistogram {
+    fn add(&mut self, power: f64) {
+        let bin = (to_lufs(power) * HISTOGRAM_BINS_PER_LU).floor() as i32;
+        let entry = self.bins.entry(bin).or_insert((0, 0.0));
+        entry.0 += 1;
+        entry.1 += power;
+    }
+
+    pub fn merge(&mut self, other: &LoudnessHistogram) {
+        for (bin, (count, power)) in &other.bins {
+            let entry = self.bins.entry(*bin).or_insert((0, 0.0));
+            entry.0 += count;
+            entry.1 += power;
+        }
+    }
+
+    /// Integrated loudness over every block in the histogram.
+    pub fn integrated_lufs(&self) -> Option<f64> {
+        let relative_gate = mean_lufs(self.bins.values().copied())? + INTEGRATED_RELATIVE_GATE_LU;
+        mean_lufs(
+            self.bins
+                .iter()
+                .filter(|(bin, _)| bin_centre(**bin) > relative_gate)
+                .map(|(_, totals)| *totals),
+        )
+    }
+}
+
+fn bin_centre(bin: i32) -> f64 {
+    (bin as f64 + 0.5) / HISTOGRAM_BINS_PER_LU
+}
+
+fn mean_lufs(totals: impl Iterator<Item = (u64, f64)>) -> Option<f64> {
+    let (count, power) = totals.fold((0, 0.0), |acc, (count, power)| (acc.0 + count, acc.1 + power));
+    (count > 0).then(|| to_lufs(power / count as f64))
+}
+
+fn to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// The gain in dB that brings `lufs` to the ReplayGain reference.
+pub fn replaygain_db(lufs: f64) -> f64 {
+    REPLAYGAIN_REFERENCE_LUFS - lufs
+}
+
+/// Measures loudness as BS.1770-4 / EBU Tech 3341 and 3342 describe it,
+/// one interleaved frame at a time.
+pub struct LoudnessMeter {
+    channels: Vec<ChannelState>,
+    step_frames: u64,
+    step_filled: u64,
+    // Mean weighted power of each completed 100 ms step
+    steps: Vec<f64>,
+    peak: f32,
+}
+
+struct ChannelState {
+    weight: f64,
+    filters: [Biquad; 2],
+    step_energy: f64,
+    oversampler: Oversampler,
+}
+
+impl LoudnessMeter {
+    pub fn new(spec: &SignalSpec) -> Self {
+        let rate = spec.rate as f64;
+        let count = spec.channels.count();
+
+        let channels = spec
+            .channels
+            .iter()
+            .map(|channel| ChannelState {
+                weight: channel_weight(channel, count),
+                filters: k_weighting(rate),
+                step_energy: 0.0,
+                oversampler: Oversampler::new(oversampling_for(spec.rate)),
+            })
+            .collect();
+
+        LoudnessMeter {
+            channels,
+            step_frames: (spec.rate / STEPS_PER_SECOND).max(1) as u64,
+            step_filled: 0,
+            steps: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, frame: &[f32]) {
+        for (channel, &sample) in self.channels.iter_mut().zip(frame) {
+            self.peak = self.peak.max(channel.oversampler.push(sample));
+
+            if channel.weight > 0.0 {
+                let filtered = channel
+                    .filters
+                    .iter_mut()
+                    .fold(sample as f64, |signal, filter| filter.process(signal));
+                channel.step_energy += filtered * filtered;
+            }
+        }
+
+        self.step_filled += 1;
+        if self.step_filled == self.step_frames {
+            let power = self
+                .channels
+                .iter_mut()
+                .map(|channel| channel.weight * std::mem::take(&mut channel.step_energy))
+                .sum::<f64>()
+                / self.step_frames as f64;
+            self.steps.push(power);
+            self.step_filled = 0;
+        }
+    }
+
+    // A trailing partial step is dropped; BS.1770 only measures whole blocks
+    pub fn finish(self) -> Loudness {
+        let mut histogram = LoudnessHistogram::default();
+        let mut momentary = Vec::new();
+        for power in windows(&self.steps, MOMENTARY_STEPS).filter(|power| to_lufs(*power) > ABSOLUTE_GATE_LUFS) {
+            histogram.add(power);
+            momentary.push(power);
+        }
+
+        let integrated_lufs = gated_mean(&momentary, INTEGRATED_RELATIVE_GATE_LU);
+
+        let short_term: Vec<f64> = windows(&self.steps, SHORT_TERM_STEPS)
+            .filter(|power| to_lufs(*power) > ABSOLUTE_GATE_LUFS)
+            .collect();
+        let range_lu = loudness_range(&short_term);
+
+        let true_peak_dbtp = (self.peak > 0.0).then(|| 20.0 * (self.peak as f64).log10());
+
+        Loudness {
+            integrated_lufs,
+            range_lu,
+            true_peak_dbtp,
+            histogram,
+        }
+    }
+}
+
+// Mean power of each run of `length` consecutive steps, advancing one step
+// at a time
+fn windows(steps: &[f64], length: usize) -> impl Iterator<Item = f64> + '_ {
+    steps.windows(length).map(move |window| window.iter().sum::<f64>() / length as f64)
+}
+
+// Loudness of the blocks within `gate` LU of the ungated mean
+fn gated_mean(blocks: &[f64], gate: f64) -> Option<f64> {
+    if blocks.is_empty() {
+        return None;
+    }
+    let relative_gate = to_lufs(blocks.iter().sum::<f64>() / blocks.len() as f64) + gate;
+    let gated: Vec<f64> = blocks.iter().copied().filter(|power| to_lufs(*power) > relative_gate).collect();
+    (!gated.is_empty()).then(|| to_lufs(gated.iter().sum::<f64>() / gated.len() as f64))
+}
+
+fn loudness_range(short_term: &[f64]) -> Option<f64> {
+    if short_term.is_empty() {
+        return None;
+    }
+    let relative_gate = to_lufs(short_term.iter().sum::<f64>() / short_term.len() as f64) + RANGE_RELATIVE_GATE_LU;
+    let mut gated: Vec<f64> = short_term
+        .iter()
+        .map(|power| to_lufs(*power))
+        .filter(|lufs| *lufs > relative_gate)
+        .collect();
+    if gated.is_empty() {
+        return None;
+    }
+    gated.sort_by(f64::total_cmp);
+
+    let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+    Some(percentile(RANGE_HIGH_PERCENTILE) - percentile(RANGE_LOW_PERCENTILE))
+}
+
+// BS.1770 weights surround channels up and ignores LFE. A mono file plays
+// through both speakers, so it's measured as two identical channels.
+fn channel_weight(channel: Channels, count: usize) -> f64 {
+    if count == 1 {
+        return 2.0;
+    }
+    if channel.intersects(Channels::LFE1 | Channels::LFE2) {
+        0.0
+    } else if channel.intersects(Channels::SIDE_LEFT | Channels::SIDE_RIGHT | Channels::REAR_LEFT | Channels::REAR_RIGHT) {
+        1.41
+    } else {
+        1.0
+    }
+}
+
+// High-rate audio already resolves inter-sample peaks well enough
+fn oversampling_for(rate: u32) -> usize {
+    match rate {
+        0..=95_999 => OVERSAMPLING,
+        96_000..=191_999 => OVERSAMPLING / 2,
+        _ => 1,
+    }
+}
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+// The K-weighting pre-filter (a high shelf modelling the head) and RLB
+// high-pass, derived for any sample rate rather than only the 48 kHz
+// coefficients BS.1770 tabulates
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+// Polyphase interpolator: each phase is one slice of a windowed-sinc low
+// pass at the original Nyquist frequency
+struct Oversampler {
+    phases: Vec<Vec<f32>>,
+    history: Vec<f32>,
+}
+
+impl Oversampler {
+    fn new(factor: usize) -> Self {
+        if factor <= 1 {
+            return Oversampler {
+                phases: Vec::new(),
+                history: Vec::new(),
+            };
+        }
+
+        let length = factor * TAPS_PER_PHASE;
+        let centre = (length - 1) as f64 / 2.0;
+        let taps: Vec<f64> = (0..length)
+            .map(|n| {
+                let t = (n as f64 - centre) / factor as f64;
+                let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+                let window = 0.5 - 0.5 * (2.0 * PI * (n as f64 + 0.5) / length as f64).cos();
+                sinc * window
+            })
+            .collect();
+
+        let phases = (0..factor)
+            .map(|phase| (0..TAPS_PER_PHASE).map(|k| taps[phase + k * factor] as f32).collect())
+            .collect();
+
+        Oversampler {
+            phases,
+            history: vec![0.0; TAPS_PER_PHASE],
+        }
+    }
+
+    // The largest absolute value among the sample and its interpolations
+    fn push(&mut self, sample: f32) -> f32 {
+        if self.phases.is_empty() {
+            return sample.abs();
+        }
+
+        self.history.rotate_right(1);
+        self.history[0] = sample;
+
+        self.phases
+            .iter()
+            .map(|phase| phase.iter().zip(&self.history).map(|(tap, x)| tap * x).sum::<f32>().abs())
+            .fold(sample.abs(), f32::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    fn stereo() -> SignalSpec {
+        SignalSpec::new(RATE, Channels::FRONT_LEFT | Channels::FRONT_RIGHT)
+    }
+
+    fn from_lufs(lufs: f64) -> f64 {
+        10f64.powf((lufs + 0.691) / 10.0)
+    }
+
+    // A 1 kHz sine on both channels, the EBU Tech 3341/3342 test signal
+    fn tone(meter: &mut LoudnessMeter, dbfs: f64, seconds: f64) {
+        let amplitude = 10f64.powf(dbfs / 20.0);
+        for n in 0..(seconds * RATE as f64) as u64 {
+            let sample = (amplitude * (2.0 * PI * 1000.0 * n as f64 / RATE as f64).sin()) as f32;
+            meter.push(&[sample, sample]);
+        }
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64, tolerance: f64) {
+        let actual = actual.expect("a measurement");
+        assert!((actual - expected).abs() <= tolerance, "{} is not within {} of {}", actual, tolerance, expected);
+    }
+
+    #[test]
+    fn ebu_reference_tones() {
+        // Tech 3341 test cases 1 and 2
+        for dbfs in [-23.0, -33.0] {
+            let mut meter = LoudnessMeter::new(&stereo());
+            tone(&mut meter, dbfs, 10.0);
+            let loudness = meter.finish();
+
+            assert_close(loudness.integrated_lufs, dbfs, 0.1);
+            assert_close(loudness.true_peak_dbtp, dbfs, 0.2);
+            assert_close(loudness.range_lu, 0.0, 0.1);
+            assert_close(loudness.histogram.integrated_lufs(), dbfs, 0.1);
+        }
+    }
+
+    #[test]
+    fn quiet_passages_are_gated_out() {
+        // Tech 3341 test case 3, shortened
+        let mut meter = LoudnessMeter::new(&stereo());
+        tone(&mut meter, -36.0, 5.0);
+        tone(&mut meter, -23.0, 30.0);
+        tone(&mut meter, -36.0, 5.0);
+        let loudness = meter.finish();
+
+        assert_close(loudness.integrated_lufs, -23.0, 0.1);
+        // The quiet parts are within 20 LU of the mean, so the range still
+        // spans both levels
+        assert_close(loudness.range_lu, 13.0, 1.0);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let mut meter = LoudnessMeter::new(&stereo());
+        for _ in 0..RATE * 5 {
+            meter.push(&[0.0, 0.0]);
+        }
+        let loudness = meter.finish();
+
+        assert_eq!(loudness.integrated_lufs, None);
+        assert_eq!(loudness.range_lu, None);
+        assert_eq!(loudness.true_peak_dbtp, None);
+        assert_eq!(loudness.histogram.integrated_lufs(), None);
+    }
+
+    #[test]
+    fn relative_gate() {
+        let blocks = [from_lufs(-20.0), from_lufs(-20.0), from_lufs(-40.0)];
+        assert_close(gated_mean(&blocks, INTEGRATED_RELATIVE_GATE_LU), -20.0, 1e-9);
+        assert_close(gated_mean(&blocks[..1], INTEGRATED_RELATIVE_GATE_LU), -20.0, 1e-9);
+        assert_eq!(gated_mean(&[], INTEGRATED_RELATIVE_GATE_LU), None);
+    }
+
+    #[test]
+    fn range_is_between_the_10th_and_95th_percentiles() {
+        // Tech 3342 test case 1: 20 s at -20 LUFS, then 20 s at -30 LUFS
+        let mut blocks = vec![from_lufs(-20.0); 200];
+        blocks.extend(vec![from_lufs(-30.0); 200]);
+        assert_close(loudness_range(&blocks), 10.0, 1e-9);
+
+        // Blocks more than 20 LU under the mean don't widen it
+        blocks.extend(vec![from_lufs(-60.0); 50]);
+        assert_close(loudness_range(&blocks), 10.0, 1e-9);
+
+        assert_close(loudness_range(&[from_lufs(-23.0); 10]), 0.0, 1e-9);
+        assert_eq!(loudness_range(&[]), None);
+    }
+
+    #[test]
+    fn merged_histograms_measure_the_album_as_one() {
+        let mut loud = LoudnessHistogram::default();
+        let mut quiet = LoudnessHistogram::default();
+        let mut whole = LoudnessHistogram::default();
+        for (lufs, blocks) in [(-14.0, 300), (-20.0, 100), (-45.0, 50)] {
+            let histogram = if lufs > -16.0 { &mut loud } else { &mut quiet };
+            for _ in 0..blocks {
+                histogram.add(from_lufs(lufs));
+                whole.add(from_lufs(lufs));
+            }
+        }
+
+        let mut album = LoudnessHistogram::default();
+        album.merge(&loud);
+        album.merge(&quiet);
+        assert_close(album.integrated_lufs(), whole.integrated_lufs().unwrap(), 1e-9);
+
+        // The -45 LUFS blocks fall under the album's relative gate
+        let expected = to_lufs((300.0 * from_lufs(-14.0) + 100.0 * from_lufs(-20.0)) / 400.0);
+        assert_close(album.integrated_lufs(), expected, 1e-9);
+
+        let json = serde_json::to_string(&album).unwrap();
+        let restored: LoudnessHistogram = serde_json::from_str(&json).unwrap();
+        assert_close(restored.integrated_lufs(), expected, 1e-9);
+    }
+
+    #[test]
+    fn lfe_is_ignored_and_mono_counts_twice() {
+        assert_eq!(channel_weight(Channels::LFE1, 6), 0.0);
+        assert_eq!(channel_weight(Channels::SIDE_LEFT, 6), 1.41);
+        assert_eq!(channel_weight(Channels::FRONT_LEFT, 2), 1.0);
+        assert_eq!(channel_weight(Channels::FRONT_CENTRE, 1), 2.0);
+    }
+
+    #[test]
+    fn replaygain() {
+        assert_eq!(replaygain_db(-18.0), 0.0);
+        assert_eq!(replaygain_db(-9.0), -9.0);
+        assert_eq!(replaygain_db(-23.0), 5.0);
+    }
+}
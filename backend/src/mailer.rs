@@ -0,0 +1,58 @@
+use chrono::Utc;
+use std::fs;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), String>;
+}
+
+// Default mailer: writes each message as an .eml file instead of talking to SMTP
+pub struct FileMailer {
+    dir: PathBuf,
+    from: String,
+}
+
+impl FileMailer {
+    pub fn new(dir: &str, from: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+            from: from.to_string(),
+        }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), String> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("Failed to create mail directory: {}", e))?;
+
+        let now = Utc::now();
+        let message_id = Uuid::new_v4();
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@spotify-clone>\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+            self.from,
+            email.to,
+            email.subject,
+            now.to_rfc2822(),
+            message_id,
+            email.body.replace('\n', "\r\n"),
+        );
+
+        let path = self
+            .dir
+            .join(format!("{}-{}.eml", now.format("%Y%m%dT%H%M%S"), message_id));
+
+        fs::write(&path, message).map_err(|e| format!("Failed to write email: {}", e))?;
+        log::info!("Wrote email to {}", path.display());
+
+        Ok(())
+    }
+}
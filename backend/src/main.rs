@@ -1,16 +1,38 @@
 mod config;
+mod covers;
+mod errors;
 mod models;
 mod handlers;
+mod jwt_keys;
+mod loudness;
+mod mailer;
+mod media;
+mod mfa;
 mod middleware;
+mod oidc;
+mod rate_limit;
 mod services;
+mod transcode;
 mod utils;
+mod validation;
+
+#[cfg(test)]
+mod test_support;
 
 use actix_cors::Cors;
-use actix_files::Files;
 use actix_web::{middleware::Logger, web, App, HttpServer};
 use dotenv::dotenv;
 use sqlx::postgres::PgPoolOptions;
 use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::mailer::{FileMailer, Mailer};
+use crate::oidc::OidcClient;
+use crate::rate_limit::{InMemoryLimiter, LoginLimiter, PostgresLimiter};
+
+const ACCOUNT_PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+const TRANSCODE_POLL_INTERVAL: Duration = Duration::from_secs(15);
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -38,6 +60,100 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Failed to run migrations");
 
+    // Maintenance commands run against the migrated database and exit
+    if let Some(command) = std::env::args().nth(1) {
+        match command.as_str() {
+            "backfill-audio-info" => {
+                match services::SongService::backfill_audio_info(&pool, &config).await {
+                    Ok((updated, skipped)) => log::info!("Backfilled audio info for {} songs, skipped {}", updated, skipped),
+                    Err(e) => return Err(std::io::Error::other(format!("Audio info backfill failed: {}", e))),
+                }
+                return Ok(());
+            }
+            "backfill-avatars" => {
+                match services::UserService::backfill_avatars(&pool, &config).await {
+                    Ok((updated, skipped)) => log::info!("Re-encoded {} avatars, skipped {}", updated, skipped),
+                    Err(e) => return Err(std::io::Error::other(format!("Avatar backfill failed: {}", e))),
+                }
+                return Ok(());
+            }
+            "backfill-covers" => {
+                match services::SongService::backfill_covers(&pool, &config).await {
+                    Ok((updated, skipped)) => log::info!("Backfilled covers for {} songs, skipped {}", updated, skipped),
+                    Err(e) => return Err(std::io::Error::other(format!("Cover backfill failed: {}", e))),
+                }
+                return Ok(());
+            }
+            other => {
+                return Err(std::io::Error::other(format!(
+                    "Unknown command {:?}; expected backfill-audio-info, backfill-avatars or backfill-covers",
+                    other
+                )))
+            }
+        }
+    }
+
+    if let Some(admin_email) = &config.admin_email {
+        match services::UserService::promote_admin_by_email(&pool, admin_email).await {
+            Ok(true) => log::info!("Promoted {} to admin", admin_email),
+            Ok(false) => log::debug!("Not promoting {}: an admin exists or the address isn't verified", admin_email),
+            Err(e) => log::warn!("Failed to promote {} to admin: {}", admin_email, e),
+        }
+    }
+
+    let mailer: Arc<dyn Mailer> = Arc::new(FileMailer::new(&config.mail_dir, &config.mail_from));
+
+    let limiter: Arc<dyn LoginLimiter> = match config.login_limiter.as_str() {
+        "postgres" => Arc::new(PostgresLimiter::new(pool.clone())),
+        "memory" => Arc::new(InMemoryLimiter::new()),
+        other => panic!("LOGIN_LIMITER must be \"memory\" or \"postgres\", got {:?}", other),
+    };
+
+    let oidc = web::Data::new(OidcClient::new(&config));
+
+    // Hard-deletes accounts whose deletion grace period has run out
+    let purge_pool = pool.clone();
+    let purge_config = config.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(ACCOUNT_PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match services::AccountService::purge_due(&purge_pool, &purge_config).await {
+                Ok(0) => {}
+                Ok(purged) => log::info!("Purged {} deleted accounts", purged),
+                Err(e) => log::error!("Account purge failed: {}", e),
+            }
+        }
+    });
+
+    // Works through the transcoding queue, then waits for new uploads
+    let transcode_pool = pool.clone();
+    let transcode_config = config.clone();
+    actix_web::rt::spawn(async move {
+        if !transcode::ffmpeg_available(&transcode_config.ffmpeg_path).await {
+            log::warn!(
+                "{} is not available; songs will not be transcoded and only the original quality can be streamed",
+                transcode_config.ffmpeg_path
+            );
+            return;
+        }
+
+        let mut interval = actix_web::rt::time::interval(TRANSCODE_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            loop {
+                match services::TranscodeService::process_next(&transcode_pool, &transcode_config).await {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => {
+                        log::error!("Transcoding queue failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
     log::info!("Starting server at http://{}:{}", host, port);
 
     HttpServer::new(move || {
@@ -50,7 +166,18 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(web::Data::new(pool.clone()))
             .app_data(web::Data::new(config.clone()))
-            .wrap(Logger::default())
+            .app_data(web::Data::from(mailer.clone()))
+            .app_data(web::Data::from(limiter.clone()))
+            .app_data(oidc.clone())
+            .app_data(validation::json_config())
+            .app_data(validation::query_config())
+            // Logger::default() minus the query string, which can hold stream tokens
+            .wrap(
+                Logger::new(r#"%a "%{request_line}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+                    .custom_request_replace("request_line", |req| {
+                        format!("{} {} {:?}", req.method(), req.path(), req.version())
+                    }),
+            )
             .wrap(cors)
             .service(
                 web::scope("/api")
@@ -58,8 +185,10 @@ async fn main() -> std::io::Result<()> {
                     .configure(handlers::songs::configure)
                     .configure(handlers::playlists::configure)
                     .configure(handlers::users::configure)
+                    .configure(handlers::admin::configure)
+                    .configure(handlers::covers::configure)
             )
-            .service(Files::new("/uploads", &upload_dir))
+            .configure(handlers::well_known::configure)
     })
     .bind(format!("{}:{}", host, port))?
     .run()
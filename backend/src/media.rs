@@ -0,0 +1,687 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{BufReader, MediaSourceStream};
+use symphonia::core::meta::{MetadataBuilder, MetadataOptions, MetadataRevision, StandardTagKey, StandardVisualKey};
+use symphonia::core::probe::{Hint, ProbeResult};
+use symphonia_metadata::id3v1;
+
+use crate::loudness::{Loudness, LoudnessMeter};
+use crate::utils::image_extension;
+
+// ID3v1 is a fixed-size block at the very end of the file
+const ID3V1_TAG_LENGTH: u64 = 128;
+
+/// Peak resolutions stored per song, finest first. Requests for other
+/// sizes are reduced from the nearest finer one.
+pub const WAVEFORM_RESOLUTIONS: [usize; 3] = [4096, 1024, 256];
+// Bounds the memory spent on peaks regardless of the song's length: once
+// this many bins exist, neighbours are merged and bins cover twice as much
+const MAX_PEAK_BINS: usize = 65536;
+const INITIAL_BIN_FRAMES: u64 = 16;
+
+/// Metadata found in an audio file's own tags. Every field is optional;
+/// anything the file doesn't say is left `None`.
+#[derive(Debug, Default)]
+pub struct EmbeddedTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<i32>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    // Embedded artwork, already checked to be an image format we read
+    pub cover: Option<Vec<u8>>,
+}
+
+impl EmbeddedTags {
+    // Fills only what is still missing, so earlier sources take precedence
+    fn merge(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            let value = tag.value.to_string();
+            let value = clean(&value);
+            if value.is_empty() {
+                continue;
+            }
+
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => fill(&mut self.title, || Some(value.to_string())),
+                Some(StandardTagKey::Artist) => fill(&mut self.artist, || Some(value.to_string())),
+                Some(StandardTagKey::Album) => fill(&mut self.album, || Some(value.to_string())),
+                Some(StandardTagKey::TrackNumber) => fill(&mut self.track_number, || leading_number(value)),
+                Some(StandardTagKey::Date)
+                | Some(StandardTagKey::ReleaseDate)
+                | Some(StandardTagKey::OriginalDate) => fill(&mut self.year, || parse_year(value)),
+                Some(StandardTagKey::Genre) => fill(&mut self.genre, || parse_genre(value)),
+                _ => {}
+            }
+        }
+
+        if self.cover.is_none() {
+            // Prefer the front cover; otherwise any picture will do
+            let visual = revision
+                .visuals()
+                .iter()
+                .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+                .or_else(|| revision.visuals().first());
+
+            self.cover = visual
+                .filter(|visual| image_extension(&visual.data).is_some())
+                .map(|visual| visual.data.to_vec());
+        }
+    }
+
+    // Album artist stands in for a missing track artist, but only once every
+    // source has had its chance to supply the real one
+    fn merge_album_artist(&mut self, revision: &MetadataRevision) {
+        if self.artist.is_some() {
+            return;
+        }
+        self.artist = revision
+            .tags()
+            .iter()
+            .filter(|tag| tag.std_key == Some(StandardTagKey::AlbumArtist))
+            .map(|tag| clean(&tag.value.to_string()).to_string())
+            .find(|value| !value.is_empty());
+    }
+}
+
+// Some writers (RIFF INFO, ID3v2 text frames) leave NUL terminators in
+fn clean(value: &str) -> &str {
+    value.trim_matches(|c: char| c.is_whitespace() || c == '\0')
+}
+
+fn fill<T>(slot: &mut Option<T>, value: impl FnOnce() -> Option<T>) {
+    if slot.is_none() {
+        *slot = value();
+    }
+}
+
+// Track numbers are often written as "3/12"
+fn leading_number(value: &str) -> Option<i32> {
+    let digits: String = value.chars().take_while(char::is_ascii_digit).collect();
+    digits.parse().ok().filter(|number| *number > 0)
+}
+
+// Dates range from "1997" to full ISO timestamps; the year is always first
+fn parse_year(value: &str) -> Option<i32> {
+    let digits: String = value.chars().take_while(char::is_ascii_digit).collect();
+    if digits.len() != 4 {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+// ID3v2.3 genres may be an ID3v1 index in parentheses, e.g. "(17)" or "(17)Rock"
+fn parse_genre(value: &str) -> Option<String> {
+    if let Some(rest) = value.strip_prefix('(') {
+        if let Some((index, name)) = rest.split_once(')') {
+            if let Ok(index) = index.parse::<u8>() {
+                let name = name.trim();
+                if !name.is_empty() {
+                    return Some(name.to_string());
+                }
+                return id3v1::util::genre_name(index).map(|name| name.to_string());
+            }
+        }
+    }
+    Some(value.to_string())
+}
+
+/// Reads the embedded tags of the audio file at `path`.
+///
+/// Sources are consulted in order of richness: metadata inside the
+/// container (Vorbis comments, FLAC, MP4 atoms), then tags prepended to it
+/// (ID3v2), then a trailing ID3v1 block.
+pub fn read_tags(path: &Path) -> Result<EmbeddedTags, String> {
+    let mut probed = open(path)?;
+
+    let mut tags = EmbeddedTags::default();
+
+    let container = probed.format.metadata().current().cloned();
+    let prepended = probed
+        .metadata
+        .get()
+        .and_then(|metadata| metadata.current().cloned());
+    let trailing = read_id3v1(path);
+
+    let revisions: Vec<&MetadataRevision> = [&container, &prepended, &trailing]
+        .into_iter()
+        .flatten()
+        .collect();
+
+    for revision in &revisions {
+        tags.merge(revision);
+    }
+    for revision in &revisions {
+        tags.merge_album_artist(revision);
+    }
+
+    Ok(tags)
+}
+
+/// Stream properties measured by decoding the whole file, not read from
+/// headers, so they hold even for files with missing or lying metadata.
+#[derive(Debug, Clone)]
+pub struct AudioInfo {
+    pub duration_ms: i32,
+    pub codec: String,
+    pub sample_rate: i32,
+    pub channels: i32,
+    // Average over the encoded audio packets, excluding tags and artwork
+    pub bitrate: i32,
+    pub waveform: Waveform,
+    pub loudness: Loudness,
+}
+
+impl AudioInfo {
+    pub fn duration_secs(&self) -> i32 {
+        (self.duration_ms + 500) / 1000
+    }
+}
+
+/// Decodes the file at `path` end to end. Fails if it isn't audio we can
+/// decode, which is what uploads are checked against.
+pub fn probe_audio(path: &Path) -> Result<AudioInfo, String> {
+    let mut probed = open(path)?;
+
+    let track = probed
+        .format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or("No audio track")?;
+    let track_id = track.id;
+
+    let codecs = symphonia::default::get_codecs();
+    let codec = codecs
+        .get_codec(track.codec_params.codec)
+        .map(|descriptor| descriptor.short_name.to_string())
+        .ok_or("Unsupported codec")?;
+    let mut decoder = codecs
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| format!("Unsupported codec: {}", e))?;
+
+    let mut frames: u64 = 0;
+    let mut packet_bytes: u64 = 0;
+    let mut spec = None;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+    let mut peaks = PeakBins::new();
+    let mut meter: Option<LoudnessMeter> = None;
+
+    loop {
+        let packet = match probed.format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(format!("Failed to read audio: {}", e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        packet_bytes += packet.data.len() as u64;
+        match decoder.decode(&packet) {
+            Ok(buffer) => {
+                frames += buffer.frames() as u64;
+                spec.get_or_insert(*buffer.spec());
+
+                let channels = buffer.spec().channels.count().max(1);
+                let samples = match &mut samples {
+                    Some(samples) if samples.capacity() >= buffer.capacity() * channels => samples,
+                    _ => samples.insert(SampleBuffer::new(buffer.capacity() as u64, *buffer.spec())),
+                };
+                let meter = meter.get_or_insert_with(|| LoudnessMeter::new(buffer.spec()));
+                samples.copy_interleaved_ref(buffer);
+                for frame in samples.samples().chunks(channels) {
+                    peaks.push(frame);
+                    meter.push(frame);
+                }
+            }
+            // A damaged packet or two is tolerable; the rest still plays
+            Err(SymphoniaError::DecodeError(e)) => log::debug!("Skipping undecodable packet in {}: {}", path.display(), e),
+            Err(e) => return Err(format!("Failed to decode audio: {}", e)),
+        }
+    }
+
+    let spec = spec.ok_or("No decodable audio")?;
+    if frames == 0 || spec.rate == 0 {
+        return Err("No decodable audio".to_string());
+    }
+
+    let duration_ms = frames * 1000 / spec.rate as u64;
+    let bitrate = packet_bytes * 8 * 1000 / duration_ms.max(1);
+
+    Ok(AudioInfo {
+        duration_ms: i32::try_from(duration_ms).map_err(|_| "Audio is too long")?,
+        codec,
+        sample_rate: spec.rate as i32,
+        channels: spec.channels.count() as i32,
+        bitrate: i32::try_from(bitrate).unwrap_or(i32::MAX),
+        waveform: peaks.waveform(),
+        loudness: meter.map(LoudnessMeter::finish).unwrap_or_default(),
+    })
+}
+
+/// Min/max peaks of a song at each of `WAVEFORM_RESOLUTIONS`, stored as
+/// JSON next to the audio file. Channels are folded together.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Waveform {
+    pub resolutions: Vec<WaveformPeaks>,
+}
+
+impl Waveform {
+    /// Peaks reduced to `points`, from the coarsest stored resolution that
+    /// still has at least that many. Short songs may have fewer points.
+    pub fn peaks(&self, points: usize) -> Option<WaveformPeaks> {
+        let source = self
+            .resolutions
+            .iter()
+            .filter(|peaks| peaks.points() >= points)
+            .min_by_key(|peaks| peaks.points())
+            .or_else(|| self.resolutions.iter().max_by_key(|peaks| peaks.points()))?;
+        Some(source.reduce(points))
+    }
+}
+
+/// Sample values scaled to -127..=127, one min and max per point.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WaveformPeaks {
+    pub min: Vec<i8>,
+    pub max: Vec<i8>,
+}
+
+impl WaveformPeaks {
+    pub fn points(&self) -> usize {
+        self.min.len()
+    }
+
+    fn reduce(&self, points: usize) -> WaveformPeaks {
+        let (min, max) = group(self.points(), points)
+            .map(|range| {
+                let min = self.min[range.clone()].iter().copied().min().unwrap_or(0);
+                let max = self.max[range].iter().copied().max().unwrap_or(0);
+                (min, max)
+            })
+            .unzip();
+        WaveformPeaks { min, max }
+    }
+}
+
+// Splits `len` items into `points` contiguous, near-equal ranges (fewer if
+// there aren't enough items)
+fn group(len: usize, points: usize) -> impl Iterator<Item = std::ops::Range<usize>> {
+    let points = points.min(len);
+    (0..points).map(move |i| i * len / points..(i + 1) * len / points)
+}
+
+// The song's length isn't trusted until it has been decoded, so peaks are
+// collected into fixed-width bins that widen as the song goes on
+struct PeakBins {
+    bins: Vec<(f32, f32)>,
+    bin_frames: u64,
+    current: (f32, f32),
+    filled: u64,
+}
+
+impl PeakBins {
+    fn new() -> Self {
+        PeakBins {
+            bins: Vec::new(),
+            bin_frames: INITIAL_BIN_FRAMES,
+            current: (f32::MAX, f32::MIN),
+            filled: 0,
+        }
+    }
+
+    fn push(&mut self, frame: &[f32]) {
+        for &sample in frame {
+            self.current.0 = self.current.0.min(sample);
+            self.current.1 = self.current.1.max(sample);
+        }
+        self.filled += 1;
+
+        if self.filled == self.bin_frames {
+            self.bins.push(self.current);
+            self.current = (f32::MAX, f32::MIN);
+            self.filled = 0;
+
+            if self.bins.len() == MAX_PEAK_BINS {
+                self.bins = self
+                    .bins
+                    .chunks(2)
+                    .map(|pair| pair.iter().fold((f32::MAX, f32::MIN), |acc, bin| (acc.0.min(bin.0), acc.1.max(bin.1))))
+                    .collect();
+                self.bin_frames *= 2;
+            }
+        }
+    }
+
+    fn waveform(mut self) -> Waveform {
+        if self.filled > 0 {
+            self.bins.push(self.current);
+        }
+
+        let resolutions = WAVEFORM_RESOLUTIONS
+            .iter()
+            .map(|&points| {
+                let (min, max) = group(self.bins.len(), points)
+                    .map(|range| {
+                        let bins = &self.bins[range];
+                        let min = bins.iter().map(|bin| bin.0).fold(f32::MAX, f32::min);
+                        let max = bins.iter().map(|bin| bin.1).fold(f32::MIN, f32::max);
+                        (scale(min), scale(max))
+                    })
+                    .unzip();
+                WaveformPeaks { min, max }
+            })
+            .collect();
+
+        Waveform { resolutions }
+    }
+}
+
+fn scale(sample: f32) -> i8 {
+    (sample.clamp(-1.0, 1.0) * 127.0).round() as i8
+}
+
+/// Writes `waveform` beside the audio at `file_path` (both relative to the
+/// upload directory) and returns where it went, e.g. `songs/{file}.waveform.json`.
+pub fn save_waveform(upload_dir: &str, file_path: &str, waveform: &Waveform) -> Result<String, String> {
+    let waveform_path = format!("{}.waveform.json", Path::new(file_path).with_extension("").display());
+    let full_path = format!("{}/{}", upload_dir, waveform_path);
+    let json = serde_json::to_vec(waveform).map_err(|e| format!("Failed to encode waveform: {}", e))?;
+    std::fs::write(&full_path, json).map_err(|e| format!("Failed to write {}: {}", full_path, e))?;
+    Ok(waveform_path)
+}
+
+pub fn load_waveform(upload_dir: &str, waveform_path: &str) -> Result<Waveform, String> {
+    let full_path = format!("{}/{}", upload_dir, waveform_path);
+    let json = std::fs::read(&full_path).map_err(|e| format!("Failed to read {}: {}", full_path, e))?;
+    serde_json::from_slice(&json).map_err(|e| format!("Invalid waveform {}: {}", full_path, e))
+}
+
+/// The `Content-Type` to serve a stored song with. The probed codec decides
+/// where it's known; older rows fall back to the file extension.
+pub fn content_type(codec: Option<&str>, file_path: &str) -> mime::Mime {
+    let extension = Path::new(file_path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    let essence = match (codec, extension.as_str()) {
+        (Some("mp1" | "mp2" | "mp3"), _) => "audio/mpeg",
+        (Some("flac"), "ogg" | "oga") => "audio/ogg",
+        (Some("flac"), _) => "audio/flac",
+        (Some("vorbis"), _) => "audio/ogg",
+        // AAC is either raw ADTS or inside an MP4 container
+        (Some("aac"), "aac") => "audio/aac",
+        (Some("aac" | "alac"), _) => "audio/mp4",
+        (Some(codec), "aif" | "aiff") if codec.starts_with("pcm") => "audio/aiff",
+        (Some(codec), _) if codec.starts_with("pcm") || codec.starts_with("adpcm") => "audio/wav",
+        (_, "mp3") => "audio/mpeg",
+        (_, "m4a" | "mp4" | "m4b") => "audio/mp4",
+        (_, "aac") => "audio/aac",
+        (_, "flac") => "audio/flac",
+        (_, "ogg" | "oga" | "opus") => "audio/ogg",
+        (_, "wav") => "audio/wav",
+        (_, "aif" | "aiff") => "audio/aiff",
+        _ => "application/octet-stream",
+    };
+
+    essence.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM)
+}
+
+/// The extension an upload is stored under, from its container's magic
+/// bytes and the probed codec. The client's filename plays no part.
+pub fn audio_extension(path: &Path, codec: &str) -> Result<&'static str, String> {
+    let mut header = Vec::with_capacity(12);
+    File::open(path)
+        .and_then(|file| file.take(12).read_to_end(&mut header))
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(container_extension(&header, codec))
+}
+
+fn container_extension(header: &[u8], codec: &str) -> &'static str {
+    match header {
+        [b'O', b'g', b'g', b'S', ..] => "ogg",
+        [b'f', b'L', b'a', b'C', ..] => "flac",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => "wav",
+        [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', b'F' | b'C', ..] => "aiff",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => "m4a",
+        // Otherwise a bare stream, possibly behind an ID3v2 tag
+        _ => match codec {
+            "aac" => "aac",
+            "flac" => "flac",
+            _ => "mp3",
+        },
+    }
+}
+
+fn open(path: &Path) -> Result<ProbeResult, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    // Gapless trimming drops encoder delay and padding, so durations are exact
+    let format_options = FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    };
+
+    symphonia::default::get_probe()
+        .format(&hint, stream, &format_options, &MetadataOptions::default())
+        .map_err(|e| format!("Unrecognised audio format: {}", e))
+}
+
+fn read_id3v1(path: &Path) -> Option<MetadataRevision> {
+    let mut file = File::open(path).ok()?;
+    if file.metadata().ok()?.len() < ID3V1_TAG_LENGTH {
+        return None;
+    }
+
+    let mut block = [0u8; ID3V1_TAG_LENGTH as usize];
+    file.seek(SeekFrom::End(-(ID3V1_TAG_LENGTH as i64))).ok()?;
+    file.read_exact(&mut block).ok()?;
+
+    let mut builder = MetadataBuilder::new();
+    id3v1::read_id3v1(&mut BufReader::new(&block), &mut builder).ok()?;
+    Some(builder.metadata())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::meta::{Tag, Value};
+
+    fn revision(tags: &[(Option<StandardTagKey>, &str)]) -> MetadataRevision {
+        let mut builder = MetadataBuilder::new();
+        for (std_key, value) in tags {
+            builder.add_tag(Tag::new(*std_key, "", Value::from(*value)));
+        }
+        builder.metadata()
+    }
+
+    fn ranges(len: usize, points: usize) -> Vec<std::ops::Range<usize>> {
+        group(len, points).collect()
+    }
+
+    #[test]
+    fn group_splits_into_contiguous_ranges() {
+        assert_eq!(ranges(10, 3), [0..3, 3..6, 6..10]);
+        assert_eq!(ranges(4, 4), [0..1, 1..2, 2..3, 3..4]);
+        assert_eq!(ranges(2, 5), [0..1, 1..2]);
+        assert!(ranges(0, 4).is_empty());
+
+        for (len, points) in [(65536, 4096), (1000, 256), (4097, 4096), (255, 256)] {
+            let ranges = ranges(len, points);
+            assert_eq!(ranges.len(), points.min(len));
+            assert_eq!(ranges.first().unwrap().start, 0);
+            assert_eq!(ranges.last().unwrap().end, len);
+            assert!(ranges.windows(2).all(|pair| pair[0].end == pair[1].start));
+            assert!(ranges.iter().all(|range| !range.is_empty()));
+        }
+    }
+
+    #[test]
+    fn peaks_fold_channels_and_keep_a_partial_bin() {
+        let mut bins = PeakBins::new();
+        for i in 0..INITIAL_BIN_FRAMES * 2 + 1 {
+            let sample = if i == 3 { 1.0 } else { 0.25 };
+            bins.push(&[sample, -0.5]);
+        }
+        bins.push(&[2.0, -3.0]);
+
+        let waveform = bins.waveform();
+        assert_eq!(waveform.resolutions.len(), WAVEFORM_RESOLUTIONS.len());
+        for peaks in &waveform.resolutions {
+            // Three bins, so every resolution is limited to three points
+            assert_eq!(peaks.min, [-64, -64, -127]);
+            assert_eq!(peaks.max, [127, 32, 127]);
+        }
+    }
+
+    #[test]
+    fn peak_bins_merge_instead_of_growing() {
+        let mut bins = PeakBins::new();
+        let frames = MAX_PEAK_BINS as u64 * INITIAL_BIN_FRAMES;
+        for i in 0..frames {
+            let sample = if i == frames - 1 { -1.0 } else { (i % 7) as f32 / 100.0 };
+            bins.push(&[sample]);
+        }
+
+        assert_eq!(bins.bins.len(), MAX_PEAK_BINS / 2);
+        assert_eq!(bins.bin_frames, INITIAL_BIN_FRAMES * 2);
+
+        // The very last frame still shows up
+        let waveform = bins.waveform();
+        let finest = &waveform.resolutions[0];
+        assert_eq!(finest.points(), WAVEFORM_RESOLUTIONS[0]);
+        assert_eq!(*finest.min.last().unwrap(), -127);
+        assert_eq!(finest.max[0], 8);
+    }
+
+    #[test]
+    fn waveform_reduces_from_the_nearest_finer_resolution() {
+        let peaks = |min: Vec<i8>, max: Vec<i8>| WaveformPeaks { min, max };
+        let waveform = Waveform {
+            resolutions: vec![
+                peaks(vec![-1, -2, -3, -4, -5, -6, -7, -8], vec![1, 2, 3, 4, 5, 6, 7, 8]),
+                peaks(vec![-10, -20, -30, -40], vec![10, 20, 30, 40]),
+            ],
+        };
+
+        let reduced = waveform.peaks(2).unwrap();
+        assert_eq!(reduced.min, [-20, -40]);
+        assert_eq!(reduced.max, [20, 40]);
+
+        let reduced = waveform.peaks(8).unwrap();
+        assert_eq!(reduced.max, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // More points than stored: the finest there is
+        assert_eq!(waveform.peaks(100).unwrap().points(), 8);
+        assert!(Waveform { resolutions: Vec::new() }.peaks(10).is_none());
+    }
+
+    #[test]
+    fn track_numbers() {
+        assert_eq!(leading_number("3"), Some(3));
+        assert_eq!(leading_number("03/12"), Some(3));
+        assert_eq!(leading_number("12 of 14"), Some(12));
+        assert_eq!(leading_number("0"), None);
+        assert_eq!(leading_number("A1"), None);
+        assert_eq!(leading_number(""), None);
+        assert_eq!(leading_number("99999999999"), None);
+    }
+
+    #[test]
+    fn years() {
+        assert_eq!(parse_year("1997"), Some(1997));
+        assert_eq!(parse_year("1997-05-21"), Some(1997));
+        assert_eq!(parse_year("2004-01-01T00:00:00Z"), Some(2004));
+        assert_eq!(parse_year("97"), None);
+        assert_eq!(parse_year("19970521"), None);
+        assert_eq!(parse_year("May 1997"), None);
+    }
+
+    #[test]
+    fn genres() {
+        assert_eq!(parse_genre("Shoegaze").as_deref(), Some("Shoegaze"));
+        assert_eq!(parse_genre("(17)").as_deref(), Some("Rock"));
+        assert_eq!(parse_genre("(0)").as_deref(), Some("Blues"));
+        // A refinement after the index wins over the index's name
+        assert_eq!(parse_genre("(17)Britpop").as_deref(), Some("Britpop"));
+        assert_eq!(parse_genre("(17) ").as_deref(), Some("Rock"));
+        assert_eq!(parse_genre("(255)"), None);
+        assert_eq!(parse_genre("(RX)").as_deref(), Some("(RX)"));
+        assert_eq!(parse_genre("(Live) Jazz").as_deref(), Some("(Live) Jazz"));
+    }
+
+    #[test]
+    fn merge_fills_only_missing_fields() {
+        let mut tags = EmbeddedTags::default();
+        tags.merge(&revision(&[
+            (Some(StandardTagKey::TrackTitle), "Title\0"),
+            (Some(StandardTagKey::TrackNumber), "4/10"),
+            (Some(StandardTagKey::Date), "2001-09-11"),
+            (Some(StandardTagKey::Genre), "   "),
+        ]));
+        tags.merge(&revision(&[
+            (Some(StandardTagKey::TrackTitle), "Other title"),
+            (Some(StandardTagKey::Artist), "Artist"),
+            (Some(StandardTagKey::Genre), "(8)"),
+        ]));
+
+        assert_eq!(tags.title.as_deref(), Some("Title"));
+        assert_eq!(tags.artist.as_deref(), Some("Artist"));
+        assert_eq!(tags.track_number, Some(4));
+        assert_eq!(tags.year, Some(2001));
+        assert_eq!(tags.genre.as_deref(), Some("Jazz"));
+    }
+
+    #[test]
+    fn album_artist_is_a_fallback() {
+        let album_artist = revision(&[(Some(StandardTagKey::AlbumArtist), "Various Artists")]);
+
+        let mut tags = EmbeddedTags::default();
+        tags.merge_album_artist(&album_artist);
+        assert_eq!(tags.artist.as_deref(), Some("Various Artists"));
+
+        let mut tags = EmbeddedTags {
+            artist: Some("Artist".to_string()),
+            ..Default::default()
+        };
+        tags.merge_album_artist(&album_artist);
+        assert_eq!(tags.artist.as_deref(), Some("Artist"));
+    }
+
+    #[test]
+    fn extension_comes_from_the_container() {
+        assert_eq!(container_extension(b"OggS\0\x02\0\0\0\0\0\0", "vorbis"), "ogg");
+        assert_eq!(container_extension(b"OggS\0\x02\0\0\0\0\0\0", "flac"), "ogg");
+        assert_eq!(container_extension(b"fLaC\0\0\0\x22", "flac"), "flac");
+        assert_eq!(container_extension(b"RIFF\x24\0\0\0WAVE", "pcm_s16le"), "wav");
+        assert_eq!(container_extension(b"FORM\x24\0\0\0AIFC", "pcm_s16be"), "aiff");
+        assert_eq!(container_extension(b"\0\0\0\x20ftypM4A ", "aac"), "m4a");
+        assert_eq!(container_extension(b"\0\0\0\x20ftypM4A ", "alac"), "m4a");
+    }
+
+    #[test]
+    fn bare_streams_are_named_by_codec() {
+        assert_eq!(container_extension(b"ID3\x04\0\0\0\0\x10\0", "mp3"), "mp3");
+        assert_eq!(container_extension(b"\xff\xfb\x90\x64", "mp3"), "mp3");
+        assert_eq!(container_extension(b"\xff\xf1\x50\x80", "aac"), "aac");
+        assert_eq!(container_extension(b"ID3\x04\0\0\0\0\x10\0", "flac"), "flac");
+        assert_eq!(container_extension(b"", "mp3"), "mp3");
+    }
+}
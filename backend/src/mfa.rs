@@ -0,0 +1,210 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+
+// RFC 6238 defaults, which is what every authenticator app expects
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// Accept one step either side to tolerate clock drift
+const TOTP_SKEW_STEPS: i64 = 1;
+const SECRET_BYTES: usize = 20;
+const NONCE_BYTES: usize = 12;
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_BYTES];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+pub fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(issuer),
+        percent_encode(account),
+        encode_secret(secret),
+        percent_encode(issuer),
+        TOTP_DIGITS,
+        TOTP_STEP_SECONDS,
+    )
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn totp_at_step(secret: &[u8], step: i64) -> u32 {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation (RFC 4226 section 5.3)
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = ((hash[offset] as u32 & 0x7f) << 24)
+        | ((hash[offset + 1] as u32) << 16)
+        | ((hash[offset + 2] as u32) << 8)
+        | (hash[offset + 3] as u32);
+
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Checks `code` against the secret at `unix_time`. Returns the matching time
+/// step so callers can reject a code that has already been used.
+pub fn verify_totp(secret: &[u8], code: &str, unix_time: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current = unix_time / TOTP_STEP_SECONDS;
+    (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS)
+        .find(|&step| totp_at_step(secret, step) == code)
+}
+
+// Secrets are stored as hex(nonce || ciphertext) using AES-256-GCM
+pub fn encrypt_secret(key: &[u8], secret: &[u8]) -> Result<String, String> {
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| "Invalid MFA encryption key".to_string())?;
+
+    let mut nonce = [0u8; NONCE_BYTES];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), secret)
+        .map_err(|_| "Failed to encrypt MFA secret".to_string())?;
+
+    let mut stored = nonce.to_vec();
+    stored.extend_from_slice(&ciphertext);
+    Ok(hex::encode(stored))
+}
+
+pub fn decrypt_secret(key: &[u8], stored: &str) -> Result<Vec<u8>, String> {
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| "Invalid MFA encryption key".to_string())?;
+
+    let bytes = hex::decode(stored).map_err(|_| "Corrupt MFA secret".to_string())?;
+    if bytes.len() <= NONCE_BYTES {
+        return Err("Corrupt MFA secret".to_string());
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_BYTES);
+
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Failed to decrypt MFA secret".to_string())
+}
+
+// Recovery codes look like `abcde-fghij`; only their hashes are stored
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 7];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let encoded = BASE32_NOPAD.encode(&bytes).to_lowercase();
+            format!("{}-{}", &encoded[..5], &encoded[5..10])
+        })
+        .collect()
+}
+
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.trim()
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA-1 seed from RFC 6238 appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn totp_matches_rfc_6238_vectors() {
+        // The RFC lists 8-digit codes; 6-digit codes are their last six digits
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+
+        for (time, code) in vectors {
+            assert_eq!(
+                verify_totp(RFC_SECRET, code, time),
+                Some(time / TOTP_STEP_SECONDS),
+                "code at t={}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn totp_accepts_one_step_of_skew() {
+        let time = 1111111111;
+        let code = "050471";
+
+        assert_eq!(verify_totp(RFC_SECRET, code, time - 30), Some(time / TOTP_STEP_SECONDS));
+        assert_eq!(verify_totp(RFC_SECRET, code, time + 30), Some(time / TOTP_STEP_SECONDS));
+        assert_eq!(verify_totp(RFC_SECRET, code, time - 60), None);
+        assert_eq!(verify_totp(RFC_SECRET, code, time + 60), None);
+    }
+
+    #[test]
+    fn totp_rejects_malformed_codes() {
+        let time = 1111111111;
+
+        assert_eq!(verify_totp(RFC_SECRET, " 050471 ", time), Some(time / TOTP_STEP_SECONDS));
+        assert_eq!(verify_totp(RFC_SECRET, "50471", time), None);
+        assert_eq!(verify_totp(RFC_SECRET, "0050471", time), None);
+        assert_eq!(verify_totp(RFC_SECRET, "+50471", time), None);
+        assert_eq!(verify_totp(RFC_SECRET, "", time), None);
+    }
+
+    #[test]
+    fn secrets_round_trip_through_encryption() {
+        let key = [7u8; 32];
+        let secret = generate_secret();
+
+        let stored = encrypt_secret(&key, &secret).unwrap();
+        assert_eq!(decrypt_secret(&key, &stored).unwrap(), secret);
+        assert!(decrypt_secret(&[8u8; 32], &stored).is_err());
+        assert!(decrypt_secret(&key, &stored[..NONCE_BYTES * 2]).is_err());
+    }
+
+    #[test]
+    fn recovery_codes_are_distinct_and_normalize_as_typed() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+
+        let mut unique = codes.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), RECOVERY_CODE_COUNT);
+
+        for code in &codes {
+            assert_eq!(code.len(), 11);
+            assert_eq!(&code[5..6], "-");
+            let typed = format!("  {} ", code.to_uppercase().replace('-', " "));
+            assert_eq!(normalize_recovery_code(&typed), normalize_recovery_code(code));
+        }
+    }
+}
@@ -1,30 +1,474 @@
-use actix_web::{dev::ServiceRequest, Error, HttpMessage};
-use actix_web_httpauth::extractors::bearer::{BearerAuth, Config};
-use actix_web_httpauth::extractors::AuthenticationError;
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use actix_web::dev::Payload;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use std::env;
+use sqlx::PgPool;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
 use uuid::Uuid;
 
+use crate::config::Config;
+use crate::errors::ErrorCode;
+use crate::jwt_keys::JwtKeys;
+use crate::models::{Role, Scope};
+use crate::services::{AccessTokenService, SessionService};
+use crate::utils::ACCESS_TOKEN_PREFIX;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // user_id
+    pub sid: String, // session_id
+    #[serde(default)]
+    pub role: Role,
     pub exp: usize,
 }
 
-// Simple JWT validation function for use in handlers
-pub fn validate_jwt(token: &str) -> Result<Uuid, String> {
-    let jwt_secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+// Decodes and verifies an access token, then checks that its session
+// hasn't been revoked; used by the auth extractors below
+pub async fn validate_jwt(pool: &PgPool, keys: &JwtKeys, token: &str) -> Result<AuthUser, AuthError> {
+    let token_data = keys.decode::<Claims>(token).map_err(AuthError::InvalidToken)?;
+
+    let id = Uuid::parse_str(&token_data.claims.sub)
+        .map_err(|_| AuthError::InvalidToken("Invalid user ID in token".to_string()))?;
+    let session_id = Uuid::parse_str(&token_data.claims.sid)
+        .map_err(|_| AuthError::InvalidToken("Invalid session ID in token".to_string()))?;
+
+    if !SessionService::touch(pool, session_id, id).await.map_err(|e| AuthError::Internal(e.to_string()))? {
+        return Err(AuthError::InvalidToken("Session has been revoked".to_string()));
+    }
+
+    Ok(AuthUser {
+        id,
+        role: token_data.claims.role,
+        session_id,
+    })
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken(String),
+    Forbidden,
+    InsufficientScope(Scope),
+    AccessTokenNotAllowed,
+    Internal(String),
+}
 
-    match decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(jwt_secret.as_bytes()),
-        &Validation::default(),
-    ) {
-        Ok(token_data) => {
-            Uuid::parse_str(&token_data.claims.sub)
-                .map_err(|_| "Invalid user ID in token".to_string())
+impl AuthError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AuthError::MissingToken => ErrorCode::Unauthenticated,
+            AuthError::InvalidToken(_) => ErrorCode::InvalidToken,
+            AuthError::Forbidden | AuthError::AccessTokenNotAllowed => ErrorCode::Forbidden,
+            AuthError::InsufficientScope(_) => ErrorCode::InsufficientScope,
+            AuthError::Internal(_) => ErrorCode::Internal,
         }
-        Err(_) => Err("Invalid token".to_string()),
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "Missing Authorization header"),
+            AuthError::InvalidToken(e) => write!(f, "{}", e),
+            AuthError::Forbidden => write!(f, "You don't have permission to do this"),
+            AuthError::InsufficientScope(scope) => {
+                write!(f, "This token is missing the {} scope", scope.as_str())
+            }
+            AuthError::AccessTokenNotAllowed => {
+                write!(f, "Personal access tokens can't be used for this endpoint")
+            }
+            AuthError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Forbidden
+            | AuthError::InsufficientScope(_)
+            | AuthError::AccessTokenNotAllowed => StatusCode::FORBIDDEN,
+            AuthError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+
+        // RFC 6750: tell the client how to authenticate
+        match self {
+            AuthError::MissingToken => {
+                response.insert_header((header::WWW_AUTHENTICATE, "Bearer realm=\"api\""));
+            }
+            AuthError::InvalidToken(e) => {
+                response.insert_header((
+                    header::WWW_AUTHENTICATE,
+                    format!(
+                        "Bearer realm=\"api\", error=\"invalid_token\", error_description=\"{}\"",
+                        e.replace('"', "'")
+                    ),
+                ));
+            }
+            AuthError::InsufficientScope(scope) => {
+                response.insert_header((
+                    header::WWW_AUTHENTICATE,
+                    format!(
+                        "Bearer realm=\"api\", error=\"insufficient_scope\", scope=\"{}\"",
+                        scope.as_str()
+                    ),
+                ));
+            }
+            AuthError::Forbidden | AuthError::AccessTokenNotAllowed | AuthError::Internal(_) => {}
+        }
+
+        response.json(serde_json::json!({
+            "error": self.to_string(),
+            "code": self.code().as_str()
+        }))
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Result<Option<String>, AuthError> {
+    let auth_header = match req.headers().get(header::AUTHORIZATION) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    let auth_header = auth_header
+        .to_str()
+        .map_err(|_| AuthError::InvalidToken("Invalid Authorization header format".to_string()))?;
+
+    auth_header
+        .strip_prefix("Bearer ")
+        .map(|token| Some(token.to_string()))
+        .ok_or_else(|| AuthError::InvalidToken("Invalid Authorization header format".to_string()))
+}
+
+fn check_role(role: Role, required: Role) -> Result<(), AuthError> {
+    if role.satisfies(required) {
+        Ok(())
+    } else {
+        Err(AuthError::Forbidden)
+    }
+}
+
+/// The caller identified by a session JWT. Declaring it as a handler
+/// argument makes the route require authentication. Personal access tokens
+/// are rejected here; routes that accept them use `Scoped` instead.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthUser {
+    pub id: Uuid,
+    pub role: Role,
+    pub session_id: Uuid,
+}
+
+impl AuthUser {
+    pub fn require_role(&self, required: Role) -> Result<(), AuthError> {
+        check_role(self.role, required)
+    }
+}
+
+fn is_access_token(token: &str) -> bool {
+    token.starts_with(ACCESS_TOKEN_PREFIX)
+}
+
+fn pool_from_request(req: &HttpRequest) -> Result<web::Data<PgPool>, AuthError> {
+    req.app_data::<web::Data<PgPool>>()
+        .cloned()
+        .ok_or_else(|| AuthError::Internal("Database pool is not configured".to_string()))
+}
+
+fn config_from_request(req: &HttpRequest) -> Result<web::Data<Config>, AuthError> {
+    req.app_data::<web::Data<Config>>()
+        .cloned()
+        .ok_or_else(|| AuthError::Internal("Config is not configured".to_string()))
+}
+
+impl FromRequest for AuthUser {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = bearer_token(req);
+        let pool = pool_from_request(req);
+        let config = config_from_request(req);
+
+        Box::pin(async move {
+            match token? {
+                Some(token) if is_access_token(&token) => Err(AuthError::AccessTokenNotAllowed),
+                Some(token) => validate_jwt(pool?.get_ref(), &config?.jwt_keys, &token).await,
+                None => Err(AuthError::MissingToken),
+            }
+        })
+    }
+}
+
+/// A scope a route requires from personal access tokens; see `scope`.
+pub trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+pub mod scope {
+    use super::RequiredScope;
+    use crate::models::Scope;
+
+    macro_rules! scope_markers {
+        ($($name:ident),* $(,)?) => {
+            $(
+                pub struct $name;
+
+                impl RequiredScope for $name {
+                    const SCOPE: Scope = Scope::$name;
+                }
+            )*
+        };
+    }
+
+    scope_markers!(SongsRead, SongsWrite, PlaylistsRead, PlaylistsWrite, ProfileRead);
+}
+
+/// An authenticated caller that may be using either a session JWT or a
+/// personal access token. Tokens must carry `S::SCOPE`; session JWTs have
+/// full access.
+pub struct Scoped<S: RequiredScope> {
+    pub id: Uuid,
+    pub role: Role,
+    _scope: PhantomData<S>,
+}
+
+impl<S: RequiredScope> Scoped<S> {
+    pub fn require_role(&self, required: Role) -> Result<(), AuthError> {
+        check_role(self.role, required)
+    }
+}
+
+async fn validate_access_token(pool: &PgPool, token: &str, required: Scope) -> Result<(Uuid, Role), AuthError> {
+    let (user_id, role, scopes) = AccessTokenService::authenticate(pool, token)
+        .await
+        .map_err(|e| AuthError::Internal(e.to_string()))?
+        .ok_or_else(|| AuthError::InvalidToken("Invalid or expired access token".to_string()))?;
+
+    if !scopes.contains(&required) {
+        return Err(AuthError::InsufficientScope(required));
+    }
+
+    Ok((user_id, role))
+}
+
+impl<S: RequiredScope + 'static> FromRequest for Scoped<S> {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = bearer_token(req);
+        let pool = pool_from_request(req);
+        let config = config_from_request(req);
+
+        Box::pin(async move {
+            let (id, role) = match token? {
+                Some(token) if is_access_token(&token) => {
+                    validate_access_token(pool?.get_ref(), &token, S::SCOPE).await?
+                }
+                Some(token) => {
+                    let user = validate_jwt(pool?.get_ref(), &config?.jwt_keys, &token).await?;
+                    (user.id, user.role)
+                }
+                None => return Err(AuthError::MissingToken),
+            };
+
+            Ok(Scoped {
+                id,
+                role,
+                _scope: PhantomData,
+            })
+        })
+    }
+}
+
+/// Like `AuthUser`, but anonymous callers are let through as `None`.
+/// A token that is present but invalid is still rejected. Personal access
+/// tokens carry no identity here and are treated as anonymous.
+#[derive(Debug, Clone, Copy)]
+pub struct MaybeAuthUser(pub Option<AuthUser>);
+
+impl FromRequest for MaybeAuthUser {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = bearer_token(req);
+        let pool = pool_from_request(req);
+        let config = config_from_request(req);
+
+        Box::pin(async move {
+            match token? {
+                Some(token) if is_access_token(&token) => Ok(MaybeAuthUser(None)),
+                Some(token) => validate_jwt(pool?.get_ref(), &config?.jwt_keys, &token)
+                    .await
+                    .map(|user| MaybeAuthUser(Some(user))),
+                None => Ok(MaybeAuthUser(None)),
+            }
+        })
+    }
+}
+
+/// Where a request came from, recorded against new sessions.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+impl FromRequest for ClientInfo {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(|ua| ua.chars().take(512).collect());
+
+        let trusted_proxies = req
+            .app_data::<web::Data<Config>>()
+            .map(|config| config.trusted_proxies.as_slice())
+            .unwrap_or_default();
+        let ip_address = client_ip(req, trusted_proxies).map(|ip| ip.to_string());
+
+        ready(Ok(ClientInfo { user_agent, ip_address }))
+    }
+}
+
+// The peer of the connection, unless that peer is one of our own proxies, in
+// which case the forwarding chain is walked back to the first hop we don't
+// trust. Anyone can send these headers, so hops added before that point
+// (including a spoofed client address) are ignored.
+fn client_ip(req: &HttpRequest, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let headers = req.headers();
+
+    let forwarded: Vec<&str> = if headers.contains_key(header::FORWARDED) {
+        headers
+            .get_all(header::FORWARDED)
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then_some(value)
+                })
+            })
+            .collect()
+    } else {
+        headers
+            .get_all("x-forwarded-for")
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .collect()
+    };
+
+    Some(resolve_client_ip(peer, &forwarded, trusted_proxies))
+}
+
+fn resolve_client_ip(peer: IpAddr, forwarded: &[&str], trusted_proxies: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    let mut client = peer;
+    for hop in forwarded.iter().rev() {
+        if !is_trusted(&client) {
+            break;
+        }
+        // An unparseable hop (obfuscated, "unknown" or garbage) ends the
+        // chain; falling back to the proxy beats trusting what's past it
+        match parse_forwarded_ip(hop) {
+            Some(ip) => client = ip,
+            None => break,
+        }
+    }
+    client
+}
+
+// Accepts `1.2.3.4`, `1.2.3.4:5678`, `[2001:db8::1]:80` and `"[2001:db8::1]"`
+fn parse_forwarded_ip(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|socket| socket.ip()))
+        .or_else(|_| hop.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn proxies() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::1/128".parse().unwrap()]
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_headers_are_ignored_from_untrusted_peers() {
+        let req = TestRequest::default()
+            .peer_addr("203.0.113.7:50000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "198.51.100.1"))
+            .insert_header((header::FORWARDED, "for=198.51.100.2"))
+            .to_http_request();
+
+        assert_eq!(client_ip(&req, &proxies()), Some(ip("203.0.113.7")));
+        assert_eq!(client_ip(&req, &[]), Some(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn trusted_proxy_forwards_the_client_address() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:50000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "198.51.100.1"))
+            .to_http_request();
+
+        assert_eq!(client_ip(&req, &proxies()), Some(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn spoofed_hops_before_the_first_untrusted_one_are_skipped() {
+        // The client sent "1.1.1.1" itself; our proxies appended the rest
+        let forwarded = ["1.1.1.1", "198.51.100.1", "10.0.0.3"];
+        assert_eq!(resolve_client_ip(ip("10.0.0.2"), &forwarded, &proxies()), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn forwarded_header_is_preferred_and_parsed() {
+        let req = TestRequest::default()
+            .peer_addr("[2001:db8::1]:443".parse().unwrap())
+            .insert_header((header::FORWARDED, "for=192.0.2.60;proto=http, For=\"[2001:db8:cafe::17]:4711\""))
+            .insert_header(("X-Forwarded-For", "198.51.100.1"))
+            .to_http_request();
+
+        assert_eq!(client_ip(&req, &proxies()), Some(ip("2001:db8:cafe::17")));
+    }
+
+    #[test]
+    fn unparseable_hop_stops_at_the_proxy() {
+        let forwarded = ["198.51.100.1", "unknown"];
+        assert_eq!(resolve_client_ip(ip("10.0.0.2"), &forwarded, &proxies()), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn forwarded_ips_may_carry_ports_and_brackets() {
+        assert_eq!(parse_forwarded_ip(" 192.0.2.1 "), Some(ip("192.0.2.1")));
+        assert_eq!(parse_forwarded_ip("192.0.2.1:8080"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_forwarded_ip("\"[2001:db8::2]:80\""), Some(ip("2001:db8::2")));
+        assert_eq!(parse_forwarded_ip("[2001:db8::2]"), Some(ip("2001:db8::2")));
+        assert_eq!(parse_forwarded_ip("_hidden"), None);
     }
 }
@@ -2,6 +2,127 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use uuid::Uuid;
+use validator::Validate;
+
+use crate::covers::{cover_id, COVER_SIZES};
+use crate::validation::{validate_not_blank, validate_password, validate_username};
+
+// Roles are ordered: every role can do what the ones before it can
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Listener,
+    Artist,
+    Admin,
+}
+
+impl Role {
+    pub fn satisfies(self, required: Role) -> bool {
+        self >= required
+    }
+}
+
+// Bitrate ladder for playback. `Original` is the uploaded file itself.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "stream_quality", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum StreamQuality {
+    Low,
+    Normal,
+    High,
+    #[default]
+    Original,
+}
+
+impl StreamQuality {
+    // The renditions the transcoder produces, lowest first
+    pub const TRANSCODED: [StreamQuality; 3] = [StreamQuality::Low, StreamQuality::Normal, StreamQuality::High];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StreamQuality::Low => "low",
+            StreamQuality::Normal => "normal",
+            StreamQuality::High => "high",
+            StreamQuality::Original => "original",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<StreamQuality> {
+        match value {
+            "low" => Some(StreamQuality::Low),
+            "normal" => Some(StreamQuality::Normal),
+            "high" => Some(StreamQuality::High),
+            "original" => Some(StreamQuality::Original),
+            _ => None,
+        }
+    }
+
+    pub fn bitrate_kbps(&self) -> Option<u32> {
+        match self {
+            StreamQuality::Low => Some(96),
+            StreamQuality::Normal => Some(160),
+            StreamQuality::High => Some(320),
+            StreamQuality::Original => None,
+        }
+    }
+}
+
+// Lets `songs.renditions` (a stream_quality[]) decode into a Vec
+impl sqlx::postgres::PgHasArrayType for StreamQuality {
+    fn array_type_info() -> sqlx::postgres::PgTypeInfo {
+        sqlx::postgres::PgTypeInfo::with_name("_stream_quality")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "transcode_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum TranscodeStatus {
+    Pending,
+    Processing,
+    Ready,
+    Failed,
+}
+
+// Permissions a personal access token can be granted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "songs:read")]
+    SongsRead,
+    #[serde(rename = "songs:write")]
+    SongsWrite,
+    #[serde(rename = "playlists:read")]
+    PlaylistsRead,
+    #[serde(rename = "playlists:write")]
+    PlaylistsWrite,
+    #[serde(rename = "profile:read")]
+    ProfileRead,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::SongsRead => "songs:read",
+            Scope::SongsWrite => "songs:write",
+            Scope::PlaylistsRead => "playlists:read",
+            Scope::PlaylistsWrite => "playlists:write",
+            Scope::ProfileRead => "profile:read",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Scope> {
+        match value {
+            "songs:read" => Some(Scope::SongsRead),
+            "songs:write" => Some(Scope::SongsWrite),
+            "playlists:read" => Some(Scope::PlaylistsRead),
+            "playlists:write" => Some(Scope::PlaylistsWrite),
+            "profile:read" => Some(Scope::ProfileRead),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct User {
@@ -10,73 +131,292 @@ pub struct User {
     pub email: String,
     #[serde(skip_serializing)]
     pub password_hash: String,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub role: Role,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_path: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Song {
     pub id: Uuid,
     pub title: String,
     pub artist: String,
     pub album: String,
     pub duration: i32, // in seconds
+    pub duration_ms: Option<i32>,
+    pub codec: Option<String>,
+    pub sample_rate: Option<i32>,
+    pub channels: Option<i32>,
+    pub bitrate: Option<i32>, // bits per second
+    pub track_number: Option<i32>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+    pub transcode_status: TranscodeStatus,
+    pub renditions: Vec<StreamQuality>,
     pub file_path: String,
     pub cover_art: Option<String>,
+    pub cover: Option<CoverImages>,
+    pub waveform_path: Option<String>,
+    // EBU R128; gains are ReplayGain 2.0 (relative to -18 LUFS)
+    pub loudness_lufs: Option<f64>,
+    pub loudness_range_lu: Option<f64>,
+    pub true_peak_dbtp: Option<f64>,
+    pub track_gain_db: Option<f64>,
+    pub album_loudness_lufs: Option<f64>,
+    pub album_true_peak_dbtp: Option<f64>,
+    pub album_gain_db: Option<f64>,
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Playlist {
     pub id: Uuid,
     pub name: String,
     pub user_id: Uuid,
     pub description: Option<String>,
     pub cover_image: Option<String>,
+    pub cover: Option<CoverImages>,
+    pub is_public: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize, FromRow)]
-pub struct PlaylistSong {
-    pub playlist_id: Uuid,
-    pub song_id: Uuid,
-    pub added_at: DateTime<Utc>,
-    pub position: i32,
-}
-
 // Request/Response DTOs
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Validate)]
 pub struct LoginRequest {
+    #[validate(length(min = 1, max = 100, message = "Email is required"))]
     pub email: String,
+    #[validate(length(min = 1, max = 128, message = "Password is required"))]
     pub password: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Validate)]
 pub struct RegisterRequest {
+    #[validate(custom = "validate_username")]
     pub username: String,
+    #[validate(email(message = "Invalid email address"), length(max = 100, message = "Email must be at most 100 characters"))]
     pub email: String,
+    #[validate(custom = "validate_password")]
     pub password: String,
 }
 
+#[derive(Debug, Deserialize, Validate)]
+pub struct RefreshRequest {
+    #[validate(length(min = 1, max = 256, message = "Refresh token is required"))]
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ForgotPasswordRequest {
+    #[validate(email(message = "Invalid email address"))]
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct VerifyEmailRequest {
+    #[validate(length(min = 1, max = 2048, message = "Token is required"))]
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ResendVerificationRequest {
+    #[validate(email(message = "Invalid email address"))]
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ResetPasswordRequest {
+    #[validate(length(min = 1, max = 256, message = "Token is required"))]
+    pub token: String,
+    #[validate(custom = "validate_password")]
+    pub password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenPair {
+    pub token: String,
+    pub refresh_token: String,
+}
+
 #[derive(Debug, Serialize)]
 pub struct AuthResponse {
     pub token: String,
+    pub refresh_token: String,
     pub user: UserResponse,
 }
 
+// Result of checking a password: either fully signed in, or waiting for the
+// second factor with a short-lived MFA token
+pub enum LoginOutcome {
+    Authenticated(TokenPair, Box<User>),
+    MfaRequired(String),
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct OidcCallbackRequest {
+    #[validate(length(min = 1, max = 256, message = "State is required"))]
+    pub state: String,
+    #[validate(length(min = 1, max = 2048, message = "Code is required"))]
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OidcAuthorizationResponse {
+    pub authorization_url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MfaChallengeResponse {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MfaSetupResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct MfaCodeRequest {
+    #[validate(length(min = 1, max = 32, message = "Code is required"))]
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct MfaDisableRequest {
+    #[validate(length(min = 1, max = 128, message = "Password is required"))]
+    pub password: String,
+    #[validate(length(min = 1, max = 32, message = "Code is required"))]
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct MfaVerifyRequest {
+    #[validate(length(min = 1, max = 2048, message = "MFA token is required"))]
+    pub mfa_token: String,
+    #[validate(length(min = 1, max = 32, message = "Code is required"))]
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct UserResponse {
     pub id: Uuid,
     pub username: String,
     pub email: String,
+    pub email_verified: bool,
+    pub role: Role,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
     pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize)]
+// What anyone can see about a user; never includes email or role
+#[derive(Debug, Serialize)]
+pub struct PublicProfileResponse {
+    pub id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
+    pub follower_count: i64,
+    pub following_count: i64,
+    pub is_following: bool,
+    pub playlists: Vec<Playlist>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserSummary {
+    pub id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
 pub struct CreatePlaylistRequest {
+    #[validate(
+        custom = "validate_not_blank",
+        length(max = 100, message = "Playlist name must be at most 100 characters")
+    )]
     pub name: String,
+    #[validate(length(max = 1000, message = "Description must be at most 1000 characters"))]
     pub description: Option<String>,
+    #[serde(default)]
+    pub is_public: bool,
+}
+
+// The text fields of a multipart song upload, after gaps have been filled
+// from the file's embedded tags
+#[derive(Debug, Default, Validate)]
+pub struct UploadSongForm {
+    #[validate(
+        custom = "validate_not_blank",
+        length(max = 255, message = "Title must be at most 255 characters")
+    )]
+    pub title: String,
+    #[validate(
+        custom = "validate_not_blank",
+        length(max = 255, message = "Artist must be at most 255 characters")
+    )]
+    pub artist: String,
+    #[validate(length(max = 255, message = "Album must be at most 255 characters"))]
+    pub album: String,
+    #[validate(range(min = 0, max = 86400, message = "Duration must be between 0 and 86400 seconds"))]
+    pub duration: i32,
+    #[validate(range(min = 1, max = 999, message = "Track number must be between 1 and 999"))]
+    pub track_number: Option<i32>,
+    #[validate(range(min = 1000, max = 9999, message = "Year must be a four-digit year"))]
+    pub year: Option<i32>,
+    #[validate(length(max = 100, message = "Genre must be at most 100 characters"))]
+    pub genre: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamQuery {
+    pub token: Option<String>,
+    // Overrides the listener's saved preference
+    pub quality: Option<StreamQuality>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WaveformQuery {
+    pub points: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WaveformResponse {
+    pub song_id: Uuid,
+    pub duration_ms: Option<i32>,
+    // May be fewer than requested for very short songs
+    pub points: usize,
+    pub min: Vec<i8>,
+    pub max: Vec<i8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserPreferences {
+    pub stream_quality: StreamQuality,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreamTokenResponse {
+    pub token: String,
+    // Ready to use as an <audio> src, relative to the API origin
+    pub url: String,
+    pub expires_in: i64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -84,6 +424,138 @@ pub struct AddSongToPlaylistRequest {
     pub song_id: Uuid,
 }
 
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub current: bool,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreateAccessTokenRequest {
+    #[validate(
+        custom = "validate_not_blank",
+        length(max = 100, message = "Token name must be at most 100 characters")
+    )]
+    pub name: String,
+    #[validate(length(min = 1, message = "At least one scope is required"))]
+    pub scopes: Vec<Scope>,
+    #[validate(range(min = 1, max = 3650, message = "expires_in_days must be between 1 and 3650"))]
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessTokenResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub token_prefix: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Returned once at creation; the plaintext token can't be retrieved later
+#[derive(Debug, Serialize)]
+pub struct CreatedAccessTokenResponse {
+    pub token: String,
+    #[serde(flatten)]
+    pub details: AccessTokenResponse,
+}
+
+// Omitted fields are left alone; an empty display name or bio clears it
+#[derive(Debug, Deserialize, Validate)]
+pub struct UpdateProfileRequest {
+    #[validate(length(max = 100, message = "Display name must be at most 100 characters"))]
+    pub display_name: Option<String>,
+    #[validate(length(max = 1000, message = "Bio must be at most 1000 characters"))]
+    pub bio: Option<String>,
+    #[validate(email(message = "Invalid email address"), length(max = 100, message = "Email must be at most 100 characters"))]
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    #[validate(custom = "validate_password")]
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccountRequest {
+    pub password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IdentityExport {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_login_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    pub actor_id: Option<Uuid>,
+    pub actor_email: Option<String>,
+    pub action: String,
+    pub target_type: Option<String>,
+    pub target_id: Option<Uuid>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub metadata: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+// Filters for GET /api/admin/audit-events
+#[derive(Debug, Deserialize)]
+pub struct AuditEventQuery {
+    pub actor_id: Option<Uuid>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<Uuid>,
+    pub ip_address: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEventPage {
+    pub events: Vec<AuditEvent>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+// Personal data export (GET /api/users/me/export)
+#[derive(Debug, Serialize)]
+pub struct AccountExport {
+    pub exported_at: DateTime<Utc>,
+    pub profile: UserResponse,
+    pub playlists: Vec<PlaylistWithSongs>,
+    pub uploads: Vec<Song>,
+    pub following: Vec<UserSummary>,
+    pub followers: Vec<UserSummary>,
+    pub blocked: Vec<UserSummary>,
+    pub sessions: Vec<SessionResponse>,
+    pub access_tokens: Vec<AccessTokenResponse>,
+    pub identities: Vec<IdentityExport>,
+    pub activity: Vec<AuditEvent>,
+    pub preferences: UserPreferences,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateRoleRequest {
+    pub role: Role,
+}
+
 #[derive(Debug, Serialize)]
 pub struct PlaylistWithSongs {
     pub id: Uuid,
@@ -91,18 +563,62 @@ pub struct PlaylistWithSongs {
     pub user_id: Uuid,
     pub description: Option<String>,
     pub cover_image: Option<String>,
+    pub cover: Option<CoverImages>,
+    pub is_public: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub songs: Vec<Song>,
 }
 
+/// Where to fetch each size of a cover, as JPEG or WebP.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CoverImages {
+    pub small: CoverImage,
+    pub medium: CoverImage,
+    pub large: CoverImage,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CoverImage {
+    pub size: u32, // square, in pixels
+    pub jpeg: String,
+    pub webp: String,
+}
+
+pub fn cover_images(cover_path: &Option<String>) -> Option<CoverImages> {
+    let id = cover_id(cover_path.as_deref()?)?;
+    let image = |size: u32| CoverImage {
+        size,
+        jpeg: format!("/api/covers/{}/{}.jpg", id, size),
+        webp: format!("/api/covers/{}/{}.webp", id, size),
+    };
+
+    Some(CoverImages {
+        small: image(COVER_SIZES[0]),
+        medium: image(COVER_SIZES[1]),
+        large: image(COVER_SIZES[2]),
+    })
+}
+
+pub fn avatar_url(user_id: Uuid, avatar_path: &Option<String>) -> Option<String> {
+    avatar_path
+        .as_ref()
+        .map(|_| format!("/api/users/{}/avatar", user_id))
+}
+
 impl From<User> for UserResponse {
     fn from(user: User) -> Self {
         Self {
             id: user.id,
             username: user.username,
             email: user.email,
+            email_verified: user.email_verified_at.is_some(),
+            role: user.role,
+            display_name: user.display_name,
+            bio: user.bio,
+            avatar_url: avatar_url(user.id, &user.avatar_path),
             created_at: user.created_at,
+            updated_at: user.updated_at,
         }
     }
 }
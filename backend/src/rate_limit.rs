@@ -0,0 +1,408 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgPool, Row};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::config::Config;
+use crate::errors::AppError;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LimiterPolicy {
+    pub max_attempts: u32,
+    pub window: Duration,
+    pub base_lockout: Duration,
+    pub max_lockout: Duration,
+}
+
+impl LimiterPolicy {
+    pub fn per_email(config: &Config) -> Self {
+        Self::with_attempts(config, config.login_max_attempts)
+    }
+
+    pub fn per_ip(config: &Config) -> Self {
+        Self::with_attempts(config, config.login_max_attempts_per_ip)
+    }
+
+    fn with_attempts(config: &Config, max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            window: Duration::seconds(config.login_window_seconds),
+            base_lockout: Duration::seconds(config.login_lockout_seconds),
+            max_lockout: Duration::seconds(config.login_max_lockout_seconds),
+        }
+    }
+
+    // Each consecutive lockout doubles, up to max_lockout
+    fn lockout_for(&self, lockouts: u32) -> Duration {
+        let factor = 2i32.saturating_pow(lockouts.saturating_sub(1).min(20));
+        std::cmp::min(self.base_lockout * factor, self.max_lockout)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitStatus {
+    Allowed,
+    Locked { retry_after_secs: u64 },
+}
+
+impl LimitStatus {
+    fn locked_until(until: DateTime<Utc>, now: DateTime<Utc>) -> Self {
+        // Round up so clients never retry a fraction of a second too early
+        let millis = (until - now).num_milliseconds().max(0) as u64;
+        LimitStatus::Locked {
+            retry_after_secs: millis.div_ceil(1000).max(1),
+        }
+    }
+
+    /// The stricter of two statuses, i.e. the longer lockout.
+    pub fn max(self, other: LimitStatus) -> LimitStatus {
+        match (self, other) {
+            (LimitStatus::Allowed, s) | (s, LimitStatus::Allowed) => s,
+            (
+                LimitStatus::Locked { retry_after_secs: a },
+                LimitStatus::Locked { retry_after_secs: b },
+            ) => LimitStatus::Locked {
+                retry_after_secs: a.max(b),
+            },
+        }
+    }
+}
+
+/// Sliding-window failure counter with temporary lockout. Keys are opaque
+/// strings such as `email:someone@example.com` or `ip:203.0.113.7`.
+#[async_trait]
+pub trait LoginLimiter: Send + Sync {
+    async fn check(&self, key: &str, policy: &LimiterPolicy) -> Result<LimitStatus, AppError>;
+
+    /// Records a failed attempt and returns the status it leaves the key in.
+    async fn record_failure(&self, key: &str, policy: &LimiterPolicy) -> Result<LimitStatus, AppError>;
+
+    async fn reset(&self, key: &str) -> Result<(), AppError>;
+}
+
+const MAX_TRACKED_KEYS: usize = 100_000;
+
+#[derive(Default)]
+struct KeyState {
+    failures: VecDeque<DateTime<Utc>>,
+    locked_until: Option<DateTime<Utc>>,
+    lockouts: u32,
+}
+
+// Single-instance limiter; state is lost on restart
+#[derive(Default)]
+pub struct InMemoryLimiter {
+    state: Mutex<HashMap<String, KeyState>>,
+}
+
+impl InMemoryLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The trait methods pass the current time; tests pass their own
+    fn check_at(&self, key: &str, now: DateTime<Utc>) -> Result<LimitStatus, AppError> {
+        let state = self.state.lock().map_err(|_| AppError::internal("Limiter state poisoned"))?;
+
+        Ok(match state.get(key).and_then(|s| s.locked_until) {
+            Some(until) if until > now => LimitStatus::locked_until(until, now),
+            _ => LimitStatus::Allowed,
+        })
+    }
+
+    fn record_failure_at(&self, key: &str, policy: &LimiterPolicy, now: DateTime<Utc>) -> Result<LimitStatus, AppError> {
+        let window_start = now - policy.window;
+        let mut state = self.state.lock().map_err(|_| AppError::internal("Limiter state poisoned"))?;
+
+        // Keep memory bounded when lots of different keys fail
+        if state.len() >= MAX_TRACKED_KEYS {
+            state.retain(|_, s| {
+                s.failures.back().is_some_and(|t| *t > window_start)
+                    || s.locked_until.is_some_and(|t| t > window_start)
+            });
+        }
+
+        let entry = state.entry(key.to_string()).or_default();
+
+        while entry.failures.front().is_some_and(|t| *t <= window_start) {
+            entry.failures.pop_front();
+        }
+        // A quiet window forgets earlier lockouts, so backoff starts over
+        if entry.failures.is_empty() && entry.locked_until.is_none_or(|t| t <= window_start) {
+            entry.lockouts = 0;
+        }
+        entry.failures.push_back(now);
+
+        if entry.failures.len() as u32 >= policy.max_attempts {
+            entry.lockouts += 1;
+            let until = now + policy.lockout_for(entry.lockouts);
+            entry.locked_until = Some(until);
+            entry.failures.clear();
+            return Ok(LimitStatus::locked_until(until, now));
+        }
+
+        Ok(LimitStatus::Allowed)
+    }
+}
+
+#[async_trait]
+impl LoginLimiter for InMemoryLimiter {
+    async fn check(&self, key: &str, _policy: &LimiterPolicy) -> Result<LimitStatus, AppError> {
+        self.check_at(key, Utc::now())
+    }
+
+    async fn record_failure(&self, key: &str, policy: &LimiterPolicy) -> Result<LimitStatus, AppError> {
+        self.record_failure_at(key, policy, Utc::now())
+    }
+
+    async fn reset(&self, key: &str) -> Result<(), AppError> {
+        let mut state = self.state.lock().map_err(|_| AppError::internal("Limiter state poisoned"))?;
+        state.remove(key);
+        Ok(())
+    }
+}
+
+// Shares limiter state between instances through the login_attempts and
+// login_lockouts tables
+pub struct PostgresLimiter {
+    pool: PgPool,
+}
+
+impl PostgresLimiter {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl LoginLimiter for PostgresLimiter {
+    async fn check(&self, key: &str, _policy: &LimiterPolicy) -> Result<LimitStatus, AppError> {
+        let now = Utc::now();
+        let row = sqlx::query("SELECT locked_until FROM login_lockouts WHERE key = $1")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        let locked_until: Option<DateTime<Utc>> = row.and_then(|r| r.get("locked_until"));
+
+        Ok(match locked_until {
+            Some(until) if until > now => LimitStatus::locked_until(until, now),
+            _ => LimitStatus::Allowed,
+        })
+    }
+
+    async fn record_failure(&self, key: &str, policy: &LimiterPolicy) -> Result<LimitStatus, AppError> {
+        let now = Utc::now();
+        let window_start = now - policy.window;
+
+        let mut tx = self
+            .pool
+            .begin()
+            .await?;
+
+        // Serialise concurrent failures for the same key
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+            .bind(key)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("DELETE FROM login_attempts WHERE key = $1 AND attempted_at <= $2")
+            .bind(key)
+            .bind(window_start)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("INSERT INTO login_attempts (key, attempted_at) VALUES ($1, $2)")
+            .bind(key)
+            .bind(now)
+            .execute(&mut tx)
+            .await?;
+
+        let failures: i64 = sqlx::query("SELECT COUNT(*) AS failures FROM login_attempts WHERE key = $1")
+            .bind(key)
+            .fetch_one(&mut tx)
+            .await?
+            .get("failures");
+
+        let mut status = LimitStatus::Allowed;
+
+        if failures >= policy.max_attempts as i64 {
+            let row = sqlx::query("SELECT locked_until, lockouts FROM login_lockouts WHERE key = $1")
+                .bind(key)
+                .fetch_optional(&mut tx)
+                .await?;
+
+            // Same rule as the in-memory limiter: a quiet window resets backoff
+            let previous_lockouts = match row {
+                Some(row) => {
+                    let locked_until: DateTime<Utc> = row.get("locked_until");
+                    let lockouts: i32 = row.get("lockouts");
+                    if locked_until > window_start { lockouts as u32 } else { 0 }
+                }
+                None => 0,
+            };
+
+            let lockouts = previous_lockouts + 1;
+            let until = now + policy.lockout_for(lockouts);
+
+            sqlx::query(
+                "INSERT INTO login_lockouts (key, locked_until, lockouts, updated_at) VALUES ($1, $2, $3, NOW())
+                 ON CONFLICT (key) DO UPDATE SET locked_until = $2, lockouts = $3, updated_at = NOW()"
+            )
+            .bind(key)
+            .bind(until)
+            .bind(lockouts as i32)
+            .execute(&mut tx)
+            .await?;
+
+            sqlx::query("DELETE FROM login_attempts WHERE key = $1")
+                .bind(key)
+                .execute(&mut tx)
+                .await?;
+
+            status = LimitStatus::locked_until(until, now);
+        }
+
+        tx.commit()
+            .await?;
+
+        Ok(status)
+    }
+
+    async fn reset(&self, key: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM login_attempts WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query("DELETE FROM login_lockouts WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn policy() -> LimiterPolicy {
+        LimiterPolicy {
+            max_attempts: 3,
+            window: Duration::minutes(15),
+            base_lockout: Duration::minutes(1),
+            max_lockout: Duration::minutes(5),
+        }
+    }
+
+    fn locked(secs: u64) -> LimitStatus {
+        LimitStatus::Locked { retry_after_secs: secs }
+    }
+
+    #[test]
+    fn locks_after_max_attempts_until_the_lockout_ends() {
+        let limiter = InMemoryLimiter::new();
+        let policy = policy();
+        let start = Utc::now();
+
+        assert_eq!(limiter.record_failure_at("k", &policy, start).unwrap(), LimitStatus::Allowed);
+        assert_eq!(limiter.record_failure_at("k", &policy, start).unwrap(), LimitStatus::Allowed);
+        assert_eq!(limiter.check_at("k", start).unwrap(), LimitStatus::Allowed);
+        assert_eq!(limiter.record_failure_at("k", &policy, start).unwrap(), locked(60));
+
+        assert_eq!(limiter.check_at("k", start + Duration::seconds(59)).unwrap(), locked(1));
+        assert_eq!(limiter.check_at("k", start + Duration::seconds(60)).unwrap(), LimitStatus::Allowed);
+        // Other keys are unaffected
+        assert_eq!(limiter.check_at("other", start).unwrap(), LimitStatus::Allowed);
+    }
+
+    #[test]
+    fn failures_outside_the_window_are_forgotten() {
+        let limiter = InMemoryLimiter::new();
+        let policy = policy();
+        let start = Utc::now();
+
+        limiter.record_failure_at("k", &policy, start).unwrap();
+        limiter.record_failure_at("k", &policy, start).unwrap();
+
+        let later = start + policy.window + Duration::seconds(1);
+        assert_eq!(limiter.record_failure_at("k", &policy, later).unwrap(), LimitStatus::Allowed);
+        assert_eq!(limiter.record_failure_at("k", &policy, later).unwrap(), LimitStatus::Allowed);
+    }
+
+    #[test]
+    fn repeated_lockouts_back_off_until_a_quiet_window() {
+        let limiter = InMemoryLimiter::new();
+        let policy = policy();
+        let mut now = Utc::now();
+
+        let lock = |now| {
+            limiter.record_failure_at("k", &policy, now).unwrap();
+            limiter.record_failure_at("k", &policy, now).unwrap();
+            limiter.record_failure_at("k", &policy, now).unwrap()
+        };
+
+        assert_eq!(lock(now), locked(60));
+        now += Duration::minutes(1);
+        assert_eq!(lock(now), locked(120));
+        now += Duration::minutes(2);
+        assert_eq!(lock(now), locked(240));
+        now += Duration::minutes(4);
+        // Capped at max_lockout
+        assert_eq!(lock(now), locked(300));
+
+        // A whole window without failures starts the backoff over
+        now += Duration::minutes(5) + policy.window + Duration::seconds(1);
+        assert_eq!(lock(now), locked(60));
+    }
+
+    #[actix_web::test]
+    async fn reset_clears_failures_and_lockouts() {
+        let limiter = InMemoryLimiter::new();
+        let policy = policy();
+        let start = Utc::now();
+
+        limiter.record_failure_at("k", &policy, start).unwrap();
+        limiter.record_failure_at("k", &policy, start).unwrap();
+        limiter.reset("k").await.unwrap();
+        assert_eq!(limiter.record_failure_at("k", &policy, start).unwrap(), LimitStatus::Allowed);
+
+        limiter.record_failure_at("k", &policy, start).unwrap();
+        limiter.record_failure_at("k", &policy, start).unwrap();
+        limiter.reset("k").await.unwrap();
+        assert_eq!(limiter.check("k", &policy).await.unwrap(), LimitStatus::Allowed);
+    }
+
+    #[test]
+    fn stricter_status_wins() {
+        assert_eq!(LimitStatus::Allowed.max(LimitStatus::Allowed), LimitStatus::Allowed);
+        assert_eq!(LimitStatus::Allowed.max(locked(5)), locked(5));
+        assert_eq!(locked(5).max(locked(9)), locked(9));
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn postgres_limiter_locks_and_resets() {
+        let limiter = PostgresLimiter::new(test_support::pool().await);
+        let policy = policy();
+        let key = format!("test:{}", uuid::Uuid::new_v4());
+
+        assert_eq!(limiter.record_failure(&key, &policy).await.unwrap(), LimitStatus::Allowed);
+        assert_eq!(limiter.record_failure(&key, &policy).await.unwrap(), LimitStatus::Allowed);
+        assert_eq!(limiter.record_failure(&key, &policy).await.unwrap(), locked(60));
+        assert!(matches!(limiter.check(&key, &policy).await.unwrap(), LimitStatus::Locked { .. }));
+
+        // The next lockout doubles
+        for _ in 0..2 {
+            limiter.record_failure(&key, &policy).await.unwrap();
+        }
+        assert_eq!(limiter.record_failure(&key, &policy).await.unwrap(), locked(120));
+
+        limiter.reset(&key).await.unwrap();
+        assert_eq!(limiter.check(&key, &policy).await.unwrap(), LimitStatus::Allowed);
+    }
+}
@@ -1,38 +1,74 @@
+use chrono::Duration;
 use sqlx::{PgPool, Row};
+use std::collections::HashSet;
+use std::path::PathBuf;
 use uuid::Uuid;
 
+use crate::config::Config;
+use crate::covers::{self, CoverRenditions};
+use crate::errors::{AppError, ErrorCode};
+use crate::loudness::{replaygain_db, LoudnessHistogram};
+use crate::mailer::{Email, Mailer};
+use crate::media;
+use crate::transcode;
+use crate::mfa;
+use crate::oidc::{self, IdTokenClaims, OidcClient};
+use crate::middleware::ClientInfo;
 use crate::models::*;
-use crate::utils::{hash_password, verify_password, create_jwt_token};
+use crate::rate_limit::{LimitStatus, LimiterPolicy, LoginLimiter};
+use crate::utils::{
+    create_email_verification_token, create_jwt_token, decode_email_verification_token,
+    create_mfa_pending_token, decode_mfa_pending_token, generate_access_token, generate_token,
+    dummy_password_hash, hash_password, hash_token, password_needs_rehash, remove_upload,
+    verify_password, EMAIL_VERIFICATION_TTL_HOURS, OIDC_AUTH_REQUEST_TTL_MINUTES,
+    PASSWORD_RESET_TTL_MINUTES, REFRESH_TOKEN_TTL_DAYS,
+};
+use crate::validation::{validate_username, MAX_USERNAME_LENGTH, MIN_USERNAME_LENGTH};
 
 pub struct AuthService;
 
 impl AuthService {
     pub async fn register(
         pool: &PgPool,
+        mailer: &dyn Mailer,
+        config: &Config,
+        client: &ClientInfo,
         username: &str,
         email: &str,
         password: &str,
-    ) -> Result<(String, User), String> {
+    ) -> Result<(TokenPair, User), AppError> {
         // Check if user already exists
         let existing_user = sqlx::query("SELECT id FROM users WHERE email = $1")
             .bind(email)
             .fetch_optional(pool)
-            .await
-            .map_err(|e| format!("Database error: {}", e))?;
+            .await?;
 
         if existing_user.is_some() {
-            return Err("User with this email already exists".to_string());
+            return Err(AppError::Conflict(ErrorCode::UserExists, "User with this email already exists".to_string()));
+        }
+
+        if UserService::is_reserved_username(username) {
+            return Err(AppError::Conflict(ErrorCode::UsernameTaken, "Username is already taken".to_string()));
+        }
+
+        let taken = sqlx::query("SELECT id FROM users WHERE LOWER(username) = LOWER($1)")
+            .bind(username)
+            .fetch_optional(pool)
+            .await?;
+
+        if taken.is_some() {
+            return Err(AppError::Conflict(ErrorCode::UsernameTaken, "Username is already taken".to_string()));
         }
 
-        let password_hash = hash_password(password)
-            .map_err(|e| format!("Password hashing error: {}", e))?;
+        let password_hash = hash_password(password, config)
+            .map_err(AppError::internal)?;
 
         let user_id = Uuid::new_v4();
         let now = chrono::Utc::now();
 
         let row = sqlx::query(
             "INSERT INTO users (id, username, email, password_hash, created_at, updated_at)
-             VALUES ($1, $2, $3, $4, $5, $6) RETURNING id, username, email, password_hash, created_at, updated_at"
+             VALUES ($1, $2, $3, $4, $5, $6) RETURNING id, username, email, password_hash, email_verified_at, role, display_name, bio, avatar_path, created_at, updated_at"
         )
         .bind(user_id)
         .bind(username)
@@ -41,119 +77,2704 @@ impl AuthService {
         .bind(now)
         .bind(now)
         .fetch_one(pool)
-        .await
-        .map_err(|e| format!("Failed to create user: {}", e))?;
+        .await?;
 
-        let user = User {
-            id: row.get("id"),
-            username: row.get("username"),
-            email: row.get("email"),
-            password_hash: row.get("password_hash"),
-            created_at: row.get("created_at"),
-            updated_at: row.get("updated_at"),
+        let user = UserService::from_row(&row);
+
+        // A failed email shouldn't undo the registration; the user can ask for a resend
+        if let Err(e) = Self::send_verification_email(mailer, config, user.id, &user.email) {
+            log::warn!("Failed to send verification email to {}: {}", user.email, e);
+        }
+
+        let tokens = Self::start_session(pool, config, user.id, user.role, client).await?;
+
+        AuditService::record(pool, "user_registered", Some(user.id), Some(&user.email), client, Some(("user", user.id)), serde_json::json!({
+            "username": user.username
+        })).await;
+
+        Ok((tokens, user))
+    }
+
+    fn send_verification_email(
+        mailer: &dyn Mailer,
+        config: &Config,
+        user_id: Uuid,
+        email: &str,
+    ) -> Result<(), AppError> {
+        let token = create_email_verification_token(&config.purpose_keys, user_id, email)
+            .map_err(AppError::internal)?;
+
+        mailer.send(&Email {
+            to: email.to_string(),
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Welcome! Please confirm your email address by opening the link below\n\
+                 within {} hours:\n\n\
+                 {}/verify-email?token={}",
+                EMAIL_VERIFICATION_TTL_HOURS, config.app_url, token
+            ),
+        })
+        .map_err(AppError::internal)
+    }
+
+    pub async fn verify_email(pool: &PgPool, config: &Config, token: &str) -> Result<(), AppError> {
+        let (user_id, email) = decode_email_verification_token(&config.purpose_keys, token)
+            .map_err(|e| AppError::BadRequest(ErrorCode::InvalidToken, e))?;
+
+        // Matching on the email too means a link goes stale if the address changes
+        let result = sqlx::query(
+            "UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW()
+             WHERE id = $1 AND email = $2"
+        )
+        .bind(user_id)
+        .bind(&email)
+        .execute(pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(AppError::BadRequest(ErrorCode::InvalidToken, "Invalid or expired verification link".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Sends a fresh verification link. Like password resets, unknown or
+    /// already verified addresses are silently ignored.
+    pub async fn resend_verification(
+        pool: &PgPool,
+        mailer: &dyn Mailer,
+        config: &Config,
+        email: &str,
+    ) -> Result<(), AppError> {
+        let row = sqlx::query("SELECT id, email FROM users WHERE email = $1 AND email_verified_at IS NULL")
+            .bind(email)
+            .fetch_optional(pool)
+            .await?;
+
+        match row {
+            Some(row) => {
+                let email: String = row.get("email");
+                Self::send_verification_email(mailer, config, row.get("id"), &email)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub async fn is_email_verified(pool: &PgPool, user_id: Uuid) -> Result<bool, AppError> {
+        let row = sqlx::query("SELECT email_verified_at IS NOT NULL AS verified FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::not_found("User not found"))?;
+
+        Ok(row.get("verified"))
+    }
+
+    /// Checks a password, throttled per email and per client IP. Every
+    /// attempt is written to `audit_events`.
+    pub async fn login(
+        pool: &PgPool,
+        limiter: &dyn LoginLimiter,
+        config: &Config,
+        client: &ClientInfo,
+        email: &str,
+        password: &str,
+    ) -> Result<LoginOutcome, AppError> {
+        let mut keys = vec![(
+            format!("email:{}", email.trim().to_lowercase()),
+            LimiterPolicy::per_email(config),
+        )];
+        if let Some(ip) = &client.ip_address {
+            keys.push((format!("ip:{}", ip), LimiterPolicy::per_ip(config)));
+        }
+
+        let mut status = LimitStatus::Allowed;
+        for (key, policy) in &keys {
+            status = status.max(limiter.check(key, policy).await?);
+        }
+        if let LimitStatus::Locked { retry_after_secs } = status {
+            AuditService::record(pool, "login_blocked", None, Some(email), client, None, serde_json::json!({
+                "retry_after_secs": retry_after_secs
+            })).await;
+            return Err(AppError::RateLimited { retry_after_secs });
+        }
+
+        let row = sqlx::query("SELECT id, username, email, password_hash, email_verified_at, role, display_name, bio, avatar_path, created_at, updated_at, mfa_enabled_at IS NOT NULL AS mfa_enabled FROM users WHERE email = $1")
+            .bind(email)
+            .fetch_optional(pool)
+            .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => {
+                let dummy_hash = dummy_password_hash(config).map_err(AppError::internal)?;
+                verify_password(password, dummy_hash).map_err(AppError::internal)?;
+                return Err(Self::login_failed(pool, limiter, &keys, None, email, client, "unknown_email").await);
+            }
+        };
+
+        let user = UserService::from_row(&row);
+
+        if !verify_password(password, &user.password_hash)
+            .map_err(AppError::internal)? {
+            return Err(Self::login_failed(pool, limiter, &keys, Some(user.id), email, client, "wrong_password").await);
+        }
+
+        // Only the email key is cleared; one good login from an IP shouldn't
+        // wipe out failures it racked up against other accounts
+        limiter.reset(&keys[0].0).await?;
+
+        // Upgrade bcrypt (or outdated argon2) hashes now that we have the plaintext
+        if password_needs_rehash(&user.password_hash, config) {
+            Self::rehash_password(pool, config, user.id, password).await;
+        }
+
+        let mfa_enabled: bool = row.get("mfa_enabled");
+        if mfa_enabled {
+            let mfa_token = create_mfa_pending_token(&config.purpose_keys, user.id)
+                .map_err(AppError::internal)?;
+            return Ok(LoginOutcome::MfaRequired(mfa_token));
+        }
+
+        let tokens = Self::start_session(pool, config, user.id, user.role, client).await?;
+
+        AuditService::record(pool, "login", Some(user.id), Some(&user.email), client, None, serde_json::json!({
+            "mfa": false
+        })).await;
+
+        Ok(LoginOutcome::Authenticated(tokens, Box::new(user)))
+    }
+
+    // Best effort: a failed upgrade leaves the old, still valid hash in place
+    async fn rehash_password(pool: &PgPool, config: &Config, user_id: Uuid, password: &str) {
+        let password_hash = match hash_password(password, config) {
+            Ok(hash) => hash,
+            Err(e) => {
+                log::error!("Failed to rehash password for {}: {}", user_id, e);
+                return;
+            }
+        };
+
+        if let Err(e) = sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
+            .bind(password_hash)
+            .bind(user_id)
+            .execute(pool)
+            .await
+        {
+            log::error!("Failed to store rehashed password for {}: {}", user_id, e);
+        }
+    }
+
+    async fn login_failed(
+        pool: &PgPool,
+        limiter: &dyn LoginLimiter,
+        keys: &[(String, LimiterPolicy)],
+        user_id: Option<Uuid>,
+        email: &str,
+        client: &ClientInfo,
+        reason: &str,
+    ) -> AppError {
+        let mut status = LimitStatus::Allowed;
+        for (key, policy) in keys {
+            match limiter.record_failure(key, policy).await {
+                Ok(s) => status = status.max(s),
+                Err(e) => return e,
+            }
+        }
+
+        AuditService::record(pool, "login_failed", user_id, Some(email), client, None, serde_json::json!({
+            "reason": reason
+        })).await;
+
+        if let LimitStatus::Locked { retry_after_secs } = status {
+            AuditService::record(pool, "account_locked", user_id, Some(email), client, None, serde_json::json!({
+                "retry_after_secs": retry_after_secs
+            })).await;
+        }
+
+        AppError::Unauthorized(ErrorCode::InvalidCredentials, "Invalid credentials".to_string())
+    }
+
+    /// Second step of an MFA login: trades the pending token plus a TOTP or
+    /// recovery code for a real session. Codes are throttled per user.
+    pub async fn complete_mfa_login(
+        pool: &PgPool,
+        limiter: &dyn LoginLimiter,
+        config: &Config,
+        client: &ClientInfo,
+        mfa_token: &str,
+        code: &str,
+    ) -> Result<(TokenPair, User), AppError> {
+        let user_id = decode_mfa_pending_token(&config.purpose_keys, mfa_token)
+            .map_err(|e| AppError::Unauthorized(ErrorCode::InvalidToken, e))?;
+
+        MfaService::check_code_limit(limiter, config, user_id).await?;
+        let valid = MfaService::verify_code(pool, config, user_id, code).await?;
+        MfaService::record_code_attempt(limiter, config, user_id, valid).await?;
+        if !valid {
+            AuditService::record(pool, "mfa_failed", Some(user_id), None, client, None, serde_json::json!({})).await;
+            return Err(AppError::Unauthorized(ErrorCode::InvalidCredentials, "Invalid authentication code".to_string()));
+        }
+
+        let row = sqlx::query("SELECT id, username, email, password_hash, email_verified_at, role, display_name, bio, avatar_path, created_at, updated_at FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::not_found("User not found"))?;
+
+        let user = UserService::from_row(&row);
+
+        let tokens = Self::start_session(pool, config, user.id, user.role, client).await?;
+
+        AuditService::record(pool, "login", Some(user.id), Some(&user.email), client, None, serde_json::json!({
+            "mfa": true
+        })).await;
+
+        Ok((tokens, user))
+    }
+
+    /// Records a new device session and issues its first token pair. The
+    /// session id doubles as the refresh token family and the `sid` claim.
+    async fn start_session(
+        pool: &PgPool,
+        config: &Config,
+        user_id: Uuid,
+        role: Role,
+        client: &ClientInfo,
+    ) -> Result<TokenPair, AppError> {
+        // Logging in during the grace period cancels a pending deletion
+        AccountService::restore_if_deleted(pool, client, user_id).await?;
+
+        let session_id = SessionService::create(pool, user_id, client).await?;
+        let (refresh_token, _) = Self::insert_refresh_token(pool, user_id, session_id).await?;
+
+        let token = create_jwt_token(&config.jwt_keys, user_id, role, session_id)
+            .map_err(AppError::internal)?;
+
+        Ok(TokenPair { token, refresh_token })
+    }
+
+    async fn insert_refresh_token<'e, E>(
+        executor: E,
+        user_id: Uuid,
+        family_id: Uuid,
+    ) -> Result<(String, Uuid), AppError>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+    {
+        let refresh_token = generate_token();
+        let token_id = Uuid::new_v4();
+        let now = chrono::Utc::now();
+
+        sqlx::query(
+            "INSERT INTO refresh_tokens (id, user_id, family_id, token_hash, expires_at, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6)"
+        )
+        .bind(token_id)
+        .bind(user_id)
+        .bind(family_id)
+        .bind(hash_token(&refresh_token))
+        .bind(now + Duration::days(REFRESH_TOKEN_TTL_DAYS))
+        .bind(now)
+        .execute(executor)
+        .await?;
+
+        Ok((refresh_token, token_id))
+    }
+
+    /// Rotates a refresh token. Presenting a token that was already rotated
+    /// is treated as theft and revokes every token in its family.
+    pub async fn refresh(
+        pool: &PgPool,
+        config: &Config,
+        client: &ClientInfo,
+        refresh_token: &str,
+    ) -> Result<TokenPair, AppError> {
+        // The role is read fresh so promotions and demotions apply on the next refresh
+        let row = sqlx::query("SELECT rt.id, rt.user_id, rt.family_id, rt.expires_at, rt.revoked_at, u.role FROM refresh_tokens rt JOIN users u ON u.id = rt.user_id WHERE rt.token_hash = $1")
+            .bind(hash_token(refresh_token))
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::Unauthorized(ErrorCode::InvalidToken, "Invalid refresh token".to_string()))?;
+
+        let token_id: Uuid = row.get("id");
+        let user_id: Uuid = row.get("user_id");
+        let family_id: Uuid = row.get("family_id");
+        let expires_at: chrono::DateTime<chrono::Utc> = row.get("expires_at");
+        let revoked_at: Option<chrono::DateTime<chrono::Utc>> = row.get("revoked_at");
+        let role: Role = row.get("role");
+
+        if revoked_at.is_some() {
+            Self::revoke_family(pool, family_id).await?;
+            AuditService::record(pool, "refresh_token_reused", Some(user_id), None, client, Some(("session", family_id)), serde_json::json!({})).await;
+            return Err(AppError::Unauthorized(ErrorCode::InvalidToken, "Refresh token has been revoked".to_string()));
+        }
+
+        if expires_at < chrono::Utc::now() {
+            return Err(AppError::Unauthorized(ErrorCode::InvalidToken, "Refresh token has expired".to_string()));
+        }
+
+        let mut tx = pool
+            .begin()
+            .await?;
+
+        // Guard against two concurrent refreshes of the same token
+        let revoked = sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL")
+            .bind(token_id)
+            .execute(&mut tx)
+            .await?;
+
+        if revoked.rows_affected() == 0 {
+            tx.rollback().await.ok();
+            Self::revoke_family(pool, family_id).await?;
+            AuditService::record(pool, "refresh_token_reused", Some(user_id), None, client, Some(("session", family_id)), serde_json::json!({})).await;
+            return Err(AppError::Unauthorized(ErrorCode::InvalidToken, "Refresh token has been revoked".to_string()));
+        }
+
+        let (new_refresh_token, new_token_id) =
+            Self::insert_refresh_token(&mut tx, user_id, family_id).await?;
+
+        sqlx::query("UPDATE refresh_tokens SET replaced_by = $1 WHERE id = $2")
+            .bind(new_token_id)
+            .bind(token_id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("UPDATE sessions SET last_seen_at = NOW() WHERE id = $1")
+            .bind(family_id)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit()
+            .await?;
+
+        let token = create_jwt_token(&config.jwt_keys, user_id, role, family_id)
+            .map_err(AppError::internal)?;
+
+        Ok(TokenPair {
+            token,
+            refresh_token: new_refresh_token,
+        })
+    }
+
+    /// Ends the session the refresh token belongs to.
+    pub async fn logout(pool: &PgPool, client: &ClientInfo, refresh_token: &str) -> Result<(), AppError> {
+        let row = sqlx::query("SELECT user_id, family_id FROM refresh_tokens WHERE token_hash = $1")
+            .bind(hash_token(refresh_token))
+            .fetch_optional(pool)
+            .await?;
+
+        if let Some(row) = row {
+            let family_id: Uuid = row.get("family_id");
+            Self::revoke_family(pool, family_id).await?;
+            AuditService::record(pool, "logout", Some(row.get("user_id")), None, client, Some(("session", family_id)), serde_json::json!({})).await;
+        }
+
+        Ok(())
+    }
+
+    /// Emails a single-use reset link. Unknown addresses are ignored so the
+    /// endpoint cannot be used to discover which emails are registered.
+    pub async fn request_password_reset(
+        pool: &PgPool,
+        mailer: &dyn Mailer,
+        config: &Config,
+        email: &str,
+    ) -> Result<(), AppError> {
+        let row = sqlx::query("SELECT id, email FROM users WHERE email = $1")
+            .bind(email)
+            .fetch_optional(pool)
+            .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(()),
         };
 
-        let token = create_jwt_token(user.id)
-            .map_err(|e| format!("Token creation error: {}", e))?;
+        let user_id: Uuid = row.get("id");
+        let token = generate_token();
+        let now = chrono::Utc::now();
+
+        sqlx::query(
+            "INSERT INTO password_reset_tokens (id, user_id, token_hash, expires_at, created_at)
+             VALUES ($1, $2, $3, $4, $5)"
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(hash_token(&token))
+        .bind(now + Duration::minutes(PASSWORD_RESET_TTL_MINUTES))
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+        mailer.send(&Email {
+            to: row.get("email"),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Someone asked to reset the password for your account.\n\n\
+                 Use the link below within {} minutes to choose a new one:\n\n\
+                 {}/reset-password?token={}\n\n\
+                 If this wasn't you, you can ignore this email.",
+                PASSWORD_RESET_TTL_MINUTES, config.app_url, token
+            ),
+        })
+        .map_err(AppError::internal)
+    }
+
+    /// Consumes a reset token, sets the new password and signs the user out
+    /// everywhere by revoking their refresh tokens.
+    pub async fn reset_password(
+        pool: &PgPool,
+        config: &Config,
+        client: &ClientInfo,
+        token: &str,
+        new_password: &str,
+    ) -> Result<(), AppError> {
+        let password_hash = hash_password(new_password, config)
+            .map_err(AppError::internal)?;
+
+        let mut tx = pool
+            .begin()
+            .await?;
+
+        let row = sqlx::query(
+            "UPDATE password_reset_tokens SET used_at = NOW()
+             WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+             RETURNING user_id"
+        )
+        .bind(hash_token(token))
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or_else(|| AppError::BadRequest(ErrorCode::InvalidToken, "Invalid or expired reset token".to_string()))?;
+
+        let user_id: Uuid = row.get("user_id");
+
+        sqlx::query("UPDATE users SET password_hash = $1, updated_at = NOW() WHERE id = $2")
+            .bind(password_hash)
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        // Any other outstanding reset links stop working too
+        sqlx::query("UPDATE password_reset_tokens SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit()
+            .await?;
+
+        AuditService::record(pool, "password_reset", Some(user_id), None, client, None, serde_json::json!({})).await;
+
+        Ok(())
+    }
+
+    // A refresh token family is a device session, so both end together
+    async fn revoke_family(pool: &PgPool, family_id: Uuid) -> Result<(), AppError> {
+        SessionService::revoke_many(pool, &[family_id]).await
+    }
+}
+
+pub struct MfaService;
+
+impl MfaService {
+    /// Starts enrollment by storing a new, not yet enforced, secret. Calling
+    /// it again before confirming simply replaces the pending secret.
+    pub async fn begin_enrollment(
+        pool: &PgPool,
+        config: &Config,
+        user_id: Uuid,
+    ) -> Result<MfaSetupResponse, AppError> {
+        let row = sqlx::query("SELECT email, mfa_enabled_at IS NOT NULL AS mfa_enabled FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::not_found("User not found"))?;
+
+        let mfa_enabled: bool = row.get("mfa_enabled");
+        if mfa_enabled {
+            return Err(AppError::Conflict(ErrorCode::AlreadyExists, "Two-factor authentication is already enabled".to_string()));
+        }
+
+        let email: String = row.get("email");
+        let secret = mfa::generate_secret();
+        let encrypted = mfa::encrypt_secret(&config.mfa_encryption_key, &secret)
+            .map_err(AppError::internal)?;
+
+        sqlx::query("UPDATE users SET mfa_secret_encrypted = $1, mfa_last_used_step = NULL, updated_at = NOW() WHERE id = $2")
+            .bind(encrypted)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+        Ok(MfaSetupResponse {
+            secret: mfa::encode_secret(&secret),
+            otpauth_uri: mfa::provisioning_uri(&config.mfa_issuer, &email, &secret),
+        })
+    }
+
+    /// Enables MFA once the user proves their authenticator works, and hands
+    /// back the recovery codes. This is the only time they are shown. Codes
+    /// are throttled per user, as at login.
+    pub async fn confirm_enrollment(
+        pool: &PgPool,
+        limiter: &dyn LoginLimiter,
+        config: &Config,
+        client: &ClientInfo,
+        user_id: Uuid,
+        code: &str,
+    ) -> Result<Vec<String>, AppError> {
+        let row = sqlx::query("SELECT mfa_secret_encrypted FROM users WHERE id = $1 AND mfa_enabled_at IS NULL")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::Conflict(ErrorCode::AlreadyExists, "Two-factor authentication is already enabled".to_string()))?;
+
+        let encrypted: Option<String> = row.get("mfa_secret_encrypted");
+        let encrypted = encrypted.ok_or_else(|| AppError::bad_request("Start two-factor setup first"))?;
+        let secret = mfa::decrypt_secret(&config.mfa_encryption_key, &encrypted)
+            .map_err(AppError::internal)?;
+
+        Self::check_code_limit(limiter, config, user_id).await?;
+        let step = mfa::verify_totp(&secret, code, chrono::Utc::now().timestamp());
+        Self::record_code_attempt(limiter, config, user_id, step.is_some()).await?;
+        let step = step.ok_or_else(|| AppError::validation("code", "Invalid authentication code"))?;
+
+        let recovery_codes = mfa::generate_recovery_codes();
+
+        let mut tx = pool
+            .begin()
+            .await?;
+
+        sqlx::query("UPDATE users SET mfa_enabled_at = NOW(), mfa_last_used_step = $1, updated_at = NOW() WHERE id = $2")
+            .bind(step)
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        for recovery_code in &recovery_codes {
+            sqlx::query("INSERT INTO mfa_recovery_codes (id, user_id, code_hash) VALUES ($1, $2, $3)")
+                .bind(Uuid::new_v4())
+                .bind(user_id)
+                .bind(hash_token(&mfa::normalize_recovery_code(recovery_code)))
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit()
+            .await?;
+
+        AuditService::record(pool, "mfa_enabled", Some(user_id), None, client, None, serde_json::json!({})).await;
+
+        Ok(recovery_codes)
+    }
+
+    /// Turns MFA off. Requires the password and a current code, both
+    /// throttled, so a stolen session alone can't remove the second factor.
+    pub async fn disable(
+        pool: &PgPool,
+        limiter: &dyn LoginLimiter,
+        config: &Config,
+        client: &ClientInfo,
+        user_id: Uuid,
+        password: &str,
+        code: &str,
+    ) -> Result<(), AppError> {
+        let email = UserService::confirm_password(pool, limiter, config, client, user_id, password, "mfa_disable_failed").await?;
+
+        Self::check_code_limit(limiter, config, user_id).await?;
+        let valid = Self::verify_code(pool, config, user_id, code).await?;
+        Self::record_code_attempt(limiter, config, user_id, valid).await?;
+        if !valid {
+            return Err(AppError::validation("code", "Invalid authentication code"));
+        }
+
+        let mut tx = pool
+            .begin()
+            .await?;
+
+        sqlx::query("UPDATE users SET mfa_secret_encrypted = NULL, mfa_enabled_at = NULL, mfa_last_used_step = NULL, updated_at = NOW() WHERE id = $1")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit()
+            .await?;
+
+        AuditService::record(pool, "mfa_disabled", Some(user_id), Some(&email), client, None, serde_json::json!({})).await;
+
+        Ok(())
+    }
+
+    // Every place a code is entered draws on one budget per user, so
+    // guesses can't be spread across endpoints
+    async fn check_code_limit(limiter: &dyn LoginLimiter, config: &Config, user_id: Uuid) -> Result<(), AppError> {
+        let policy = LimiterPolicy::per_email(config);
+        match limiter.check(&format!("mfa:{}", user_id), &policy).await? {
+            LimitStatus::Locked { retry_after_secs } => Err(AppError::RateLimited { retry_after_secs }),
+            _ => Ok(()),
+        }
+    }
+
+    async fn record_code_attempt(
+        limiter: &dyn LoginLimiter,
+        config: &Config,
+        user_id: Uuid,
+        valid: bool,
+    ) -> Result<(), AppError> {
+        let key = format!("mfa:{}", user_id);
+        if valid {
+            limiter.reset(&key).await
+        } else {
+            limiter.record_failure(&key, &LimiterPolicy::per_email(config)).await.map(|_| ())
+        }
+    }
+
+    /// Accepts either a TOTP code or an unused recovery code. Both are
+    /// consumed on success so neither can be replayed.
+    pub async fn verify_code(
+        pool: &PgPool,
+        config: &Config,
+        user_id: Uuid,
+        code: &str,
+    ) -> Result<bool, AppError> {
+        let row = sqlx::query("SELECT mfa_secret_encrypted FROM users WHERE id = $1 AND mfa_enabled_at IS NOT NULL")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::bad_request("Two-factor authentication is not enabled"))?;
+
+        let encrypted: String = row.get("mfa_secret_encrypted");
+        let secret = mfa::decrypt_secret(&config.mfa_encryption_key, &encrypted)
+            .map_err(AppError::internal)?;
+
+        if let Some(step) = mfa::verify_totp(&secret, code, chrono::Utc::now().timestamp()) {
+            return Self::consume_totp_step(pool, user_id, step).await;
+        }
+
+        Self::consume_recovery_code(pool, user_id, code).await
+    }
+
+    // A step is accepted once; a code replayed within its window is refused
+    async fn consume_totp_step(pool: &PgPool, user_id: Uuid, step: i64) -> Result<bool, AppError> {
+        let result = sqlx::query(
+            "UPDATE users SET mfa_last_used_step = $1
+             WHERE id = $2 AND (mfa_last_used_step IS NULL OR mfa_last_used_step < $1)"
+        )
+        .bind(step)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn consume_recovery_code(pool: &PgPool, user_id: Uuid, code: &str) -> Result<bool, AppError> {
+        let result = sqlx::query("UPDATE mfa_recovery_codes SET used_at = NOW() WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL")
+            .bind(user_id)
+            .bind(hash_token(&mfa::normalize_recovery_code(code)))
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+pub struct OidcService;
+
+impl OidcService {
+    /// Starts a sign-in with an identity provider and returns the URL to
+    /// send the browser to. State, nonce and PKCE verifier are kept server side.
+    pub async fn begin(pool: &PgPool, oidc: &OidcClient, provider: &str) -> Result<String, AppError> {
+        let state = generate_token();
+        let nonce = generate_token();
+        let code_verifier = oidc::generate_code_verifier();
+
+        if !oidc.provider_names().iter().any(|name| name == provider) {
+            return Err(AppError::not_found(format!("Unknown identity provider {:?}", provider)));
+        }
+
+        let authorization_url = oidc
+            .authorization_url(provider, &state, &nonce, &code_verifier)
+            .await
+            .map_err(AppError::Upstream)?;
+
+        // Abandoned sign-ins are swept up here rather than by a background job
+        sqlx::query("DELETE FROM oidc_auth_requests WHERE expires_at < NOW()")
+            .execute(pool)
+            .await?;
+
+        sqlx::query(
+            "INSERT INTO oidc_auth_requests (state_hash, provider, code_verifier, nonce, expires_at, created_at)
+             VALUES ($1, $2, $3, $4, $5, NOW())"
+        )
+        .bind(hash_token(&state))
+        .bind(provider)
+        .bind(&code_verifier)
+        .bind(&nonce)
+        .bind(chrono::Utc::now() + Duration::minutes(OIDC_AUTH_REQUEST_TTL_MINUTES))
+        .execute(pool)
+        .await?;
+
+        Ok(authorization_url)
+    }
+
+    /// Finishes a sign-in: redeems the code, then logs in the linked user,
+    /// creating one on first login. Accounts with MFA still need a code.
+    pub async fn complete(
+        pool: &PgPool,
+        oidc: &OidcClient,
+        config: &Config,
+        client: &ClientInfo,
+        state: &str,
+        code: &str,
+    ) -> Result<LoginOutcome, AppError> {
+        // Deleting the request makes each state single-use
+        let request = sqlx::query(
+            "DELETE FROM oidc_auth_requests WHERE state_hash = $1 AND expires_at > NOW()
+             RETURNING provider, code_verifier, nonce"
+        )
+        .bind(hash_token(state))
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::BadRequest(ErrorCode::InvalidToken, "Invalid or expired sign-in request".to_string()))?;
+
+        let provider: String = request.get("provider");
+        let code_verifier: String = request.get("code_verifier");
+        let nonce: String = request.get("nonce");
+
+        let claims = oidc
+            .exchange_code(&provider, code, &code_verifier, &nonce)
+            .await
+            .map_err(AppError::Upstream)?;
+
+        let user_id = Self::resolve_user(pool, config, client, &provider, &claims).await?;
+
+        sqlx::query("UPDATE user_identities SET last_login_at = NOW(), email = $1 WHERE provider = $2 AND subject = $3")
+            .bind(&claims.email)
+            .bind(&provider)
+            .bind(&claims.sub)
+            .execute(pool)
+            .await?;
+
+        let row = sqlx::query("SELECT id, username, email, password_hash, email_verified_at, role, display_name, bio, avatar_path, created_at, updated_at, mfa_enabled_at IS NOT NULL AS mfa_enabled FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(pool)
+            .await?;
+
+        let user = UserService::from_row(&row);
+
+        AuditService::record(pool, "oidc_login", Some(user.id), Some(&user.email), client, None, serde_json::json!({
+            "provider": provider
+        })).await;
+
+        let mfa_enabled: bool = row.get("mfa_enabled");
+        if mfa_enabled {
+            let mfa_token = create_mfa_pending_token(&config.purpose_keys, user.id)
+                .map_err(AppError::internal)?;
+            return Ok(LoginOutcome::MfaRequired(mfa_token));
+        }
+
+        let tokens = AuthService::start_session(pool, config, user.id, user.role, client).await?;
+
+        Ok(LoginOutcome::Authenticated(tokens, Box::new(user)))
+    }
+
+    // Known identity -> its user. Otherwise link to the account with the same
+    // email if the provider has verified it, or create a new account.
+    async fn resolve_user(
+        pool: &PgPool,
+        config: &Config,
+        client: &ClientInfo,
+        provider: &str,
+        claims: &IdTokenClaims,
+    ) -> Result<Uuid, AppError> {
+        let identity = sqlx::query("SELECT user_id FROM user_identities WHERE provider = $1 AND subject = $2")
+            .bind(provider)
+            .bind(&claims.sub)
+            .fetch_optional(pool)
+            .await?;
+
+        if let Some(identity) = identity {
+            return Ok(identity.get("user_id"));
+        }
+
+        let email = claims
+            .email
+            .as_deref()
+            .ok_or_else(|| AppError::bad_request("The identity provider did not share an email address"))?;
+
+        let existing = sqlx::query("SELECT id FROM users WHERE LOWER(email) = LOWER($1)")
+            .bind(email)
+            .fetch_optional(pool)
+            .await?;
+
+        let (user_id, created) = match existing {
+            Some(_) if !claims.email_verified => {
+                return Err(AppError::Conflict(
+                    ErrorCode::UserExists,
+                    "An account with this email already exists; log in with your password instead".to_string(),
+                ));
+            }
+            Some(row) => (row.get("id"), false),
+            None => (Self::create_user(pool, config, claims, email).await?, true),
+        };
+
+        sqlx::query(
+            "INSERT INTO user_identities (id, user_id, provider, subject, email, created_at)
+             VALUES ($1, $2, $3, $4, $5, NOW())"
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(provider)
+        .bind(&claims.sub)
+        .bind(email)
+        .execute(pool)
+        .await?;
+
+        let event_type = if created { "oidc_account_created" } else { "oidc_identity_linked" };
+        AuditService::record(pool, event_type, Some(user_id), Some(email), client, None, serde_json::json!({
+            "provider": provider
+        })).await;
+
+        Ok(user_id)
+    }
+
+    // Just-in-time provisioning. The account gets a random password nobody
+    // knows; the user can set one later through a password reset.
+    async fn create_user(
+        pool: &PgPool,
+        config: &Config,
+        claims: &IdTokenClaims,
+        email: &str,
+    ) -> Result<Uuid, AppError> {
+        let username = Self::available_username(pool, claims, email).await?;
+        let password_hash = hash_password(&generate_token(), config)
+            .map_err(AppError::internal)?;
+        let display_name = claims
+            .name
+            .as_deref()
+            .map(|name| name.chars().take(MAX_DISPLAY_NAME_LENGTH).collect::<String>());
+
+        let row = sqlx::query(
+            "INSERT INTO users (id, username, email, password_hash, display_name, email_verified_at, created_at, updated_at)
+             VALUES ($1, $2, $3, $4, $5, $6, NOW(), NOW()) RETURNING id"
+        )
+        .bind(Uuid::new_v4())
+        .bind(&username)
+        .bind(email)
+        .bind(password_hash)
+        .bind(display_name)
+        .bind(claims.email_verified.then(chrono::Utc::now))
+        .fetch_one(pool)
+        .await?;
+
+        Ok(row.get("id"))
+    }
+
+    // Derives a valid username from the provider's claims, adding a random
+    // suffix when it's already taken
+    async fn available_username(pool: &PgPool, claims: &IdTokenClaims, email: &str) -> Result<String, AppError> {
+        let source = claims
+            .preferred_username
+            .as_deref()
+            .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+        let mut base: String = source
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-' || *c == '.')
+            .take(MAX_USERNAME_LENGTH - 5)
+            .collect();
+        if base.len() < MIN_USERNAME_LENGTH {
+            base = "user".to_string();
+        }
+
+        let mut candidate = base.clone();
+        for _ in 0..5 {
+            if validate_username(&candidate).is_ok() && !UserService::is_reserved_username(&candidate) {
+                let taken = sqlx::query("SELECT id FROM users WHERE LOWER(username) = LOWER($1)")
+                    .bind(&candidate)
+                    .fetch_optional(pool)
+                    .await?;
+
+                if taken.is_none() {
+                    return Ok(candidate);
+                }
+            }
+            candidate = format!("{}-{}", base, &generate_token()[..4]);
+        }
+
+        Err(AppError::internal("Could not pick a username"))
+    }
+}
+
+pub const DEFAULT_AUDIT_PAGE_SIZE: i64 = 50;
+pub const MAX_AUDIT_PAGE_SIZE: i64 = 200;
+
+pub struct AuditService;
+
+impl AuditService {
+    /// Appends to the audit log. `target` is the kind and id of the object
+    /// acted on, if any. Best effort: failing to write an event is logged
+    /// but never fails the request.
+    pub async fn record(
+        pool: &PgPool,
+        action: &str,
+        actor_id: Option<Uuid>,
+        actor_email: Option<&str>,
+        client: &ClientInfo,
+        target: Option<(&str, Uuid)>,
+        metadata: serde_json::Value,
+    ) {
+        let result = sqlx::query(
+            "INSERT INTO audit_events (id, actor_id, actor_email, action, target_type, target_id, ip_address, user_agent, metadata, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, NOW())"
+        )
+        .bind(Uuid::new_v4())
+        .bind(actor_id)
+        .bind(actor_email.map(|e| e.chars().take(100).collect::<String>()))
+        .bind(action)
+        .bind(target.map(|(kind, _)| kind))
+        .bind(target.map(|(_, id)| id))
+        .bind(client.ip_address.as_deref())
+        .bind(client.user_agent.as_deref())
+        .bind(metadata)
+        .execute(pool)
+        .await;
+
+        if let Err(e) = result {
+            log::error!("Failed to record audit event {}: {}", action, e);
+        }
+    }
+
+    /// Newest first. Every filter is optional.
+    pub async fn query(pool: &PgPool, filter: &AuditEventQuery) -> Result<AuditEventPage, AppError> {
+        let page = filter.page.unwrap_or(1).max(1);
+        let per_page = filter
+            .per_page
+            .unwrap_or(DEFAULT_AUDIT_PAGE_SIZE)
+            .clamp(1, MAX_AUDIT_PAGE_SIZE);
+
+        let conditions = "($1::uuid IS NULL OR actor_id = $1)
+               AND ($2::text IS NULL OR action = $2)
+               AND ($3::text IS NULL OR target_type = $3)
+               AND ($4::uuid IS NULL OR target_id = $4)
+               AND ($5::text IS NULL OR ip_address = $5)
+               AND ($6::timestamptz IS NULL OR created_at >= $6)
+               AND ($7::timestamptz IS NULL OR created_at < $7)";
+
+        let total = sqlx::query(&format!("SELECT COUNT(*) AS total FROM audit_events WHERE {}", conditions))
+            .bind(filter.actor_id)
+            .bind(&filter.action)
+            .bind(&filter.target_type)
+            .bind(filter.target_id)
+            .bind(&filter.ip_address)
+            .bind(filter.from)
+            .bind(filter.to)
+            .fetch_one(pool)
+            .await?
+            .get("total");
+
+        let rows = sqlx::query(&format!(
+            "SELECT id, actor_id, actor_email, action, target_type, target_id, ip_address, user_agent, metadata, created_at
+             FROM audit_events WHERE {}
+             ORDER BY created_at DESC, id
+             LIMIT $8 OFFSET $9",
+            conditions
+        ))
+        .bind(filter.actor_id)
+        .bind(&filter.action)
+        .bind(&filter.target_type)
+        .bind(filter.target_id)
+        .bind(&filter.ip_address)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(per_page)
+        .bind((page - 1) * per_page)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(AuditEventPage {
+            events: rows.iter().map(Self::from_row).collect(),
+            page,
+            per_page,
+            total,
+        })
+    }
+
+    pub async fn list_for_actor(pool: &PgPool, actor_id: Uuid) -> Result<Vec<AuditEvent>, AppError> {
+        let rows = sqlx::query(
+            "SELECT id, actor_id, actor_email, action, target_type, target_id, ip_address, user_agent, metadata, created_at
+             FROM audit_events WHERE actor_id = $1 ORDER BY created_at"
+        )
+        .bind(actor_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    fn from_row(row: &sqlx::postgres::PgRow) -> AuditEvent {
+        AuditEvent {
+            id: row.get("id"),
+            actor_id: row.get("actor_id"),
+            actor_email: row.get("actor_email"),
+            action: row.get("action"),
+            target_type: row.get("target_type"),
+            target_id: row.get("target_id"),
+            ip_address: row.get("ip_address"),
+            user_agent: row.get("user_agent"),
+            metadata: row.get("metadata"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+pub struct SessionService;
+
+impl SessionService {
+    pub async fn create(pool: &PgPool, user_id: Uuid, client: &ClientInfo) -> Result<Uuid, AppError> {
+        let session_id = Uuid::new_v4();
+
+        sqlx::query(
+            "INSERT INTO sessions (id, user_id, user_agent, ip_address, created_at, last_seen_at)
+             VALUES ($1, $2, $3, $4, NOW(), NOW())"
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .bind(client.user_agent.as_deref())
+        .bind(client.ip_address.as_deref())
+        .execute(pool)
+        .await?;
+
+        Ok(session_id)
+    }
+
+    /// Checks that a session from an access token is still live. `last_seen_at`
+    /// is only written once a minute to keep this off the hot path.
+    pub async fn touch(pool: &PgPool, session_id: Uuid, user_id: Uuid) -> Result<bool, AppError> {
+        let row = sqlx::query(
+            "SELECT revoked_at IS NULL AS active, last_seen_at < NOW() - INTERVAL '1 minute' AS stale
+             FROM sessions WHERE id = $1 AND user_id = $2"
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+
+        let active: bool = row.get("active");
+        let stale: bool = row.get("stale");
+
+        if active && stale {
+            sqlx::query("UPDATE sessions SET last_seen_at = NOW() WHERE id = $1")
+                .bind(session_id)
+                .execute(pool)
+                .await?;
+        }
+
+        Ok(active)
+    }
+
+    pub async fn list_active(
+        pool: &PgPool,
+        user_id: Uuid,
+        current_session_id: Uuid,
+    ) -> Result<Vec<SessionResponse>, AppError> {
+        let rows = sqlx::query("SELECT id, user_agent, ip_address, created_at, last_seen_at FROM sessions WHERE user_id = $1 AND revoked_at IS NULL ORDER BY last_seen_at DESC")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+        let sessions = rows.into_iter().map(|row| {
+            let id: Uuid = row.get("id");
+            SessionResponse {
+                id,
+                user_agent: row.get("user_agent"),
+                ip_address: row.get("ip_address"),
+                created_at: row.get("created_at"),
+                last_seen_at: row.get("last_seen_at"),
+                current: id == current_session_id,
+            }
+        }).collect();
+
+        Ok(sessions)
+    }
+
+    /// Revokes one of the user's sessions. Returns false if it doesn't exist
+    /// or belongs to someone else.
+    pub async fn revoke(pool: &PgPool, client: &ClientInfo, user_id: Uuid, session_id: Uuid) -> Result<bool, AppError> {
+        let row = sqlx::query("SELECT id FROM sessions WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL")
+            .bind(session_id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+        if row.is_none() {
+            return Ok(false);
+        }
+
+        Self::revoke_many(pool, &[session_id]).await?;
+        AuditService::record(pool, "session_revoked", Some(user_id), None, client, Some(("session", session_id)), serde_json::json!({})).await;
+        Ok(true)
+    }
+
+    /// "Log out everywhere else": revokes every session except the current one.
+    pub async fn revoke_others(
+        pool: &PgPool,
+        client: &ClientInfo,
+        user_id: Uuid,
+        current_session_id: Uuid,
+    ) -> Result<u64, AppError> {
+        let rows = sqlx::query("SELECT id FROM sessions WHERE user_id = $1 AND id <> $2 AND revoked_at IS NULL")
+            .bind(user_id)
+            .bind(current_session_id)
+            .fetch_all(pool)
+            .await?;
+
+        let session_ids: Vec<Uuid> = rows.into_iter().map(|row| row.get("id")).collect();
+        Self::revoke_many(pool, &session_ids).await?;
+        for &session_id in &session_ids {
+            AuditService::record(pool, "session_revoked", Some(user_id), None, client, Some(("session", session_id)), serde_json::json!({})).await;
+        }
+
+        Ok(session_ids.len() as u64)
+    }
+
+    pub async fn revoke_all(pool: &PgPool, user_id: Uuid) -> Result<u64, AppError> {
+        let rows = sqlx::query("SELECT id FROM sessions WHERE user_id = $1 AND revoked_at IS NULL")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+        let session_ids: Vec<Uuid> = rows.into_iter().map(|row| row.get("id")).collect();
+        Self::revoke_many(pool, &session_ids).await?;
+
+        Ok(session_ids.len() as u64)
+    }
+
+    // Ends the sessions and every refresh token issued for them
+    async fn revoke_many(pool: &PgPool, session_ids: &[Uuid]) -> Result<(), AppError> {
+        if session_ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = pool
+            .begin()
+            .await?;
+
+        sqlx::query("UPDATE sessions SET revoked_at = NOW() WHERE id = ANY($1) AND revoked_at IS NULL")
+            .bind(session_ids)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE family_id = ANY($1) AND revoked_at IS NULL")
+            .bind(session_ids)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit()
+            .await?;
+
+        Ok(())
+    }
+}
+
+pub struct AccessTokenService;
+
+impl AccessTokenService {
+    pub async fn create(
+        pool: &PgPool,
+        client: &ClientInfo,
+        user_id: Uuid,
+        name: &str,
+        scopes: &[Scope],
+        expires_in_days: Option<i64>,
+    ) -> Result<CreatedAccessTokenResponse, AppError> {
+        let name = name.trim();
+        let expires_at = expires_in_days.map(|days| chrono::Utc::now() + Duration::days(days));
+
+        let token = generate_access_token();
+        let token_prefix: String = token.chars().take(12).collect();
+        let scope_names: Vec<&str> = scopes.iter().map(|scope| scope.as_str()).collect();
+
+        let row = sqlx::query(
+            "INSERT INTO personal_access_tokens (id, user_id, name, token_hash, token_prefix, scopes, expires_at, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, NOW())
+             RETURNING id, name, token_prefix, scopes, expires_at, last_used_at, created_at"
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(name)
+        .bind(hash_token(&token))
+        .bind(&token_prefix)
+        .bind(&scope_names)
+        .bind(expires_at)
+        .fetch_one(pool)
+        .await?;
+
+        let details = Self::from_row(&row);
+        AuditService::record(pool, "access_token_created", Some(user_id), None, client, Some(("access_token", details.id)), serde_json::json!({
+            "name": &details.name,
+            "scopes": &scope_names,
+            "expires_at": details.expires_at
+        })).await;
+
+        Ok(CreatedAccessTokenResponse { token, details })
+    }
+
+    pub async fn list(pool: &PgPool, user_id: Uuid) -> Result<Vec<AccessTokenResponse>, AppError> {
+        let rows = sqlx::query("SELECT id, name, token_prefix, scopes, expires_at, last_used_at, created_at FROM personal_access_tokens WHERE user_id = $1 AND revoked_at IS NULL ORDER BY created_at DESC")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    pub async fn revoke(pool: &PgPool, client: &ClientInfo, user_id: Uuid, token_id: Uuid) -> Result<bool, AppError> {
+        let result = sqlx::query("UPDATE personal_access_tokens SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL")
+            .bind(token_id)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        AuditService::record(pool, "access_token_revoked", Some(user_id), None, client, Some(("access_token", token_id)), serde_json::json!({})).await;
+        Ok(true)
+    }
+
+    /// Resolves a presented token to its owner, role and scopes. Returns
+    /// `None` for unknown, revoked or expired tokens.
+    pub async fn authenticate(
+        pool: &PgPool,
+        token: &str,
+    ) -> Result<Option<(Uuid, Role, Vec<Scope>)>, AppError> {
+        let row = sqlx::query(
+            "SELECT pat.id, pat.user_id, pat.scopes, u.role,
+                    pat.last_used_at IS NULL OR pat.last_used_at < NOW() - INTERVAL '1 minute' AS stale
+             FROM personal_access_tokens pat JOIN users u ON u.id = pat.user_id
+             WHERE pat.token_hash = $1 AND pat.revoked_at IS NULL
+               AND (pat.expires_at IS NULL OR pat.expires_at > NOW())"
+        )
+        .bind(hash_token(token))
+        .fetch_optional(pool)
+        .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        // Like sessions, last use is recorded at most once a minute
+        let stale: bool = row.get("stale");
+        if stale {
+            let token_id: Uuid = row.get("id");
+            sqlx::query("UPDATE personal_access_tokens SET last_used_at = NOW() WHERE id = $1")
+                .bind(token_id)
+                .execute(pool)
+                .await?;
+        }
+
+        let scopes: Vec<String> = row.get("scopes");
+        let scopes = scopes.iter().filter_map(|scope| Scope::parse(scope)).collect();
+
+        Ok(Some((row.get("user_id"), row.get("role"), scopes)))
+    }
+
+    fn from_row(row: &sqlx::postgres::PgRow) -> AccessTokenResponse {
+        let scopes: Vec<String> = row.get("scopes");
+        AccessTokenResponse {
+            id: row.get("id"),
+            name: row.get("name"),
+            token_prefix: row.get("token_prefix"),
+            scopes: scopes.iter().filter_map(|scope| Scope::parse(scope)).collect(),
+            expires_at: row.get("expires_at"),
+            last_used_at: row.get("last_used_at"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 100;
+// These would shadow routes under /api/users
+const RESERVED_USERNAMES: &[&str] = &["me"];
+
+pub struct UserService;
+
+impl UserService {
+    /// Changes a user's role and records who changed it. Demoting the last
+    /// admin is refused, so an install always keeps one.
+    pub async fn update_role(
+        pool: &PgPool,
+        client: &ClientInfo,
+        actor_id: Uuid,
+        user_id: Uuid,
+        role: Role,
+    ) -> Result<Option<User>, AppError> {
+        let mut tx = pool.begin().await?;
+
+        // Locking every admin row keeps two concurrent demotions from both
+        // seeing another admin left
+        let admins: Vec<Uuid> = sqlx::query("SELECT id FROM users WHERE role = 'admin' AND deleted_at IS NULL FOR UPDATE")
+            .fetch_all(&mut tx)
+            .await?
+            .iter()
+            .map(|row| row.get("id"))
+            .collect();
+
+        let old_role: Role = match sqlx::query("SELECT role FROM users WHERE id = $1 FOR UPDATE")
+            .bind(user_id)
+            .fetch_optional(&mut tx)
+            .await?
+        {
+            Some(row) => row.get("role"),
+            None => return Ok(None),
+        };
+
+        if old_role == Role::Admin && role != Role::Admin && admins.iter().all(|&id| id == user_id) {
+            return Err(AppError::Conflict(ErrorCode::LastAdmin, "Cannot demote the last admin".to_string()));
+        }
+
+        let row = sqlx::query(
+            "UPDATE users SET role = $1, updated_at = NOW() WHERE id = $2
+             RETURNING id, username, email, password_hash, email_verified_at, role, display_name, bio, avatar_path, created_at, updated_at"
+        )
+        .bind(role)
+        .bind(user_id)
+        .fetch_one(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        AuditService::record(pool, "role_changed", Some(actor_id), None, client, Some(("user", user_id)), serde_json::json!({
+            "old_role": old_role,
+            "new_role": role
+        })).await;
+
+        Ok(Some(Self::from_row(&row)))
+    }
+
+    pub fn is_reserved_username(username: &str) -> bool {
+        RESERVED_USERNAMES.contains(&username.to_ascii_lowercase().as_str())
+    }
+
+    pub async fn find_by_username(pool: &PgPool, username: &str) -> Result<Option<User>, AppError> {
+        let row = sqlx::query("SELECT id, username, email, password_hash, email_verified_at, role, display_name, bio, avatar_path, created_at, updated_at FROM users WHERE LOWER(username) = LOWER($1) AND deleted_at IS NULL")
+            .bind(username)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.as_ref().map(Self::from_row))
+    }
+
+    /// Builds the public view of a user. Returns `None` when the user doesn't
+    /// exist, is being deleted or has blocked the viewer.
+    pub async fn get_public_profile(
+        pool: &PgPool,
+        username: &str,
+        viewer_id: Option<Uuid>,
+    ) -> Result<Option<PublicProfileResponse>, AppError> {
+        let user = match Self::find_by_username(pool, username).await? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        if let Some(viewer_id) = viewer_id {
+            if SocialService::has_blocked(pool, user.id, viewer_id).await? {
+                return Ok(None);
+            }
+        }
+
+        let row = sqlx::query(
+            "SELECT (SELECT COUNT(*) FROM follows f JOIN users u ON u.id = f.follower_id
+                     WHERE f.followee_id = $1 AND u.deleted_at IS NULL) AS follower_count,
+                    (SELECT COUNT(*) FROM follows f JOIN users u ON u.id = f.followee_id
+                     WHERE f.follower_id = $1 AND u.deleted_at IS NULL) AS following_count,
+                    EXISTS (SELECT 1 FROM follows WHERE follower_id = $2 AND followee_id = $1) AS is_following"
+        )
+        .bind(user.id)
+        .bind(viewer_id)
+        .fetch_one(pool)
+        .await?;
+
+        let playlists = PlaylistService::get_public_playlists(pool, user.id).await?;
+
+        Ok(Some(PublicProfileResponse {
+            id: user.id,
+            avatar_url: avatar_url(user.id, &user.avatar_path),
+            username: user.username,
+            display_name: user.display_name,
+            bio: user.bio,
+            follower_count: row.get("follower_count"),
+            following_count: row.get("following_count"),
+            is_following: row.get("is_following"),
+            playlists,
+            created_at: user.created_at,
+        }))
+    }
+
+    pub async fn get_by_id(pool: &PgPool, user_id: Uuid) -> Result<Option<User>, AppError> {
+        let row = sqlx::query("SELECT id, username, email, password_hash, email_verified_at, role, display_name, bio, avatar_path, created_at, updated_at FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.as_ref().map(Self::from_row))
+    }
+
+    /// Applies a partial profile update. A new email address starts out
+    /// unverified and gets a fresh verification link.
+    pub async fn update_profile(
+        pool: &PgPool,
+        mailer: &dyn Mailer,
+        config: &Config,
+        client: &ClientInfo,
+        user_id: Uuid,
+        update: &UpdateProfileRequest,
+    ) -> Result<Option<User>, AppError> {
+        let mut tx = pool
+            .begin()
+            .await?;
+
+        let row = sqlx::query("SELECT id, username, email, password_hash, email_verified_at, role, display_name, bio, avatar_path, created_at, updated_at FROM users WHERE id = $1 FOR UPDATE")
+            .bind(user_id)
+            .fetch_optional(&mut tx)
+            .await?;
+
+        let mut user = match row {
+            Some(row) => Self::from_row(&row),
+            None => return Ok(None),
+        };
+
+        if let Some(display_name) = &update.display_name {
+            let display_name = display_name.trim();
+            user.display_name = Some(display_name.to_string()).filter(|name| !name.is_empty());
+        }
+
+        if let Some(bio) = &update.bio {
+            let bio = bio.trim();
+            user.bio = Some(bio.to_string()).filter(|bio| !bio.is_empty());
+        }
+
+        let old_email = user.email.clone();
+        if let Some(email) = &update.email {
+            user.email = email.trim().to_string();
+        }
+
+        let email_changed = user.email != old_email;
+        if email_changed {
+            let taken = sqlx::query("SELECT id FROM users WHERE email = $1 AND id <> $2")
+                .bind(&user.email)
+                .bind(user_id)
+                .fetch_optional(&mut tx)
+                .await?;
+
+            if taken.is_some() {
+                return Err(AppError::Conflict(ErrorCode::UserExists, "User with this email already exists".to_string()));
+            }
+
+            user.email_verified_at = None;
+        }
+
+        let row = sqlx::query(
+            "UPDATE users SET display_name = $1, bio = $2, email = $3, email_verified_at = $4, updated_at = NOW()
+             WHERE id = $5
+             RETURNING id, username, email, password_hash, email_verified_at, role, display_name, bio, avatar_path, created_at, updated_at"
+        )
+        .bind(&user.display_name)
+        .bind(&user.bio)
+        .bind(&user.email)
+        .bind(user.email_verified_at)
+        .bind(user_id)
+        .fetch_one(&mut tx)
+        .await?;
+
+        tx.commit()
+            .await?;
+
+        let user = Self::from_row(&row);
+
+        if email_changed {
+            AuditService::record(pool, "email_changed", Some(user.id), Some(&user.email), client, None, serde_json::json!({
+                "previous_email": old_email
+            })).await;
+
+            if let Err(e) = AuthService::send_verification_email(mailer, config, user.id, &user.email) {
+                log::warn!("Failed to send verification email to {}: {}", user.email, e);
+            }
+        }
+
+        Ok(Some(user))
+    }
+
+    /// Changes the password after checking the current one, throttled like a
+    /// login. Every other session is signed out.
+    pub async fn change_password(
+        pool: &PgPool,
+        limiter: &dyn LoginLimiter,
+        config: &Config,
+        client: &ClientInfo,
+        user_id: Uuid,
+        session_id: Uuid,
+        change: &ChangePasswordRequest,
+    ) -> Result<(), AppError> {
+        let email = Self::confirm_password(
+            pool,
+            limiter,
+            config,
+            client,
+            user_id,
+            &change.current_password,
+            "password_change_failed",
+        )
+        .await?;
+
+        let new_hash = hash_password(&change.new_password, config)
+            .map_err(AppError::internal)?;
+
+        sqlx::query("UPDATE users SET password_hash = $1, updated_at = NOW() WHERE id = $2")
+            .bind(new_hash)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+        // Outstanding reset links were issued for the old password
+        sqlx::query("UPDATE password_reset_tokens SET used_at = NOW() WHERE user_id = $1 AND used_at IS NULL")
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+
+        SessionService::revoke_others(pool, client, user_id, session_id).await?;
+
+        AuditService::record(pool, "password_changed", Some(user_id), Some(&email), client, None, serde_json::json!({})).await;
+
+        Ok(())
+    }
+
+    /// Re-checks the password of a signed-in user before a sensitive change,
+    /// throttled like a login. Returns the account's email.
+    async fn confirm_password(
+        pool: &PgPool,
+        limiter: &dyn LoginLimiter,
+        config: &Config,
+        client: &ClientInfo,
+        user_id: Uuid,
+        password: &str,
+        failure_event: &str,
+    ) -> Result<String, AppError> {
+        let key = format!("password:{}", user_id);
+        let policy = LimiterPolicy::per_email(config);
+
+        if let LimitStatus::Locked { retry_after_secs } = limiter.check(&key, &policy).await? {
+            return Err(AppError::RateLimited { retry_after_secs });
+        }
+
+        let row = sqlx::query("SELECT email, password_hash FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::not_found("User not found"))?;
+
+        let email: String = row.get("email");
+        let password_hash: String = row.get("password_hash");
+
+        if !verify_password(password, &password_hash)
+            .map_err(AppError::internal)? {
+            limiter.record_failure(&key, &policy).await?;
+            AuditService::record(pool, failure_event, Some(user_id), Some(&email), client, None, serde_json::json!({})).await;
+            return Err(AppError::BadRequest(ErrorCode::InvalidCredentials, "Current password is incorrect".to_string()));
+        }
+
+        limiter.reset(&key).await?;
+
+        Ok(email)
+    }
+
+    /// Points the user at a new avatar (or none) and returns the path of the
+    /// one it replaces so the caller can delete the file.
+    pub async fn set_avatar(
+        pool: &PgPool,
+        user_id: Uuid,
+        avatar_path: Option<&str>,
+    ) -> Result<Option<String>, AppError> {
+        let row = sqlx::query(
+            "UPDATE users u SET avatar_path = $1, updated_at = NOW()
+             FROM (SELECT avatar_path FROM users WHERE id = $2 FOR UPDATE) old
+             WHERE u.id = $2
+             RETURNING old.avatar_path"
+        )
+        .bind(avatar_path)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.and_then(|row| row.get("avatar_path")))
+    }
+
+    /// Re-encodes avatars stored before uploads were re-encoded, which still
+    /// carry the uploader's EXIF. Those were named `avatars/{user id}-...`.
+    /// Returns how many were converted and how many couldn't be read.
+    pub async fn backfill_avatars(pool: &PgPool, config: &Config) -> Result<(usize, usize), AppError> {
+        let rows = sqlx::query("SELECT id, avatar_path FROM users WHERE avatar_path LIKE 'avatars/' || id::text || '-%'")
+            .fetch_all(pool)
+            .await?;
+
+        let mut updated = 0;
+        let mut skipped = 0;
+
+        for row in rows {
+            let user_id: Uuid = row.get("id");
+            let legacy_path: String = row.get("avatar_path");
+
+            let upload_dir = config.upload_dir.clone();
+            let source = legacy_path.clone();
+            let rendered = actix_web::web::block(move || {
+                let data = std::fs::read(format!("{}/{}", upload_dir, source)).map_err(|e| e.to_string())?;
+                let jpeg = covers::render_avatar(&data)?;
+                covers::save_avatar(&upload_dir, &jpeg)
+            });
+
+            let avatar_path = match rendered.await {
+                Ok(Ok(avatar_path)) => avatar_path,
+                Ok(Err(e)) => {
+                    log::warn!("Skipping avatar for user {}: {}", user_id, e);
+                    skipped += 1;
+                    continue;
+                }
+                Err(e) => return Err(AppError::internal(e)),
+            };
+
+            match Self::set_avatar(pool, user_id, Some(&avatar_path)).await {
+                Ok(old_path) => {
+                    if let Some(old_path) = old_path {
+                        remove_upload(&config.upload_dir, &old_path);
+                    }
+                }
+                Err(e) => {
+                    remove_upload(&config.upload_dir, &avatar_path);
+                    return Err(e);
+                }
+            }
+            updated += 1;
+        }
+
+        Ok((updated, skipped))
+    }
+
+    pub async fn get_avatar_path(pool: &PgPool, user_id: Uuid) -> Result<Option<String>, AppError> {
+        let row = sqlx::query("SELECT avatar_path FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.and_then(|row| row.get("avatar_path")))
+    }
+
+    // Users who never saved preferences get the defaults
+    pub async fn get_preferences(pool: &PgPool, user_id: Uuid) -> Result<UserPreferences, AppError> {
+        let row = sqlx::query("SELECT stream_quality FROM user_preferences WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(UserPreferences {
+            stream_quality: row.map(|row| row.get("stream_quality")).unwrap_or_default(),
+        })
+    }
+
+    pub async fn set_preferences(pool: &PgPool, user_id: Uuid, preferences: &UserPreferences) -> Result<UserPreferences, AppError> {
+        sqlx::query(
+            "INSERT INTO user_preferences (user_id, stream_quality, updated_at) VALUES ($1, $2, NOW())
+             ON CONFLICT (user_id) DO UPDATE SET stream_quality = EXCLUDED.stream_quality, updated_at = NOW()"
+        )
+        .bind(user_id)
+        .bind(preferences.stream_quality)
+        .execute(pool)
+        .await?;
+
+        Self::get_preferences(pool, user_id).await
+    }
+
+    pub fn from_row(row: &sqlx::postgres::PgRow) -> User {
+        User {
+            id: row.get("id"),
+            username: row.get("username"),
+            email: row.get("email"),
+            password_hash: row.get("password_hash"),
+            email_verified_at: row.get("email_verified_at"),
+            role: row.get("role"),
+            display_name: row.get("display_name"),
+            bio: row.get("bio"),
+            avatar_path: row.get("avatar_path"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
+    // Used at startup to bootstrap the first admin. Only a verified address
+    // is promoted, and only while the install has no admin at all, so a
+    // squatter on the address or a deliberate demotion isn't undone.
+    pub async fn promote_admin_by_email(pool: &PgPool, email: &str) -> Result<bool, AppError> {
+        let result = sqlx::query(
+            "UPDATE users SET role = 'admin', updated_at = NOW()
+             WHERE email = $1 AND email_verified_at IS NOT NULL AND deleted_at IS NULL
+               AND NOT EXISTS (SELECT 1 FROM users WHERE role = 'admin')"
+        )
+        .bind(email)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+pub struct AccountService;
+
+impl AccountService {
+    /// Soft-deletes the account: it disappears from public view and every
+    /// session and token is revoked, but nothing is removed until the grace
+    /// period ends. Returns when the account will be purged.
+    pub async fn request_deletion(
+        pool: &PgPool,
+        limiter: &dyn LoginLimiter,
+        config: &Config,
+        client: &ClientInfo,
+        user_id: Uuid,
+        password: &str,
+    ) -> Result<chrono::DateTime<chrono::Utc>, AppError> {
+        let email = UserService::confirm_password(
+            pool,
+            limiter,
+            config,
+            client,
+            user_id,
+            password,
+            "account_deletion_failed",
+        )
+        .await?;
+
+        let purge_after = chrono::Utc::now() + Duration::days(config.account_deletion_grace_days);
+
+        let mut tx = pool
+            .begin()
+            .await?;
+
+        sqlx::query("UPDATE users SET deleted_at = NOW(), purge_after = $1, updated_at = NOW() WHERE id = $2")
+            .bind(purge_after)
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("UPDATE personal_access_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit()
+            .await?;
+
+        SessionService::revoke_all(pool, user_id).await?;
+
+        AuditService::record(pool, "account_deletion_requested", Some(user_id), Some(&email), client, None, serde_json::json!({
+            "purge_after": purge_after
+        })).await;
+
+        Ok(purge_after)
+    }
+
+    pub async fn restore_if_deleted(pool: &PgPool, client: &ClientInfo, user_id: Uuid) -> Result<(), AppError> {
+        let row = sqlx::query(
+            "UPDATE users SET deleted_at = NULL, purge_after = NULL, updated_at = NOW()
+             WHERE id = $1 AND deleted_at IS NOT NULL
+             RETURNING email"
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+        if let Some(row) = row {
+            let email: String = row.get("email");
+            AuditService::record(pool, "account_restored", Some(user_id), Some(&email), client, None, serde_json::json!({})).await;
+        }
+
+        Ok(())
+    }
+
+    /// Hard-deletes every account whose grace period is over. Safe to run
+    /// from several instances at once.
+    pub async fn purge_due(pool: &PgPool, config: &Config) -> Result<usize, AppError> {
+        let rows = sqlx::query("SELECT id FROM users WHERE purge_after <= NOW()")
+            .fetch_all(pool)
+            .await?;
+
+        let mut purged = 0;
+        for row in rows {
+            let user_id: Uuid = row.get("id");
+            match Self::purge(pool, config, user_id).await {
+                Ok(true) => purged += 1,
+                Ok(false) => {}
+                Err(e) => log::error!("Failed to purge account {}: {}", user_id, e),
+            }
+        }
+
+        Ok(purged)
+    }
+
+    // Playlists, sessions, tokens, follows and the rest go with the users row
+    // through ON DELETE CASCADE. Songs the user uploaded are deleted too,
+    // which also takes them out of other people's playlists.
+    async fn purge(pool: &PgPool, config: &Config, user_id: Uuid) -> Result<bool, AppError> {
+        let mut tx = pool
+            .begin()
+            .await?;
+
+        // Another instance may be purging the same account
+        let due = sqlx::query("SELECT id FROM users WHERE id = $1 AND purge_after <= NOW() FOR UPDATE SKIP LOCKED")
+            .bind(user_id)
+            .fetch_optional(&mut tx)
+            .await?;
+
+        if due.is_none() {
+            return Ok(false);
+        }
+
+        let song_rows = sqlx::query("DELETE FROM songs WHERE uploaded_by = $1 RETURNING id, file_path, cover_art, waveform_path")
+            .bind(user_id)
+            .fetch_all(&mut tx)
+            .await?;
+
+        let playlist_rows = sqlx::query("DELETE FROM playlists WHERE user_id = $1 RETURNING cover_image")
+            .bind(user_id)
+            .fetch_all(&mut tx)
+            .await?;
+
+        // The audit trail would otherwise outlive the account with its email
+        // and IPs. This is the only sanctioned delete from audit_events.
+        sqlx::query("SET LOCAL app.allow_audit_purge = 'on'")
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query("DELETE FROM audit_events WHERE actor_id = $1")
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+
+        let user_row = sqlx::query("DELETE FROM users WHERE id = $1 RETURNING avatar_path")
+            .bind(user_id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        tx.commit()
+            .await?;
+
+        let avatar_path: Option<String> = user_row.get("avatar_path");
+        let file_paths = SongService::stored_files(pool, &song_rows)
+            .await?
+            .into_iter()
+            .chain(playlist_rows.iter().filter_map(|row| row.get("cover_image")))
+            .chain(avatar_path);
+
+        for file_path in file_paths {
+            remove_upload(&config.upload_dir, &file_path);
+        }
+
+        log::info!("Purged account {}", user_id);
+
+        Ok(true)
+    }
+
+    /// Everything we hold about the user, as one JSON document.
+    pub async fn export(pool: &PgPool, user_id: Uuid, session_id: Uuid) -> Result<Option<AccountExport>, AppError> {
+        let user = match UserService::get_by_id(pool, user_id).await? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        let mut playlists = Vec::new();
+        for playlist in PlaylistService::get_user_playlists(pool, user_id).await? {
+            if let Some(playlist) = PlaylistService::get_playlist_with_songs(pool, playlist.id).await? {
+                playlists.push(playlist);
+            }
+        }
+
+        let identity_rows = sqlx::query("SELECT provider, subject, email, created_at, last_login_at FROM user_identities WHERE user_id = $1 ORDER BY created_at")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+        let identities = identity_rows.into_iter().map(|row| IdentityExport {
+            provider: row.get("provider"),
+            subject: row.get("subject"),
+            email: row.get("email"),
+            created_at: row.get("created_at"),
+            last_login_at: row.get("last_login_at"),
+        }).collect();
+
+        Ok(Some(AccountExport {
+            exported_at: chrono::Utc::now(),
+            profile: user.into(),
+            playlists,
+            uploads: SongService::get_songs_uploaded_by(pool, user_id).await?,
+            following: SocialService::list_following(pool, user_id).await?,
+            followers: SocialService::list_followers(pool, user_id).await?,
+            blocked: SocialService::list_blocked(pool, user_id).await?,
+            sessions: SessionService::list_active(pool, user_id, session_id).await?,
+            access_tokens: AccessTokenService::list(pool, user_id).await?,
+            identities,
+            activity: AuditService::list_for_actor(pool, user_id).await?,
+            preferences: UserService::get_preferences(pool, user_id).await?,
+        }))
+    }
+}
+
+pub struct SocialService;
+
+impl SocialService {
+    pub async fn follow(pool: &PgPool, follower_id: Uuid, followee_id: Uuid) -> Result<(), AppError> {
+        if follower_id == followee_id {
+            return Err(AppError::bad_request("You can't follow yourself"));
+        }
+
+        if Self::has_blocked(pool, followee_id, follower_id).await?
+            || Self::has_blocked(pool, follower_id, followee_id).await?
+        {
+            return Err(AppError::forbidden("You can't follow this user"));
+        }
+
+        sqlx::query(
+            "INSERT INTO follows (follower_id, followee_id, created_at) VALUES ($1, $2, NOW())
+             ON CONFLICT DO NOTHING"
+        )
+        .bind(follower_id)
+        .bind(followee_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn unfollow(pool: &PgPool, follower_id: Uuid, followee_id: Uuid) -> Result<bool, AppError> {
+        let result = sqlx::query("DELETE FROM follows WHERE follower_id = $1 AND followee_id = $2")
+            .bind(follower_id)
+            .bind(followee_id)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Blocks a user and drops any follow between the two of them.
+    pub async fn block(pool: &PgPool, blocker_id: Uuid, blocked_id: Uuid) -> Result<(), AppError> {
+        if blocker_id == blocked_id {
+            return Err(AppError::bad_request("You can't block yourself"));
+        }
+
+        let mut tx = pool
+            .begin()
+            .await?;
+
+        sqlx::query(
+            "INSERT INTO blocks (blocker_id, blocked_id, created_at) VALUES ($1, $2, NOW())
+             ON CONFLICT DO NOTHING"
+        )
+        .bind(blocker_id)
+        .bind(blocked_id)
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query(
+            "DELETE FROM follows
+             WHERE (follower_id = $1 AND followee_id = $2) OR (follower_id = $2 AND followee_id = $1)"
+        )
+        .bind(blocker_id)
+        .bind(blocked_id)
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit()
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn unblock(pool: &PgPool, blocker_id: Uuid, blocked_id: Uuid) -> Result<bool, AppError> {
+        let result = sqlx::query("DELETE FROM blocks WHERE blocker_id = $1 AND blocked_id = $2")
+            .bind(blocker_id)
+            .bind(blocked_id)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn has_blocked(pool: &PgPool, blocker_id: Uuid, blocked_id: Uuid) -> Result<bool, AppError> {
+        let row = sqlx::query("SELECT EXISTS (SELECT 1 FROM blocks WHERE blocker_id = $1 AND blocked_id = $2) AS blocked")
+            .bind(blocker_id)
+            .bind(blocked_id)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(row.get("blocked"))
+    }
+
+    pub async fn list_followers(pool: &PgPool, user_id: Uuid) -> Result<Vec<UserSummary>, AppError> {
+        let rows = sqlx::query(
+            "SELECT u.id, u.username, u.display_name, u.avatar_path
+             FROM follows f JOIN users u ON u.id = f.follower_id
+             WHERE f.followee_id = $1 AND u.deleted_at IS NULL ORDER BY f.created_at DESC"
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.iter().map(Self::summary_from_row).collect())
+    }
+
+    pub async fn list_following(pool: &PgPool, user_id: Uuid) -> Result<Vec<UserSummary>, AppError> {
+        let rows = sqlx::query(
+            "SELECT u.id, u.username, u.display_name, u.avatar_path
+             FROM follows f JOIN users u ON u.id = f.followee_id
+             WHERE f.follower_id = $1 AND u.deleted_at IS NULL ORDER BY f.created_at DESC"
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.iter().map(Self::summary_from_row).collect())
+    }
+
+    pub async fn list_blocked(pool: &PgPool, user_id: Uuid) -> Result<Vec<UserSummary>, AppError> {
+        let rows = sqlx::query(
+            "SELECT u.id, u.username, u.display_name, u.avatar_path
+             FROM blocks b JOIN users u ON u.id = b.blocked_id
+             WHERE b.blocker_id = $1 ORDER BY b.created_at DESC"
+        )
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.iter().map(Self::summary_from_row).collect())
+    }
+
+    fn summary_from_row(row: &sqlx::postgres::PgRow) -> UserSummary {
+        let id = row.get("id");
+        let avatar_path: Option<String> = row.get("avatar_path");
+        UserSummary {
+            id,
+            username: row.get("username"),
+            display_name: row.get("display_name"),
+            avatar_url: avatar_url(id, &avatar_path),
+        }
+    }
+}
+
+const SONG_COLUMNS: &str = "id, title, artist, album, duration, duration_ms, codec, sample_rate, channels, bitrate, \
+    track_number, year, genre, transcode_status, renditions, file_path, cover_art, waveform_path, \
+    loudness_lufs, loudness_range_lu, true_peak_dbtp, album_loudness_lufs, album_true_peak_dbtp, created_at";
+
+pub struct SongService;
+
+impl SongService {
+    pub async fn get_all_songs(pool: &PgPool) -> Result<Vec<Song>, AppError> {
+        let rows = sqlx::query(&format!("SELECT {} FROM songs ORDER BY created_at DESC", SONG_COLUMNS))
+            .fetch_all(pool)
+            .await?;
+
+        let songs = rows.iter().map(Self::from_row).collect();
+
+        Ok(songs)
+    }
+
+    pub async fn get_songs_uploaded_by(pool: &PgPool, user_id: Uuid) -> Result<Vec<Song>, AppError> {
+        let rows = sqlx::query(&format!("SELECT {} FROM songs WHERE uploaded_by = $1 ORDER BY created_at DESC", SONG_COLUMNS))
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+        let songs = rows.iter().map(Self::from_row).collect();
+
+        Ok(songs)
+    }
+
+    pub async fn get_song_by_id(pool: &PgPool, song_id: Uuid) -> Result<Option<Song>, AppError> {
+        let row = sqlx::query(&format!("SELECT {} FROM songs WHERE id = $1", SONG_COLUMNS))
+            .bind(song_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.as_ref().map(Self::from_row))
+    }
+
+    // Returns the deleted song's stored files so the caller can remove them
+    pub async fn delete_song(pool: &PgPool, song_id: Uuid) -> Result<Option<Vec<String>>, AppError> {
+        let row = sqlx::query(
+            "DELETE FROM songs WHERE id = $1 RETURNING id, file_path, cover_art, waveform_path, uploaded_by, album"
+        )
+        .bind(song_id)
+        .fetch_optional(pool)
+        .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        // The rest of the album is now quieter or louder without it
+        Self::update_album_loudness(pool, row.get("uploaded_by"), row.get("album")).await?;
+
+        Ok(Some(Self::stored_files(pool, &[row]).await?))
+    }
+
+    // `Some(None)` is a song with no known uploader, which only admins manage
+    pub async fn get_uploader(pool: &PgPool, song_id: Uuid) -> Result<Option<Option<Uuid>>, AppError> {
+        let row = sqlx::query("SELECT uploaded_by FROM songs WHERE id = $1")
+            .bind(song_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.map(|row| row.get("uploaded_by")))
+    }
+
+    /// Points the song at a new cover (or none) and returns the one it
+    /// replaces, if no other song still uses it, so the caller can delete it.
+    pub async fn set_cover(pool: &PgPool, song_id: Uuid, cover_path: Option<&str>) -> Result<Option<String>, AppError> {
+        let row = sqlx::query(
+            "UPDATE songs s SET cover_art = $1
+             FROM (SELECT cover_art FROM songs WHERE id = $2 FOR UPDATE) old
+             WHERE s.id = $2
+             RETURNING old.cover_art"
+        )
+        .bind(cover_path)
+        .bind(song_id)
+        .fetch_optional(pool)
+        .await?;
+
+        let old_path = row.and_then(|row| row.get("cover_art"));
+        Ok(Self::unused_covers(pool, old_path).await?.pop())
+    }
+
+    /// Gives every song in one of the uploader's albums the same cover and
+    /// returns the album's songs. The songs share one stored cover; it is
+    /// only deleted once none of them use it.
+    pub async fn set_album_cover(
+        pool: &PgPool,
+        config: &Config,
+        uploaded_by: Uuid,
+        album: &str,
+        renditions: &CoverRenditions,
+    ) -> Result<Vec<Song>, AppError> {
+        let cover_path = renditions.save(&config.upload_dir).map_err(AppError::internal)?;
+
+        let old_paths = match Self::set_album_cover_path(pool, uploaded_by, album, &cover_path).await {
+            Ok(Some(old_paths)) => old_paths,
+            Ok(None) => {
+                remove_upload(&config.upload_dir, &cover_path);
+                return Ok(Vec::new());
+            }
+            Err(e) => {
+                remove_upload(&config.upload_dir, &cover_path);
+                return Err(e);
+            }
+        };
+        for old_path in old_paths {
+            remove_upload(&config.upload_dir, &old_path);
+        }
+
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM songs WHERE uploaded_by = $1 AND album = $2 ORDER BY track_number NULLS LAST, title",
+            SONG_COLUMNS
+        ))
+        .bind(uploaded_by)
+        .bind(album)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    // Returns the covers the album's songs no longer use, or `None` if the
+    // uploader has no such album
+    async fn set_album_cover_path(
+        pool: &PgPool,
+        uploaded_by: Uuid,
+        album: &str,
+        cover_path: &str,
+    ) -> Result<Option<Vec<String>>, AppError> {
+        let rows = sqlx::query(
+            "UPDATE songs s SET cover_art = $3
+             FROM (SELECT id, cover_art FROM songs WHERE uploaded_by = $1 AND album = $2 FOR UPDATE) old
+             WHERE s.id = old.id
+             RETURNING old.cover_art"
+        )
+        .bind(uploaded_by)
+        .bind(album)
+        .bind(cover_path)
+        .fetch_all(pool)
+        .await?;
 
-        Ok((token, user))
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        let old_paths = rows.iter().filter_map(|row| row.get::<Option<String>, _>("cover_art"));
+        Ok(Some(Self::unused_covers(pool, old_paths).await?))
     }
 
-    pub async fn login(
+    /// Measures an album's loudness across all of its analysed songs and
+    /// records it on each of them. Songs without an album name have no
+    /// album loudness. Returns the album's loudness and true peak.
+    pub async fn update_album_loudness(
         pool: &PgPool,
-        email: &str,
-        password: &str,
-    ) -> Result<(String, User), String> {
-        let row = sqlx::query("SELECT id, username, email, password_hash, created_at, updated_at FROM users WHERE email = $1")
-            .bind(email)
-            .fetch_optional(pool)
-            .await
-            .map_err(|e| format!("Database error: {}", e))?
-            .ok_or_else(|| "Invalid credentials".to_string())?;
+        uploaded_by: Option<Uuid>,
+        album: &str,
+    ) -> Result<(Option<f64>, Option<f64>), AppError> {
+        if album.is_empty() {
+            return Ok((None, None));
+        }
 
-        let user = User {
-            id: row.get("id"),
-            username: row.get("username"),
-            email: row.get("email"),
-            password_hash: row.get("password_hash"),
-            created_at: row.get("created_at"),
-            updated_at: row.get("updated_at"),
-        };
+        let rows = sqlx::query(
+            "SELECT loudness_histogram, true_peak_dbtp FROM songs
+             WHERE uploaded_by IS NOT DISTINCT FROM $1 AND album = $2 AND loudness_histogram IS NOT NULL"
+        )
+        .bind(uploaded_by)
+        .bind(album)
+        .fetch_all(pool)
+        .await?;
 
-        if !verify_password(password, &user.password_hash)
-            .map_err(|e| format!("Password verification error: {}", e))? {
-            return Err("Invalid credentials".to_string());
+        let mut histogram = LoudnessHistogram::default();
+        let mut true_peak: Option<f64> = None;
+        for row in &rows {
+            match serde_json::from_value::<LoudnessHistogram>(row.get("loudness_histogram")) {
+                Ok(song_histogram) => histogram.merge(&song_histogram),
+                Err(e) => log::warn!("Ignoring unreadable loudness histogram in album {:?}: {}", album, e),
+            }
+            if let Some(peak) = row.get::<Option<f64>, _>("true_peak_dbtp") {
+                true_peak = Some(true_peak.map_or(peak, |max| max.max(peak)));
+            }
         }
+        let loudness = histogram.integrated_lufs();
 
-        let token = create_jwt_token(user.id)
-            .map_err(|e| format!("Token creation error: {}", e))?;
+        sqlx::query(
+            "UPDATE songs SET album_loudness_lufs = $3, album_true_peak_dbtp = $4
+             WHERE uploaded_by IS NOT DISTINCT FROM $1 AND album = $2"
+        )
+        .bind(uploaded_by)
+        .bind(album)
+        .bind(loudness)
+        .bind(true_peak)
+        .execute(pool)
+        .await?;
 
-        Ok((token, user))
+        Ok((loudness, true_peak))
     }
-}
 
-pub struct SongService;
+    // Paths under the upload directory that belonged to deleted song rows.
+    // A cover is left out while other songs of its album still show it.
+    async fn stored_files(pool: &PgPool, rows: &[sqlx::postgres::PgRow]) -> Result<Vec<String>, AppError> {
+        let mut paths = Vec::new();
+        for row in rows {
+            paths.push(row.get::<String, _>("file_path"));
+            paths.extend(row.get::<Option<String>, _>("waveform_path"));
+            paths.push(transcode::song_dir(row.get("id")));
+        }
 
-impl SongService {
-    pub async fn get_all_songs(pool: &PgPool) -> Result<Vec<Song>, String> {
-        let rows = sqlx::query("SELECT id, title, artist, album, duration, file_path, cover_art, created_at FROM songs ORDER BY created_at DESC")
+        let covers = rows.iter().filter_map(|row| row.get::<Option<String>, _>("cover_art"));
+        paths.extend(Self::unused_covers(pool, covers).await?);
+        Ok(paths)
+    }
+
+    // The covers, once each, that no song points at any more. An album
+    // cover is shared by its songs, but no song ever takes on an existing
+    // cover, so once unused a cover stays unused.
+    async fn unused_covers(pool: &PgPool, covers: impl IntoIterator<Item = String>) -> Result<Vec<String>, AppError> {
+        let mut covers: Vec<String> = covers.into_iter().collect();
+        covers.sort();
+        covers.dedup();
+        if covers.is_empty() {
+            return Ok(covers);
+        }
+
+        let rows = sqlx::query(
+            "SELECT cover FROM UNNEST($1::text[]) cover
+             WHERE NOT EXISTS (SELECT 1 FROM songs WHERE cover_art = cover)"
+        )
+        .bind(&covers)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.iter().map(|row| row.get("cover")).collect())
+    }
+
+    pub async fn search_songs(pool: &PgPool, query: &str) -> Result<Vec<Song>, AppError> {
+        let search_pattern = format!("%{}%", query);
+        let rows = sqlx::query(&format!("SELECT {} FROM songs WHERE title ILIKE $1 OR artist ILIKE $1 OR album ILIKE $1", SONG_COLUMNS))
+            .bind(&search_pattern)
             .fetch_all(pool)
-            .await
-            .map_err(|e| format!("Database error: {}", e))?;
+            .await?;
 
-        let songs = rows.into_iter().map(|row| Song {
-            id: row.get("id"),
-            title: row.get("title"),
-            artist: row.get("artist"),
-            album: row.get("album"),
-            duration: row.get("duration"),
-            file_path: row.get("file_path"),
-            cover_art: row.get("cover_art"),
-            created_at: row.get("created_at"),
-        }).collect();
+        let songs = rows.iter().map(Self::from_row).collect();
 
         Ok(songs)
     }
 
-    pub async fn get_song_by_id(pool: &PgPool, song_id: Uuid) -> Result<Option<Song>, String> {
-        let row = sqlx::query("SELECT id, title, artist, album, duration, file_path, cover_art, created_at FROM songs WHERE id = $1")
+    /// Probes every song stored before audio probing, waveforms or loudness
+    /// analysis existed and records what it finds. Files that are missing or
+    /// don't decode are logged and left as they are. Returns how many songs
+    /// were updated and skipped.
+    pub async fn backfill_audio_info(pool: &PgPool, config: &Config) -> Result<(usize, usize), AppError> {
+        let rows = sqlx::query(
+            "SELECT id, file_path, uploaded_by, album FROM songs
+             WHERE codec IS NULL OR waveform_path IS NULL OR loudness_histogram IS NULL
+             ORDER BY created_at"
+        )
+            .fetch_all(pool)
+            .await?;
+
+        let mut updated = 0;
+        let mut skipped = 0;
+        let mut albums = HashSet::new();
+
+        for row in rows {
+            let song_id: Uuid = row.get("id");
+            let file_path: String = row.get("file_path");
+            let full_path = PathBuf::from(format!("{}/{}", config.upload_dir, file_path));
+            let upload_dir = config.upload_dir.clone();
+            let stored_path = file_path.clone();
+
+            let analysed = actix_web::web::block(move || {
+                let info = media::probe_audio(&full_path)?;
+                let waveform_path = media::save_waveform(&upload_dir, &stored_path, &info.waveform)?;
+                Ok::<_, String>((info, waveform_path))
+            });
+            let (info, waveform_path) = match analysed.await {
+                Ok(Ok(analysed)) => analysed,
+                Ok(Err(e)) => {
+                    log::warn!("Skipping song {} ({}): {}", song_id, file_path, e);
+                    skipped += 1;
+                    continue;
+                }
+                Err(e) => return Err(AppError::internal(e)),
+            };
+
+            sqlx::query(
+                "UPDATE songs SET duration = $2, duration_ms = $3, codec = $4, sample_rate = $5, channels = $6, bitrate = $7,
+                                  waveform_path = $8, loudness_lufs = $9, loudness_range_lu = $10, true_peak_dbtp = $11,
+                                  loudness_histogram = $12
+                 WHERE id = $1"
+            )
             .bind(song_id)
-            .fetch_optional(pool)
-            .await
-            .map_err(|e| format!("Database error: {}", e))?;
-
-        Ok(row.map(|r| Song {
-            id: r.get("id"),
-            title: r.get("title"),
-            artist: r.get("artist"),
-            album: r.get("album"),
-            duration: r.get("duration"),
-            file_path: r.get("file_path"),
-            cover_art: r.get("cover_art"),
-            created_at: r.get("created_at"),
-        }))
+            .bind(info.duration_secs())
+            .bind(info.duration_ms)
+            .bind(&info.codec)
+            .bind(info.sample_rate)
+            .bind(info.channels)
+            .bind(info.bitrate)
+            .bind(&waveform_path)
+            .bind(info.loudness.integrated_lufs)
+            .bind(info.loudness.range_lu)
+            .bind(info.loudness.true_peak_dbtp)
+            .bind(sqlx::types::Json(&info.loudness.histogram))
+            .execute(pool)
+            .await?;
+
+            albums.insert((row.get::<Option<Uuid>, _>("uploaded_by"), row.get::<String, _>("album")));
+            updated += 1;
+        }
+
+        // Albums are measured once all of their songs have been
+        for (uploaded_by, album) in albums {
+            Self::update_album_loudness(pool, uploaded_by, &album).await?;
+        }
+
+        Ok((updated, skipped))
     }
 
-    pub async fn search_songs(pool: &PgPool, query: &str) -> Result<Vec<Song>, String> {
-        let search_pattern = format!("%{}%", query);
-        let rows = sqlx::query("SELECT id, title, artist, album, duration, file_path, cover_art, created_at FROM songs WHERE title ILIKE $1 OR artist ILIKE $1 OR album ILIKE $1")
-            .bind(&search_pattern)
+    /// Gives older songs resized covers: a cover saved as a single file
+    /// before renditions existed is converted, and a song without one gets
+    /// its embedded artwork, if any. Returns how many songs were updated and
+    /// skipped.
+    pub async fn backfill_covers(pool: &PgPool, config: &Config) -> Result<(usize, usize), AppError> {
+        // Cover directories are bare ids; the old single files had an extension
+        let rows = sqlx::query("SELECT id, file_path, cover_art FROM songs WHERE cover_art IS NULL OR cover_art LIKE '%.%'")
             .fetch_all(pool)
-            .await
-            .map_err(|e| format!("Database error: {}", e))?;
+            .await?;
+
+        let mut updated = 0;
+        let mut skipped = 0;
+
+        for row in rows {
+            let song_id: Uuid = row.get("id");
+            let file_path: String = row.get("file_path");
+            let legacy_cover: Option<String> = row.get("cover_art");
+
+            let upload_dir = config.upload_dir.clone();
+            let source = legacy_cover.clone();
+            let rendered = actix_web::web::block(move || {
+                let data = match source {
+                    Some(cover) => std::fs::read(format!("{}/{}", upload_dir, cover)).map_err(|e| e.to_string())?,
+                    None => match media::read_tags(&PathBuf::from(format!("{}/{}", upload_dir, file_path)))?.cover {
+                        Some(data) => data,
+                        None => return Ok(None),
+                    },
+                };
+                let cover_path = covers::render(&data)?.save(&upload_dir)?;
+                Ok::<_, String>(Some(cover_path))
+            });
 
-        let songs = rows.into_iter().map(|row| Song {
+            let cover_path = match rendered.await {
+                Ok(Ok(Some(cover_path))) => cover_path,
+                Ok(Ok(None)) => continue,
+                Ok(Err(e)) => {
+                    log::warn!("Skipping cover for song {}: {}", song_id, e);
+                    skipped += 1;
+                    continue;
+                }
+                Err(e) => return Err(AppError::internal(e)),
+            };
+
+            if let Err(e) = Self::set_cover(pool, song_id, Some(&cover_path)).await {
+                remove_upload(&config.upload_dir, &cover_path);
+                return Err(e);
+            }
+            if let Some(legacy_cover) = legacy_cover {
+                remove_upload(&config.upload_dir, &legacy_cover);
+            }
+            updated += 1;
+        }
+
+        Ok((updated, skipped))
+    }
+
+    /// Puts a song back in the transcoding queue, e.g. after a failure.
+    pub async fn requeue_transcode(pool: &PgPool, song_id: Uuid) -> Result<bool, AppError> {
+        let result = sqlx::query(
+            "UPDATE songs SET transcode_status = 'pending', transcode_error = NULL, transcode_started_at = NULL,
+                              transcode_lease = NULL, transcode_heartbeat_at = NULL, transcode_attempts = 0
+             WHERE id = $1"
+        )
+        .bind(song_id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub fn from_row(row: &sqlx::postgres::PgRow) -> Song {
+        let cover_art: Option<String> = row.get("cover_art");
+        let loudness_lufs: Option<f64> = row.get("loudness_lufs");
+        let album_loudness_lufs: Option<f64> = row.get("album_loudness_lufs");
+
+        Song {
             id: row.get("id"),
             title: row.get("title"),
             artist: row.get("artist"),
             album: row.get("album"),
             duration: row.get("duration"),
+            duration_ms: row.get("duration_ms"),
+            codec: row.get("codec"),
+            sample_rate: row.get("sample_rate"),
+            channels: row.get("channels"),
+            bitrate: row.get("bitrate"),
+            track_number: row.get("track_number"),
+            year: row.get("year"),
+            genre: row.get("genre"),
+            transcode_status: row.get("transcode_status"),
+            renditions: row.get("renditions"),
             file_path: row.get("file_path"),
-            cover_art: row.get("cover_art"),
+            cover: cover_images(&cover_art),
+            cover_art,
+            waveform_path: row.get("waveform_path"),
+            loudness_lufs,
+            loudness_range_lu: row.get("loudness_range_lu"),
+            true_peak_dbtp: row.get("true_peak_dbtp"),
+            track_gain_db: loudness_lufs.map(replaygain_db),
+            album_loudness_lufs,
+            album_true_peak_dbtp: row.get("album_true_peak_dbtp"),
+            album_gain_db: album_loudness_lufs.map(replaygain_db),
             created_at: row.get("created_at"),
-        }).collect();
+        }
+    }
+}
 
-        Ok(songs)
+// Workers renew their lease this often while ffmpeg runs. A lease that
+// hasn't been renewed for `TRANSCODE_LEASE_SECONDS` belonged to a worker
+// that died, and another may take the song over.
+const TRANSCODE_HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+const TRANSCODE_LEASE_SECONDS: i32 = 120;
+const MAX_TRANSCODE_ATTEMPTS: i32 = 3;
+
+pub struct TranscodeService;
+
+impl TranscodeService {
+    /// Claims the oldest song waiting for transcoding and produces its
+    /// renditions. Returns false when the queue is empty. `SKIP LOCKED` and
+    /// the lease let several instances share the queue without two of them
+    /// ever working on the same song.
+    pub async fn process_next(pool: &PgPool, config: &Config) -> Result<bool, AppError> {
+        Self::give_up_interrupted(pool).await?;
+
+        let lease = Uuid::new_v4();
+        let row = match Self::claim(pool, lease).await? {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+
+        let song_id: Uuid = row.get("id");
+        let file_path: String = row.get("file_path");
+        let source = PathBuf::from(format!("{}/{}", config.upload_dir, file_path));
+        let qualities = transcode::planned_renditions(row.get("bitrate"));
+        let staging_path = transcode::staging_dir(song_id, lease);
+        let staging = PathBuf::from(&config.upload_dir).join(&staging_path);
+
+        let transcoded = tokio::select! {
+            result = transcode::transcode(&config.ffmpeg_path, &staging, &source, &qualities) => Some(result),
+            () = Self::hold_lease(pool, song_id, lease) => None,
+        };
+
+        // Locking the row keeps anyone from claiming the song while its
+        // files are swapped
+        let mut tx = pool.begin().await?;
+        let owned = sqlx::query("SELECT 1 FROM songs WHERE id = $1 AND transcode_lease = $2 FOR UPDATE")
+            .bind(song_id)
+            .bind(lease)
+            .fetch_optional(&mut tx)
+            .await?
+            .is_some();
+
+        // The song was deleted or requeued, or another worker took it over
+        let result = match transcoded {
+            Some(result) if owned => result,
+            _ => {
+                log::info!("Dropping transcode of song {}: it is no longer ours", song_id);
+                remove_upload(&config.upload_dir, &staging_path);
+                return Ok(true);
+            }
+        };
+
+        let result = match result {
+            Ok(()) => transcode::publish(&config.upload_dir, song_id, &staging).await,
+            Err(e) => Err(e),
+        };
+
+        match &result {
+            Ok(()) => {
+                sqlx::query(
+                    "UPDATE songs SET transcode_status = 'ready', renditions = $2, transcode_lease = NULL
+                     WHERE id = $1"
+                )
+                .bind(song_id)
+                .bind(&qualities)
+                .execute(&mut tx)
+                .await?;
+            }
+            Err(e) => {
+                log::warn!("Transcoding song {} failed: {}", song_id, e);
+                remove_upload(&config.upload_dir, &staging_path);
+                sqlx::query(
+                    "UPDATE songs SET transcode_status = 'failed', transcode_error = $2, renditions = '{}', transcode_lease = NULL
+                     WHERE id = $1"
+                )
+                .bind(song_id)
+                .bind(e)
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    // A job whose worker keeps dying (ffmpeg killed for memory, say) would
+    // otherwise take every worker down in turn
+    async fn give_up_interrupted(pool: &PgPool) -> Result<(), AppError> {
+        sqlx::query(
+            "UPDATE songs SET transcode_status = 'failed', transcode_lease = NULL,
+                              transcode_error = 'Transcoding was interrupted ' || transcode_attempts || ' times'
+             WHERE transcode_status = 'processing'
+               AND transcode_heartbeat_at < NOW() - $1 * INTERVAL '1 second'
+               AND transcode_attempts >= $2"
+        )
+        .bind(TRANSCODE_LEASE_SECONDS)
+        .bind(MAX_TRANSCODE_ATTEMPTS)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn claim(pool: &PgPool, lease: Uuid) -> Result<Option<sqlx::postgres::PgRow>, AppError> {
+        let row = sqlx::query(
+            "UPDATE songs SET transcode_status = 'processing', transcode_error = NULL, transcode_lease = $2,
+                              transcode_started_at = NOW(), transcode_heartbeat_at = NOW(),
+                              transcode_attempts = transcode_attempts + 1
+             WHERE id = (
+                 SELECT id FROM songs
+                 WHERE transcode_status = 'pending'
+                    OR (transcode_status = 'processing' AND transcode_heartbeat_at < NOW() - $1 * INTERVAL '1 second')
+                 ORDER BY created_at
+                 LIMIT 1
+                 FOR UPDATE SKIP LOCKED
+             )
+             RETURNING id, file_path, bitrate"
+        )
+        .bind(TRANSCODE_LEASE_SECONDS)
+        .bind(lease)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row)
+    }
+
+    async fn renew_lease(pool: &PgPool, song_id: Uuid, lease: Uuid) -> Result<bool, AppError> {
+        let result = sqlx::query("UPDATE songs SET transcode_heartbeat_at = NOW() WHERE id = $1 AND transcode_lease = $2")
+            .bind(song_id)
+            .bind(lease)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Only returns once the lease is lost. While the database is unreachable
+    // the work goes on; the lease then runs out and the next renewal fails.
+    async fn hold_lease(pool: &PgPool, song_id: Uuid, lease: Uuid) {
+        let mut interval = tokio::time::interval(TRANSCODE_HEARTBEAT_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match Self::renew_lease(pool, song_id, lease).await {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(e) => log::warn!("Failed to renew the transcode lease on song {}: {}", song_id, e),
+            }
+        }
     }
 }
 
@@ -165,51 +2786,46 @@ impl PlaylistService {
         user_id: Uuid,
         name: &str,
         description: Option<&str>,
-    ) -> Result<Playlist, String> {
+        is_public: bool,
+    ) -> Result<Playlist, AppError> {
         let playlist_id = Uuid::new_v4();
         let now = chrono::Utc::now();
 
         let row = sqlx::query(
-            "INSERT INTO playlists (id, name, user_id, description, created_at, updated_at)
-             VALUES ($1, $2, $3, $4, $5, $6) RETURNING id, name, user_id, description, cover_image, created_at, updated_at"
+            "INSERT INTO playlists (id, name, user_id, description, is_public, created_at, updated_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id, name, user_id, description, cover_image, is_public, created_at, updated_at"
         )
         .bind(playlist_id)
         .bind(name)
         .bind(user_id)
         .bind(description)
+        .bind(is_public)
         .bind(now)
         .bind(now)
         .fetch_one(pool)
-        .await
-        .map_err(|e| format!("Database error: {}", e))?;
+        .await?;
 
-        Ok(Playlist {
-            id: row.get("id"),
-            name: row.get("name"),
-            user_id: row.get("user_id"),
-            description: row.get("description"),
-            cover_image: row.get("cover_image"),
-            created_at: row.get("created_at"),
-            updated_at: row.get("updated_at"),
-        })
+        Ok(Self::from_row(&row))
     }
 
-    pub async fn get_user_playlists(pool: &PgPool, user_id: Uuid) -> Result<Vec<Playlist>, String> {
-        let rows = sqlx::query("SELECT id, name, user_id, description, cover_image, created_at, updated_at FROM playlists WHERE user_id = $1 ORDER BY created_at DESC")
+    pub async fn get_user_playlists(pool: &PgPool, user_id: Uuid) -> Result<Vec<Playlist>, AppError> {
+        let rows = sqlx::query("SELECT id, name, user_id, description, cover_image, is_public, created_at, updated_at FROM playlists WHERE user_id = $1 ORDER BY created_at DESC")
             .bind(user_id)
             .fetch_all(pool)
-            .await
-            .map_err(|e| format!("Database error: {}", e))?;
+            .await?;
 
-        let playlists = rows.into_iter().map(|row| Playlist {
-            id: row.get("id"),
-            name: row.get("name"),
-            user_id: row.get("user_id"),
-            description: row.get("description"),
-            cover_image: row.get("cover_image"),
-            created_at: row.get("created_at"),
-            updated_at: row.get("updated_at"),
-        }).collect();
+        let playlists = rows.iter().map(Self::from_row).collect();
+
+        Ok(playlists)
+    }
+
+    pub async fn get_public_playlists(pool: &PgPool, user_id: Uuid) -> Result<Vec<Playlist>, AppError> {
+        let rows = sqlx::query("SELECT id, name, user_id, description, cover_image, is_public, created_at, updated_at FROM playlists WHERE user_id = $1 AND is_public ORDER BY created_at DESC")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+        let playlists = rows.iter().map(Self::from_row).collect();
 
         Ok(playlists)
     }
@@ -217,40 +2833,25 @@ impl PlaylistService {
     pub async fn get_playlist_with_songs(
         pool: &PgPool,
         playlist_id: Uuid,
-    ) -> Result<Option<PlaylistWithSongs>, String> {
-        let playlist_row = sqlx::query("SELECT id, name, user_id, description, cover_image, created_at, updated_at FROM playlists WHERE id = $1")
+    ) -> Result<Option<PlaylistWithSongs>, AppError> {
+        let playlist_row = sqlx::query(
+            "SELECT p.id, p.name, p.user_id, p.description, p.cover_image, p.is_public, p.created_at, p.updated_at
+             FROM playlists p JOIN users u ON u.id = p.user_id
+             WHERE p.id = $1 AND u.deleted_at IS NULL"
+        )
             .bind(playlist_id)
             .fetch_optional(pool)
-            .await
-            .map_err(|e| format!("Database error: {}", e))?;
+            .await?;
 
         if let Some(row) = playlist_row {
-            let playlist = Playlist {
-                id: row.get("id"),
-                name: row.get("name"),
-                user_id: row.get("user_id"),
-                description: row.get("description"),
-                cover_image: row.get("cover_image"),
-                created_at: row.get("created_at"),
-                updated_at: row.get("updated_at"),
-            };
+            let playlist = Self::from_row(&row);
 
-            let song_rows = sqlx::query("SELECT s.id, s.title, s.artist, s.album, s.duration, s.file_path, s.cover_art, s.created_at FROM songs s JOIN playlist_songs ps ON s.id = ps.song_id WHERE ps.playlist_id = $1 ORDER BY ps.position")
+            let song_rows = sqlx::query(&format!("SELECT {} FROM songs s JOIN playlist_songs ps ON s.id = ps.song_id WHERE ps.playlist_id = $1 ORDER BY ps.position", SONG_COLUMNS))
                 .bind(playlist_id)
                 .fetch_all(pool)
-                .await
-                .map_err(|e| format!("Database error: {}", e))?;
-
-            let songs = song_rows.into_iter().map(|row| Song {
-                id: row.get("id"),
-                title: row.get("title"),
-                artist: row.get("artist"),
-                album: row.get("album"),
-                duration: row.get("duration"),
-                file_path: row.get("file_path"),
-                cover_art: row.get("cover_art"),
-                created_at: row.get("created_at"),
-            }).collect();
+                .await?;
+
+            let songs = song_rows.iter().map(SongService::from_row).collect();
 
             Ok(Some(PlaylistWithSongs {
                 id: playlist.id,
@@ -258,6 +2859,8 @@ impl PlaylistService {
                 user_id: playlist.user_id,
                 description: playlist.description,
                 cover_image: playlist.cover_image,
+                cover: playlist.cover,
+                is_public: playlist.is_public,
                 created_at: playlist.created_at,
                 updated_at: playlist.updated_at,
                 songs,
@@ -267,17 +2870,68 @@ impl PlaylistService {
         }
     }
 
+    pub async fn get_playlist_owner(pool: &PgPool, playlist_id: Uuid) -> Result<Option<Uuid>, AppError> {
+        let row = sqlx::query("SELECT user_id FROM playlists WHERE id = $1")
+            .bind(playlist_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.map(|r| r.get("user_id")))
+    }
+
+    // Returns the deleted playlist's cover, if any, so the caller can remove it
+    pub async fn delete_playlist(pool: &PgPool, playlist_id: Uuid) -> Result<Option<Option<String>>, AppError> {
+        let row = sqlx::query("DELETE FROM playlists WHERE id = $1 RETURNING cover_image")
+            .bind(playlist_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.map(|row| row.get("cover_image")))
+    }
+
+    /// Points the playlist at a new cover (or none) and returns the one it
+    /// replaces so the caller can delete it.
+    pub async fn set_cover(pool: &PgPool, playlist_id: Uuid, cover_path: Option<&str>) -> Result<Option<String>, AppError> {
+        let row = sqlx::query(
+            "UPDATE playlists p SET cover_image = $1, updated_at = NOW()
+             FROM (SELECT cover_image FROM playlists WHERE id = $2 FOR UPDATE) old
+             WHERE p.id = $2
+             RETURNING old.cover_image"
+        )
+        .bind(cover_path)
+        .bind(playlist_id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.and_then(|row| row.get("cover_image")))
+    }
+
+    fn from_row(row: &sqlx::postgres::PgRow) -> Playlist {
+        let cover_image: Option<String> = row.get("cover_image");
+
+        Playlist {
+            id: row.get("id"),
+            name: row.get("name"),
+            user_id: row.get("user_id"),
+            description: row.get("description"),
+            cover: cover_images(&cover_image),
+            cover_image,
+            is_public: row.get("is_public"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+
     pub async fn add_song_to_playlist(
         pool: &PgPool,
         playlist_id: Uuid,
         song_id: Uuid,
-    ) -> Result<(), String> {
+    ) -> Result<(), AppError> {
         // Get the next position
         let row = sqlx::query("SELECT COALESCE(MAX(position), 0) + 1 as next_position FROM playlist_songs WHERE playlist_id = $1")
             .bind(playlist_id)
             .fetch_one(pool)
-            .await
-            .map_err(|e| format!("Database error: {}", e))?;
+            .await?;
 
         let next_position: i32 = row.get("next_position");
 
@@ -287,9 +2941,351 @@ impl PlaylistService {
             .bind(next_position)
             .bind(chrono::Utc::now())
             .execute(pool)
-            .await
-            .map_err(|e| format!("Database error: {}", e))?;
+            .await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn reused_refresh_tokens_revoke_the_session() {
+        let pool = test_support::pool().await;
+        let config = test_support::config();
+        let client = ClientInfo::default();
+        let user_id = test_support::create_user(&pool).await;
+
+        let first = AuthService::start_session(&pool, &config, user_id, Role::Listener, &client).await.unwrap();
+        let second = AuthService::refresh(&pool, &config, &client, &first.refresh_token).await.unwrap();
+        assert_ne!(second.refresh_token, first.refresh_token);
+
+        // Replaying the rotated token ends the whole family, including the new token
+        assert!(AuthService::refresh(&pool, &config, &client, &first.refresh_token).await.is_err());
+        assert!(AuthService::refresh(&pool, &config, &client, &second.refresh_token).await.is_err());
+
+        let revoked: bool = sqlx::query("SELECT revoked_at IS NOT NULL AS revoked FROM sessions WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("revoked");
+        assert!(revoked);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn expired_and_logged_out_refresh_tokens_are_rejected() {
+        let pool = test_support::pool().await;
+        let config = test_support::config();
+        let client = ClientInfo::default();
+        let user_id = test_support::create_user(&pool).await;
+
+        let expired = AuthService::start_session(&pool, &config, user_id, Role::Listener, &client).await.unwrap();
+        sqlx::query("UPDATE refresh_tokens SET expires_at = NOW() - INTERVAL '1 minute' WHERE token_hash = $1")
+            .bind(hash_token(&expired.refresh_token))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(AuthService::refresh(&pool, &config, &client, &expired.refresh_token).await.is_err());
+
+        let tokens = AuthService::start_session(&pool, &config, user_id, Role::Listener, &client).await.unwrap();
+        AuthService::logout(&pool, &client, &tokens.refresh_token).await.unwrap();
+        assert!(AuthService::refresh(&pool, &config, &client, &tokens.refresh_token).await.is_err());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn recovery_codes_work_once() {
+        let pool = test_support::pool().await;
+        let user_id = test_support::create_user(&pool).await;
+
+        sqlx::query("INSERT INTO mfa_recovery_codes (id, user_id, code_hash) VALUES ($1, $2, $3)")
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(hash_token(&mfa::normalize_recovery_code("abcde-fghij")))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(!MfaService::consume_recovery_code(&pool, user_id, "abcde-zzzzz").await.unwrap());
+        assert!(MfaService::consume_recovery_code(&pool, user_id, "ABCDE FGHIJ").await.unwrap());
+        assert!(!MfaService::consume_recovery_code(&pool, user_id, "abcde-fghij").await.unwrap());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn totp_steps_work_once() {
+        let pool = test_support::pool().await;
+        let user_id = test_support::create_user(&pool).await;
+
+        assert!(MfaService::consume_totp_step(&pool, user_id, 100).await.unwrap());
+        assert!(!MfaService::consume_totp_step(&pool, user_id, 100).await.unwrap());
+        // An older code still inside the skew window is a replay too
+        assert!(!MfaService::consume_totp_step(&pool, user_id, 99).await.unwrap());
+        assert!(MfaService::consume_totp_step(&pool, user_id, 101).await.unwrap());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn profile_counts_skip_deleted_accounts() {
+        let pool = test_support::pool().await;
+        let user_id = test_support::create_user(&pool).await;
+        let fan = test_support::create_user(&pool).await;
+        let idol = test_support::create_user(&pool).await;
+        let deleted = test_support::create_user(&pool).await;
+
+        SocialService::follow(&pool, fan, user_id).await.unwrap();
+        SocialService::follow(&pool, deleted, user_id).await.unwrap();
+        SocialService::follow(&pool, user_id, idol).await.unwrap();
+        SocialService::follow(&pool, user_id, deleted).await.unwrap();
+        sqlx::query("UPDATE users SET deleted_at = NOW() WHERE id = $1")
+            .bind(deleted)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let username = format!("user-{}", user_id.simple());
+        let profile = UserService::get_public_profile(&pool, &username, Some(fan)).await.unwrap().unwrap();
+        assert_eq!(profile.follower_count, 1);
+        assert_eq!(profile.following_count, 1);
+        assert!(profile.is_following);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn transcode_leases_keep_workers_apart() {
+        let pool = test_support::pool().await;
+        let user_id = test_support::create_user(&pool).await;
+        let song_id = test_support::create_song(&pool, user_id, "Album").await;
+        // Oldest in the queue, so it's the one claimed
+        sqlx::query("UPDATE songs SET transcode_status = 'pending', created_at = 'epoch' WHERE id = $1")
+            .bind(song_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let claimed = TranscodeService::claim(&pool, first).await.unwrap().unwrap();
+        assert_eq!(claimed.get::<Uuid, _>("id"), song_id);
+
+        // A slow job with a live heartbeat isn't taken over
+        let claimed = TranscodeService::claim(&pool, second).await.unwrap();
+        assert_ne!(claimed.map(|row| row.get::<Uuid, _>("id")), Some(song_id));
+        assert!(TranscodeService::renew_lease(&pool, song_id, first).await.unwrap());
+        assert!(!TranscodeService::renew_lease(&pool, song_id, second).await.unwrap());
+
+        // Once the heartbeat stops, the next worker takes over and the first
+        // one's lease is gone
+        let expire = "UPDATE songs SET transcode_heartbeat_at = NOW() - INTERVAL '1 hour' WHERE id = $1";
+        sqlx::query(expire).bind(song_id).execute(&pool).await.unwrap();
+        let claimed = TranscodeService::claim(&pool, second).await.unwrap().unwrap();
+        assert_eq!(claimed.get::<Uuid, _>("id"), song_id);
+        assert!(!TranscodeService::renew_lease(&pool, song_id, first).await.unwrap());
+
+        // After too many interruptions the song fails instead of being retried
+        sqlx::query("UPDATE songs SET transcode_attempts = $2 WHERE id = $1")
+            .bind(song_id)
+            .bind(MAX_TRANSCODE_ATTEMPTS)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(expire).bind(song_id).execute(&pool).await.unwrap();
+        TranscodeService::give_up_interrupted(&pool).await.unwrap();
+        let row = sqlx::query("SELECT transcode_status, transcode_error, transcode_lease FROM songs WHERE id = $1")
+            .bind(song_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<TranscodeStatus, _>("transcode_status"), TranscodeStatus::Failed);
+        assert_eq!(row.get::<Option<String>, _>("transcode_error").as_deref(), Some("Transcoding was interrupted 3 times"));
+        assert_eq!(row.get::<Option<Uuid>, _>("transcode_lease"), None);
+
+        // A requeue starts the count again
+        assert!(SongService::requeue_transcode(&pool, song_id).await.unwrap());
+        let claimed = TranscodeService::claim(&pool, first).await.unwrap().unwrap();
+        assert_eq!(claimed.get::<Uuid, _>("id"), song_id);
+
+        sqlx::query("DELETE FROM songs WHERE id = $1").bind(song_id).execute(&pool).await.unwrap();
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn album_covers_are_shared_and_outlive_single_songs() {
+        let pool = test_support::pool().await;
+        let user_id = test_support::create_user(&pool).await;
+        let first = test_support::create_song(&pool, user_id, "Album").await;
+        let second = test_support::create_song(&pool, user_id, "Album").await;
+        test_support::create_song(&pool, user_id, "Single").await;
+        let cover = |name: &str| format!("covers/{}-{}", user_id, name);
+
+        assert_eq!(SongService::set_cover(&pool, first, Some(&cover("own"))).await.unwrap(), None);
+        assert_eq!(SongService::set_album_cover_path(&pool, user_id, "Missing", &cover("x")).await.unwrap(), None);
+
+        // Both songs now point at the one album cover; the replaced one is unused
+        let unused = SongService::set_album_cover_path(&pool, user_id, "Album", &cover("album")).await.unwrap();
+        assert_eq!(unused, Some(vec![cover("own")]));
+        let sharing: i64 = sqlx::query("SELECT COUNT(*) AS n FROM songs WHERE cover_art = $1")
+            .bind(cover("album"))
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("n");
+        assert_eq!(sharing, 2);
+
+        // The album cover survives while any of its songs still shows it
+        let files = SongService::delete_song(&pool, second).await.unwrap().unwrap();
+        assert!(files.contains(&format!("songs/{}.mp3", second)));
+        assert!(!files.contains(&cover("album")));
+        assert_eq!(SongService::set_cover(&pool, first, None).await.unwrap(), Some(cover("album")));
+
+        // A cover the album still uses is never handed back for deletion
+        SongService::set_album_cover_path(&pool, user_id, "Album", &cover("again")).await.unwrap();
+        let unused = SongService::set_album_cover_path(&pool, user_id, "Album", &cover("again")).await.unwrap();
+        assert_eq!(unused, Some(Vec::new()));
+    }
+
+    // The only test that touches roles, since "no admin yet" is database-wide
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn admins_are_bootstrapped_once_and_never_all_demoted() {
+        let pool = test_support::pool().await;
+        let client = ClientInfo::default();
+        sqlx::query("UPDATE users SET role = 'listener' WHERE role = 'admin'").execute(&pool).await.unwrap();
+
+        let first = test_support::create_user(&pool).await;
+        let second = test_support::create_user(&pool).await;
+        let email = |id: Uuid| format!("{}@example.test", id.simple());
+
+        assert!(!UserService::promote_admin_by_email(&pool, &email(first)).await.unwrap());
+        sqlx::query("UPDATE users SET email_verified_at = NOW() WHERE id = ANY($1)")
+            .bind(vec![first, second])
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(UserService::promote_admin_by_email(&pool, &email(first)).await.unwrap());
+        assert!(!UserService::promote_admin_by_email(&pool, &email(second)).await.unwrap());
+
+        let err = UserService::update_role(&pool, &client, first, first, Role::Listener).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::LastAdmin);
+
+        UserService::update_role(&pool, &client, first, second, Role::Admin).await.unwrap();
+        let demoted = UserService::update_role(&pool, &client, second, first, Role::Listener).await.unwrap().unwrap();
+        assert_eq!(demoted.role, Role::Listener);
+
+        let metadata: serde_json::Value = sqlx::query(
+            "SELECT metadata FROM audit_events WHERE action = 'role_changed' AND actor_id = $1 AND target_id = $2"
+        )
+        .bind(second)
+        .bind(first)
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .get("metadata");
+        assert_eq!(metadata, serde_json::json!({ "old_role": "admin", "new_role": "listener" }));
+
+        // A demoted admin stays demoted across restarts
+        assert!(!UserService::promote_admin_by_email(&pool, &email(first)).await.unwrap());
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn mfa_confirm_and_disable_are_throttled() {
+        let pool = test_support::pool().await;
+        let config = test_support::config();
+        let limiter = crate::rate_limit::InMemoryLimiter::new();
+        let client = ClientInfo::default();
+        let attempts = config.login_max_attempts;
+
+        let enrolling = test_support::create_user(&pool).await;
+        MfaService::begin_enrollment(&pool, &config, enrolling).await.unwrap();
+        for _ in 0..attempts {
+            let err = MfaService::confirm_enrollment(&pool, &limiter, &config, &client, enrolling, "not-a-code").await.unwrap_err();
+            assert_eq!(err.code(), ErrorCode::ValidationFailed);
+        }
+        let err = MfaService::confirm_enrollment(&pool, &limiter, &config, &client, enrolling, "not-a-code").await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::RateLimited);
+
+        let enrolled = test_support::create_user(&pool).await;
+        MfaService::begin_enrollment(&pool, &config, enrolled).await.unwrap();
+        sqlx::query("UPDATE users SET mfa_enabled_at = NOW(), password_hash = $1 WHERE id = $2")
+            .bind(hash_password("correct horse", &config).unwrap())
+            .bind(enrolled)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let err = MfaService::disable(&pool, &limiter, &config, &client, enrolled, "wrong", "123456").await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidCredentials);
+        for _ in 0..attempts {
+            let err = MfaService::disable(&pool, &limiter, &config, &client, enrolled, "correct horse", "not-a-code").await.unwrap_err();
+            assert_eq!(err.code(), ErrorCode::ValidationFailed);
+        }
+        let err = MfaService::disable(&pool, &limiter, &config, &client, enrolled, "correct horse", "not-a-code").await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::RateLimited);
+
+        let enabled: bool = sqlx::query("SELECT mfa_enabled_at IS NOT NULL AS enabled FROM users WHERE id = $1")
+            .bind(enrolled)
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("enabled");
+        assert!(enabled);
+    }
+
+    async fn audit_actions(pool: &PgPool, user_id: Uuid) -> Vec<String> {
+        sqlx::query("SELECT action FROM audit_events WHERE actor_id = $1 ORDER BY action")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("action"))
+            .collect()
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn account_security_changes_are_audited() {
+        let pool = test_support::pool().await;
+        let config = test_support::config();
+        let limiter = crate::rate_limit::InMemoryLimiter::new();
+        let client = ClientInfo::default();
+        let user_id = test_support::create_user(&pool).await;
+
+        let token = AccessTokenService::create(&pool, &client, user_id, "ci", &[Scope::SongsRead], None).await.unwrap();
+        assert!(AccessTokenService::revoke(&pool, &client, user_id, token.details.id).await.unwrap());
+        assert!(!AccessTokenService::revoke(&pool, &client, user_id, token.details.id).await.unwrap());
+
+        let current = SessionService::create(&pool, user_id, &client).await.unwrap();
+        let other = SessionService::create(&pool, user_id, &client).await.unwrap();
+        SessionService::create(&pool, user_id, &client).await.unwrap();
+        assert!(SessionService::revoke(&pool, &client, user_id, other).await.unwrap());
+        assert_eq!(SessionService::revoke_others(&pool, &client, user_id, current).await.unwrap(), 1);
+
+        MfaService::begin_enrollment(&pool, &config, user_id).await.unwrap();
+        sqlx::query("UPDATE users SET mfa_enabled_at = NOW(), password_hash = $1 WHERE id = $2")
+            .bind(hash_password("correct horse", &config).unwrap())
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO mfa_recovery_codes (id, user_id, code_hash) VALUES ($1, $2, $3)")
+            .bind(Uuid::new_v4())
+            .bind(user_id)
+            .bind(hash_token(&mfa::normalize_recovery_code("abcde-fghij")))
+            .execute(&pool)
+            .await
+            .unwrap();
+        MfaService::disable(&pool, &limiter, &config, &client, user_id, "correct horse", "abcde-fghij").await.unwrap();
+
+        assert_eq!(
+            audit_actions(&pool, user_id).await,
+            ["access_token_created", "access_token_revoked", "mfa_disabled", "session_revoked", "session_revoked"]
+        );
+    }
+}
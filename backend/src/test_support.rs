@@ -0,0 +1,92 @@
+//! Helpers for tests that need Postgres. Those tests are `#[ignore]`d so a
+//! plain `cargo test` needs no database; run them with
+//! `TEST_DATABASE_URL=postgres://... cargo test -- --ignored`.
+
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::jwt_keys::{JwtKeys, PurposeKeys};
+
+const SECRET: &str = "test-secret";
+
+// Fixed values instead of `Config::from_env`, with cheap argon2 parameters
+pub fn config() -> Config {
+    Config {
+        database_url: String::new(),
+        jwt_keys: Arc::new(JwtKeys::hmac(SECRET)),
+        purpose_keys: Arc::new(PurposeKeys::derive(SECRET)),
+        host: "127.0.0.1".to_string(),
+        port: 8080,
+        upload_dir: std::env::temp_dir().join("crate-test-uploads").to_string_lossy().into_owned(),
+        app_url: "http://localhost:3000".to_string(),
+        mail_dir: std::env::temp_dir().join("crate-test-mail").to_string_lossy().into_owned(),
+        mail_from: "no-reply@example.test".to_string(),
+        require_email_verification: false,
+        admin_email: None,
+        mfa_issuer: "Test".to_string(),
+        mfa_encryption_key: vec![7; 32],
+        login_limiter: "memory".to_string(),
+        login_max_attempts: 5,
+        login_max_attempts_per_ip: 20,
+        login_window_seconds: 900,
+        login_lockout_seconds: 60,
+        login_max_lockout_seconds: 3600,
+        argon2_memory_kib: 1024,
+        argon2_iterations: 1,
+        argon2_parallelism: 1,
+        oidc_providers: Vec::new(),
+        oidc_redirect_url: "http://localhost:3000/oidc/callback".to_string(),
+        account_deletion_grace_days: 30,
+        ffmpeg_path: "ffmpeg".to_string(),
+        trusted_proxies: Vec::new(),
+    }
+}
+
+pub async fn pool() -> PgPool {
+    let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&url)
+        .await
+        .expect("Failed to connect to the test database");
+
+    sqlx::migrate!()
+        .run(&pool)
+        .await
+        .expect("Failed to run migrations");
+
+    pool
+}
+
+// Every test gets its own user so tests can share one database
+pub async fn create_user(pool: &PgPool) -> Uuid {
+    let id = Uuid::new_v4();
+    sqlx::query("INSERT INTO users (id, username, email, password_hash) VALUES ($1, $2, $3, 'x')")
+        .bind(id)
+        .bind(format!("user-{}", id.simple()))
+        .bind(format!("{}@example.test", id.simple()))
+        .execute(pool)
+        .await
+        .expect("Failed to create test user");
+    id
+}
+
+// Starts out transcoded so the song stays out of the transcoding queue
+pub async fn create_song(pool: &PgPool, uploaded_by: Uuid, album: &str) -> Uuid {
+    let id = Uuid::new_v4();
+    sqlx::query(
+        "INSERT INTO songs (id, title, artist, album, duration, file_path, uploaded_by, transcode_status)
+         VALUES ($1, 'Title', 'Artist', $2, 180, $3, $4, 'ready')"
+    )
+    .bind(id)
+    .bind(album)
+    .bind(format!("songs/{}.mp3", id))
+    .bind(uploaded_by)
+    .execute(pool)
+    .await
+    .expect("Failed to create test song");
+    id
+}
@@ -0,0 +1,233 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use uuid::Uuid;
+
+use crate::models::StreamQuality;
+
+const HLS_SEGMENT_SECONDS: u32 = 6;
+// Our own master playlist names the media playlists after the quality
+pub const HLS_MASTER_PLAYLIST: &str = "master.m3u8";
+pub const HLS_MEDIA_PLAYLIST: &str = "index.m3u8";
+
+/// A song's renditions and HLS files, relative to the upload directory.
+pub fn song_dir(song_id: Uuid) -> String {
+    format!("transcodes/{}", song_id)
+}
+
+/// Where one transcoding attempt writes before `publish` moves it into
+/// place, so a worker that lost its lease can't touch the live files.
+pub fn staging_dir(song_id: Uuid, lease: Uuid) -> String {
+    format!("{}.{}.partial", song_dir(song_id), lease)
+}
+
+/// The progressive (single file) rendition served by `/stream?quality=`.
+pub fn rendition_path(song_id: Uuid, quality: StreamQuality) -> String {
+    format!("{}/{}", song_dir(song_id), rendition_file(quality))
+}
+
+fn rendition_file(quality: StreamQuality) -> String {
+    format!("{}.m4a", quality.as_str())
+}
+
+/// True for the names ffmpeg gives HLS segments, e.g. `segment_007.ts`.
+/// Anything else under `hls/` is refused, which also rules out traversal.
+pub fn is_segment_name(name: &str) -> bool {
+    name.strip_prefix("segment_")
+        .and_then(|rest| rest.strip_suffix(".ts"))
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+pub async fn ffmpeg_available(ffmpeg: &str) -> bool {
+    Command::new(ffmpeg)
+        .arg("-version")
+        .output()
+        .await
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// The renditions worth producing. Upscaling a lossy original only costs
+/// space, so a rendition is skipped unless the source beats its bitrate;
+/// the lowest one is always made so every song has a light option.
+pub fn planned_renditions(source_bitrate: Option<i32>) -> Vec<StreamQuality> {
+    StreamQuality::TRANSCODED
+        .into_iter()
+        .filter(|quality| {
+            let target = quality.bitrate_kbps().unwrap_or(0) as i64 * 1000;
+            *quality == StreamQuality::Low || source_bitrate.is_none_or(|source| source as i64 > target)
+        })
+        .collect()
+}
+
+/// Encodes `source` into each of `qualities` as AAC: an `.m4a` for
+/// progressive download and an HLS media playlist with segments, then
+/// writes a master playlist over all of them. Everything goes in `dir`,
+/// laid out the way `song_dir` is served.
+pub async fn transcode(
+    ffmpeg: &str,
+    dir: &Path,
+    source: &Path,
+    qualities: &[StreamQuality],
+) -> Result<(), String> {
+    if dir.exists() {
+        tokio::fs::remove_dir_all(dir)
+            .await
+            .map_err(|e| format!("Failed to clear {}: {}", dir.display(), e))?;
+    }
+
+    for quality in qualities {
+        let bitrate = format!("{}k", quality.bitrate_kbps().unwrap_or(0));
+        let rendition = dir.join(rendition_file(*quality));
+        let hls_dir = dir.join(quality.as_str());
+        tokio::fs::create_dir_all(&hls_dir)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", hls_dir.display(), e))?;
+
+        // Stereo AAC plays everywhere, including native HLS on Apple devices
+        run_ffmpeg(
+            ffmpeg,
+            [
+                OsStr::new("-i"),
+                source.as_os_str(),
+                OsStr::new("-map"),
+                OsStr::new("0:a:0"),
+                OsStr::new("-vn"),
+                OsStr::new("-ac"),
+                OsStr::new("2"),
+                OsStr::new("-c:a"),
+                OsStr::new("aac"),
+                OsStr::new("-b:a"),
+                OsStr::new(&bitrate),
+                OsStr::new("-movflags"),
+                OsStr::new("+faststart"),
+                rendition.as_os_str(),
+            ],
+        )
+        .await?;
+
+        // Segmenting the finished rendition avoids encoding twice
+        let segment_pattern = hls_dir.join("segment_%03d.ts");
+        let media_playlist = hls_dir.join(HLS_MEDIA_PLAYLIST);
+        let segment_seconds = HLS_SEGMENT_SECONDS.to_string();
+        run_ffmpeg(
+            ffmpeg,
+            [
+                OsStr::new("-i"),
+                rendition.as_os_str(),
+                OsStr::new("-c"),
+                OsStr::new("copy"),
+                OsStr::new("-f"),
+                OsStr::new("hls"),
+                OsStr::new("-hls_time"),
+                OsStr::new(&segment_seconds),
+                OsStr::new("-hls_playlist_type"),
+                OsStr::new("vod"),
+                OsStr::new("-hls_segment_filename"),
+                segment_pattern.as_os_str(),
+                media_playlist.as_os_str(),
+            ],
+        )
+        .await?;
+    }
+
+    let master = dir.join(HLS_MASTER_PLAYLIST);
+    tokio::fs::write(&master, master_playlist(qualities))
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", master.display(), e))
+}
+
+/// Replaces the song's served files with a finished `staging` directory.
+pub async fn publish(upload_dir: &str, song_id: Uuid, staging: &Path) -> Result<(), String> {
+    let dir = PathBuf::from(upload_dir).join(song_dir(song_id));
+    if dir.exists() {
+        tokio::fs::remove_dir_all(&dir)
+            .await
+            .map_err(|e| format!("Failed to clear {}: {}", dir.display(), e))?;
+    }
+    tokio::fs::rename(staging, &dir)
+        .await
+        .map_err(|e| format!("Failed to move {} into place: {}", staging.display(), e))
+}
+
+fn master_playlist(qualities: &[StreamQuality]) -> String {
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    for quality in qualities {
+        // BANDWIDTH is the peak rate; MPEG-TS framing adds roughly 10%
+        let bandwidth = quality.bitrate_kbps().unwrap_or(0) * 1100;
+        playlist.push_str(&format!(
+            "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"mp4a.40.2\"\n{}/{}\n",
+            bandwidth,
+            quality.as_str(),
+            HLS_MEDIA_PLAYLIST
+        ));
+    }
+    playlist
+}
+
+async fn run_ffmpeg<'a>(ffmpeg: &str, args: impl IntoIterator<Item = &'a OsStr>) -> Result<(), String> {
+    let output = Command::new(ffmpeg)
+        .args(["-nostdin", "-hide_banner", "-loglevel", "error", "-y"])
+        .args(args)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run {}: {}", ffmpeg, e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("ffmpeg exited with {}: {}", output.status, stderr.trim()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_names() {
+        assert!(is_segment_name("segment_000.ts"));
+        assert!(is_segment_name("segment_1234.ts"));
+        assert!(!is_segment_name("segment_.ts"));
+        assert!(!is_segment_name("segment_01a.ts"));
+        assert!(!is_segment_name("segment_001.ts.bak"));
+        assert!(!is_segment_name("index.m3u8"));
+        assert!(!is_segment_name("../segment_001.ts"));
+        assert!(!is_segment_name("segment_../001.ts"));
+        assert!(!is_segment_name("segment_+1.ts"));
+    }
+
+    #[test]
+    fn renditions_never_upscale() {
+        use StreamQuality::{High, Low, Normal};
+
+        assert_eq!(planned_renditions(None), [Low, Normal, High]);
+        assert_eq!(planned_renditions(Some(1_411_200)), [Low, Normal, High]);
+        assert_eq!(planned_renditions(Some(320_000)), [Low, Normal]);
+        assert_eq!(planned_renditions(Some(192_000)), [Low, Normal]);
+        assert_eq!(planned_renditions(Some(160_000)), [Low]);
+        assert_eq!(planned_renditions(Some(64_000)), [Low]);
+    }
+
+    #[test]
+    fn master_playlist_lists_each_rendition() {
+        assert_eq!(
+            master_playlist(&[StreamQuality::Low, StreamQuality::High]),
+            "#EXTM3U\n#EXT-X-VERSION:3\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=105600,CODECS=\"mp4a.40.2\"\nlow/index.m3u8\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=352000,CODECS=\"mp4a.40.2\"\nhigh/index.m3u8\n"
+        );
+    }
+
+    #[test]
+    fn staging_is_outside_the_served_directory() {
+        let song_id = Uuid::new_v4();
+        let staging = staging_dir(song_id, Uuid::new_v4());
+
+        assert!(!staging.starts_with(&format!("{}/", song_dir(song_id))));
+        assert_ne!(staging, staging_dir(song_id, Uuid::new_v4()));
+        assert_eq!(rendition_path(song_id, StreamQuality::Low), format!("transcodes/{}/low.m4a", song_id));
+    }
+}
@@ -1,41 +1,339 @@
-use bcrypt::{hash, verify, DEFAULT_COST};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{encode, EncodingKey, Header};
-use std::env;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
 use uuid::Uuid;
 
+use crate::config::Config;
+use crate::jwt_keys::{JwtKeys, PurposeKeys};
 use crate::middleware::Claims;
+use crate::models::Role;
 
-pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
-    hash(password, DEFAULT_COST)
+// Access tokens are short-lived; long sessions are kept alive with refresh tokens
+pub const ACCESS_TOKEN_TTL_MINUTES: i64 = 15;
+pub const REFRESH_TOKEN_TTL_DAYS: i64 = 30;
+pub const PASSWORD_RESET_TTL_MINUTES: i64 = 60;
+pub const EMAIL_VERIFICATION_TTL_HOURS: i64 = 24;
+pub const MFA_PENDING_TTL_MINUTES: i64 = 5;
+pub const OIDC_AUTH_REQUEST_TTL_MINUTES: i64 = 10;
+// Long enough to pause a track and come back to it; players fetch a new one
+// per song
+pub const STREAM_TOKEN_TTL_HOURS: i64 = 6;
+pub const MAX_AVATAR_BYTES: usize = 5 * 1024 * 1024;
+pub const MAX_COVER_BYTES: usize = 10 * 1024 * 1024;
+pub const MAX_SONG_BYTES: usize = 200 * 1024 * 1024;
+// Personal access tokens carry a recognisable prefix so the auth layer can
+// tell them apart from JWTs (and secret scanners can spot leaked ones)
+pub const ACCESS_TOKEN_PREFIX: &str = "scpat_";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EmailVerificationClaims {
+    sub: String, // user_id
+    email: String,
+    exp: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StreamClaims {
+    sub: String, // user_id
+    song: String, // song_id
+    exp: usize,
 }
 
-pub fn verify_password(password: &str, hash: &str) -> Result<bool, bcrypt::BcryptError> {
-    verify(password, hash)
+#[derive(Debug, Serialize, Deserialize)]
+struct MfaPendingClaims {
+    sub: String, // user_id
+    exp: usize,
 }
 
-pub fn create_jwt_token(user_id: Uuid) -> Result<String, jsonwebtoken::errors::Error> {
-    let jwt_secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    
+fn argon2_for(config: &Config) -> Result<Argon2<'static>, String> {
+    let params = Params::new(
+        config.argon2_memory_kib,
+        config.argon2_iterations,
+        config.argon2_parallelism,
+        None,
+    )
+    .map_err(|e| format!("Invalid argon2 parameters: {}", e))?;
+
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+// New hashes are always argon2id with the parameters from Config
+pub fn hash_password(password: &str, config: &Config) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    argon2_for(config)?
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+// Accepts argon2 PHC strings as well as legacy bcrypt ($2a$/$2b$/$2y$) hashes
+pub fn verify_password(password: &str, hash: &str) -> Result<bool, String> {
+    if hash.starts_with("$2") {
+        return bcrypt::verify(password, hash).map_err(|e| e.to_string());
+    }
+
+    let parsed = PasswordHash::new(hash).map_err(|e| e.to_string())?;
+    // The algorithm and parameters are read from the hash itself
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_ok())
+}
+
+// Checked against when a login names no account, so that costs as much as
+// a wrong password and response times don't reveal which emails exist.
+// Made once, with the configured parameters, on first use.
+pub fn dummy_password_hash(config: &Config) -> Result<&'static str, String> {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    if let Some(hash) = DUMMY_HASH.get() {
+        return Ok(hash);
+    }
+    let hash = hash_password(&generate_token(), config)?;
+    Ok(DUMMY_HASH.get_or_init(|| hash))
+}
+
+// True for bcrypt hashes and for argon2 hashes made with other parameters
+pub fn password_needs_rehash(hash: &str, config: &Config) -> bool {
+    let parsed = match PasswordHash::new(hash) {
+        Ok(parsed) => parsed,
+        Err(_) => return true,
+    };
+
+    if parsed.algorithm.as_str() != Algorithm::Argon2id.ident().as_str() {
+        return true;
+    }
+
+    match Params::try_from(&parsed) {
+        Ok(params) => {
+            params.m_cost() != config.argon2_memory_kib
+                || params.t_cost() != config.argon2_iterations
+                || params.p_cost() != config.argon2_parallelism
+        }
+        Err(_) => true,
+    }
+}
+
+pub fn create_jwt_token(
+    keys: &JwtKeys,
+    user_id: Uuid,
+    role: Role,
+    session_id: Uuid,
+) -> Result<String, jsonwebtoken::errors::Error> {
     let expiration = Utc::now()
-        .checked_add_signed(Duration::days(7))
+        .checked_add_signed(Duration::minutes(ACCESS_TOKEN_TTL_MINUTES))
         .expect("valid timestamp")
         .timestamp();
 
     let claims = Claims {
         sub: user_id.to_string(),
+        sid: session_id.to_string(),
+        role,
         exp: expiration as usize,
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(jwt_secret.as_bytes()),
-    )
+    keys.encode(&claims)
+}
+
+pub fn create_email_verification_token(
+    keys: &PurposeKeys,
+    user_id: Uuid,
+    email: &str,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let expiration = Utc::now()
+        .checked_add_signed(Duration::hours(EMAIL_VERIFICATION_TTL_HOURS))
+        .expect("valid timestamp")
+        .timestamp();
+
+    let claims = EmailVerificationClaims {
+        sub: user_id.to_string(),
+        email: email.to_string(),
+        exp: expiration as usize,
+    };
+
+    keys.email_verification.encode(&claims)
+}
+
+// Returns the user id and the email address the link was issued for
+pub fn decode_email_verification_token(keys: &PurposeKeys, token: &str) -> Result<(Uuid, String), String> {
+    let token_data = keys
+        .email_verification
+        .decode::<EmailVerificationClaims>(token)
+        .map_err(|_| "Invalid or expired verification link".to_string())?;
+
+    let user_id = Uuid::parse_str(&token_data.claims.sub)
+        .map_err(|_| "Invalid user ID in token".to_string())?;
+
+    Ok((user_id, token_data.claims.email))
+}
+
+// Issued after a correct password when the account has MFA enabled; it only
+// proves the first factor and can be traded in at /api/auth/mfa/verify
+pub fn create_mfa_pending_token(keys: &PurposeKeys, user_id: Uuid) -> Result<String, jsonwebtoken::errors::Error> {
+    let expiration = Utc::now()
+        .checked_add_signed(Duration::minutes(MFA_PENDING_TTL_MINUTES))
+        .expect("valid timestamp")
+        .timestamp();
+
+    let claims = MfaPendingClaims {
+        sub: user_id.to_string(),
+        exp: expiration as usize,
+    };
+
+    keys.mfa_pending.encode(&claims)
+}
+
+pub fn decode_mfa_pending_token(keys: &PurposeKeys, token: &str) -> Result<Uuid, String> {
+    let token_data = keys
+        .mfa_pending
+        .decode::<MfaPendingClaims>(token)
+        .map_err(|_| "Invalid or expired MFA session".to_string())?;
+
+    Uuid::parse_str(&token_data.claims.sub).map_err(|_| "Invalid user ID in token".to_string())
+}
+
+// Audio elements can't send an Authorization header, so players stream with
+// a token in the URL instead. It only unlocks the one song it was issued for.
+pub fn create_stream_token(keys: &PurposeKeys, user_id: Uuid, song_id: Uuid) -> Result<String, jsonwebtoken::errors::Error> {
+    let expiration = Utc::now()
+        .checked_add_signed(Duration::hours(STREAM_TOKEN_TTL_HOURS))
+        .expect("valid timestamp")
+        .timestamp();
+
+    let claims = StreamClaims {
+        sub: user_id.to_string(),
+        song: song_id.to_string(),
+        exp: expiration as usize,
+    };
+
+    keys.song_stream.encode(&claims)
+}
+
+// Returns the user the token was issued to, if it is valid for `song_id`
+pub fn decode_stream_token(keys: &PurposeKeys, token: &str, song_id: Uuid) -> Result<Uuid, String> {
+    let token_data = keys
+        .song_stream
+        .decode::<StreamClaims>(token)
+        .map_err(|_| "Invalid or expired stream token".to_string())?;
+
+    if token_data.claims.song != song_id.to_string() {
+        return Err("Stream token is for a different song".to_string());
+    }
+
+    Uuid::parse_str(&token_data.claims.sub).map_err(|_| "Invalid user ID in token".to_string())
+}
+
+// Opaque random token handed to the client; only its hash is stored
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub fn generate_access_token() -> String {
+    format!("{}{}", ACCESS_TOKEN_PREFIX, generate_token())
 }
 
-pub fn format_duration(seconds: i32) -> String {
-    let minutes = seconds / 60;
-    let remaining_seconds = seconds % 60;
-    format!("{}:{:02}", minutes, remaining_seconds)
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// Identifies an uploaded image by its magic bytes rather than trusting the
+// client's filename or content type
+pub fn image_extension(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some("png")
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("jpg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("gif")
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("webp")
+    } else {
+        None
+    }
+}
+
+// Deletes a stored upload, which may be a file or a whole directory
+pub fn remove_upload(upload_dir: &str, path: &str) {
+    let full_path = format!("{}/{}", upload_dir, path);
+    let result = if std::path::Path::new(&full_path).is_dir() {
+        std::fs::remove_dir_all(&full_path)
+    } else {
+        std::fs::remove_file(&full_path)
+    };
+    if let Err(e) = result {
+        log::warn!("Failed to remove {}: {}", full_path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn bcrypt_hashes_verify_and_need_upgrading() {
+        let config = test_support::config();
+        let hash = bcrypt::hash("hunter22", 4).unwrap();
+        assert!(hash.starts_with("$2b$"));
+
+        assert!(verify_password("hunter22", &hash).unwrap());
+        assert!(!verify_password("hunter23", &hash).unwrap());
+        assert!(password_needs_rehash(&hash, &config));
+    }
+
+    #[test]
+    fn argon2_hashes_follow_the_configured_parameters() {
+        let mut config = test_support::config();
+        let hash = hash_password("hunter22", &config).unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+
+        assert!(verify_password("hunter22", &hash).unwrap());
+        assert!(!verify_password("hunter23", &hash).unwrap());
+        assert!(!password_needs_rehash(&hash, &config));
+
+        config.argon2_memory_kib *= 2;
+        assert!(password_needs_rehash(&hash, &config));
+    }
+
+    #[test]
+    fn long_passwords_are_not_truncated() {
+        let config = test_support::config();
+        let prefix = "a".repeat(72);
+        let hash = hash_password(&format!("{}1", prefix), &config).unwrap();
+
+        assert!(verify_password(&format!("{}1", prefix), &hash).unwrap());
+        assert!(!verify_password(&format!("{}2", prefix), &hash).unwrap());
+    }
+
+    #[test]
+    fn unreadable_hashes_need_replacing() {
+        let config = test_support::config();
+        assert!(verify_password("hunter22", "not a hash").is_err());
+        assert!(password_needs_rehash("not a hash", &config));
+    }
+
+    #[test]
+    fn stream_tokens_only_unlock_their_own_song() {
+        let keys = PurposeKeys::derive("secret");
+        let user_id = Uuid::new_v4();
+        let song_id = Uuid::new_v4();
+        let token = create_stream_token(&keys, user_id, song_id).unwrap();
+
+        assert_eq!(decode_stream_token(&keys, &token, song_id), Ok(user_id));
+        assert_eq!(
+            decode_stream_token(&keys, &token, Uuid::new_v4()),
+            Err("Stream token is for a different song".to_string())
+        );
+        assert!(decode_stream_token(&PurposeKeys::derive("other"), &token, song_id).is_err());
+
+        let mfa_token = create_mfa_pending_token(&keys, user_id).unwrap();
+        assert!(decode_stream_token(&keys, &mfa_token, song_id).is_err());
+        assert!(decode_stream_token(&keys, "not-a-token", song_id).is_err());
+    }
 }
@@ -0,0 +1,212 @@
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::ops::Deref;
+use validator::{Validate, ValidationError};
+
+use crate::errors::AppError;
+
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 30;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+// Argon2 cost grows with input length, so cap what we're willing to hash
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+/// `web::Json<T>` that also runs `T`'s `Validate` rules. Any failure is a 422
+/// `VALIDATION_FAILED` listing every invalid field.
+pub struct ValidatedJson<T>(pub T);
+
+impl<T> Deref for ValidatedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for ValidatedJson<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<T>::from_request(req, payload);
+
+        Box::pin(async move {
+            let value = json.await?.into_inner();
+            value.validate().map_err(AppError::from)?;
+            Ok(ValidatedJson(value))
+        })
+    }
+}
+
+/// Malformed JSON bodies get the same error shape as everything else.
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .error_handler(|err, _| AppError::bad_request(format!("Invalid request body: {}", err)).into())
+}
+
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default()
+        .error_handler(|err, _| AppError::bad_request(format!("Invalid query string: {}", err)).into())
+}
+
+pub fn validation_error(code: &'static str, message: impl Into<Cow<'static, str>>) -> ValidationError {
+    let mut error = ValidationError::new(code);
+    error.message = Some(message.into());
+    error
+}
+
+// Usernames appear in URLs, so they're kept to a conservative charset
+pub fn validate_username(username: &str) -> Result<(), ValidationError> {
+    let length = username.chars().count();
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+        return Err(validation_error(
+            "length",
+            format!("Username must be between {} and {} characters", MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH),
+        ));
+    }
+
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        return Err(validation_error(
+            "charset",
+            "Username may only contain letters, digits, '_', '-' and '.'".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<(), ValidationError> {
+    let length = password.chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        return Err(validation_error(
+            "length",
+            format!("Password must be between {} and {} characters", MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH),
+        ));
+    }
+
+    let has_letter = password.chars().any(char::is_alphabetic);
+    let has_other = password.chars().any(|c| !c.is_alphabetic());
+    if !has_letter || !has_other {
+        return Err(validation_error(
+            "strength",
+            "Password must contain a letter and a digit or symbol".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// Rejects values that are only whitespace, which `length(min = 1)` lets through
+pub fn validate_not_blank(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(validation_error("blank", "Must not be blank".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    use crate::models::{MfaVerifyRequest, RegisterRequest};
+
+    async fn post(uri: &str, body: &str) -> (StatusCode, serde_json::Value) {
+        let app = init_service(
+            App::new()
+                .app_data(json_config())
+                .route("/register", web::post().to(|_: ValidatedJson<RegisterRequest>| async { HttpResponse::NoContent().finish() }))
+                .route("/mfa", web::post().to(|_: ValidatedJson<MfaVerifyRequest>| async { HttpResponse::NoContent().finish() })),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri(uri)
+            .insert_header(("content-type", "application/json"))
+            .set_payload(body.to_string())
+            .to_request();
+        let res = call_service(&app, req).await;
+        let status = res.status();
+        let body = read_body(res).await;
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    #[actix_web::test]
+    async fn accepts_valid_bodies() {
+        let (status, _) = post("/register", r#"{"username":"ada","email":"ada@example.com","password":"hunter22"}"#).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = post("/mfa", r#"{"mfa_token":"t","code":"123456"}"#).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+
+    #[actix_web::test]
+    async fn lists_every_invalid_field() {
+        let (status, body) = post("/register", r#"{"username":"a b","email":"nope","password":"short"}"#).await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], "VALIDATION_FAILED");
+        let fields: Vec<&str> = body["details"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|detail| detail["field"].as_str().unwrap())
+            .collect();
+        assert_eq!(fields, ["email", "password", "username"]);
+    }
+
+    #[actix_web::test]
+    async fn rejects_empty_and_oversized_values() {
+        let (status, body) = post("/mfa", r#"{"mfa_token":"","code":"123456"}"#).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["details"][0]["message"], "MFA token is required");
+
+        let code = "1".repeat(33);
+        let (status, body) = post("/mfa", &format!(r#"{{"mfa_token":"t","code":"{}"}}"#, code)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["details"][0]["field"], "code");
+    }
+
+    #[actix_web::test]
+    async fn malformed_json_is_a_bad_request() {
+        let (status, body) = post("/mfa", r#"{"mfa_token":"t""#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "BAD_REQUEST");
+
+        let (status, _) = post("/mfa", r#"{"mfa_token":"t"}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn usernames() {
+        assert!(validate_username("ada.lovelace_1-2").is_ok());
+        assert!(validate_username("ab").is_err());
+        assert!(validate_username(&"a".repeat(MAX_USERNAME_LENGTH + 1)).is_err());
+        assert!(validate_username("ada/admin").is_err());
+        assert!(validate_username("adä").is_err());
+    }
+
+    #[test]
+    fn passwords() {
+        assert!(validate_password("hunter22").is_ok());
+        assert!(validate_password("letters only").is_ok());
+        assert!(validate_password("hunter2").is_err());
+        assert!(validate_password("lettersonly").is_err());
+        assert!(validate_password("12345678").is_err());
+        assert!(validate_password(&format!("a{}", "1".repeat(MAX_PASSWORD_LENGTH))).is_err());
+    }
+
+    #[test]
+    fn blank_values() {
+        assert!(validate_not_blank("x").is_ok());
+        assert!(validate_not_blank(" \t\n").is_err());
+    }
+}
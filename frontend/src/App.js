@@ -10,6 +10,7 @@ import ProtectedRoute from './components/ProtectedRoute';
 import Home from './pages/Home';
 import Login from './pages/Login';
 import Register from './pages/Register';
+import OidcCallback from './pages/OidcCallback';
 import Search from './pages/Search';
 import Playlist from './pages/Playlist';
 import Library from './pages/Library';
@@ -34,6 +35,7 @@ function App() {
       <Routes>
         <Route path="/login" element={!user ? <Login /> : <Navigate to="/" />} />
         <Route path="/register" element={!user ? <Register /> : <Navigate to="/" />} />
+        <Route path="/oidc/callback" element={<OidcCallback />} />
         
         <Route path="/" element={
           <ProtectedRoute>
@@ -8,6 +8,7 @@ import {
   FaMusic 
 } from 'react-icons/fa';
 import { usePlayer } from '../context/PlayerContext';
+import { musicService } from '../services/musicService';
 import '../styles/Player.css';
 
 const Player = () => {
@@ -59,8 +60,8 @@ const Player = () => {
       <div className="player-content">
         <div className="player-info">
           <div className="song-cover">
-            {currentSong.cover_art ? (
-              <img src={currentSong.cover_art} alt={currentSong.title} />
+            {currentSong.cover ? (
+              <img src={musicService.getCoverUrl(currentSong)} alt={currentSong.title} />
             ) : (
               <div className="cover-placeholder">
                 <FaMusic />
@@ -1,6 +1,7 @@
 import React from 'react';
 import { FaPlay, FaPause, FaMusic, FaPlus } from 'react-icons/fa';
 import { usePlayer } from '../context/PlayerContext';
+import { musicService } from '../services/musicService';
 import '../styles/SongList.css';
 
 const SongList = ({ songs, showAddToPlaylist = false, onAddToPlaylist }) => {
@@ -52,8 +53,8 @@ const SongList = ({ songs, showAddToPlaylist = false, onAddToPlaylist }) => {
 
               <div className="song-info">
                 <div className="song-cover">
-                  {song.cover_art ? (
-                    <img src={song.cover_art} alt={song.title} />
+                  {song.cover ? (
+                    <img src={musicService.getCoverUrl(song)} alt={song.title} />
                   ) : (
                     <div className="cover-placeholder">
                       <FaMusic />
@@ -25,6 +25,7 @@ export const AuthProvider = ({ children }) => {
         } catch (error) {
           console.error('Failed to get current user:', error);
           localStorage.removeItem('token');
+          localStorage.removeItem('refreshToken');
         }
       }
       setLoading(false);
@@ -36,7 +37,12 @@ export const AuthProvider = ({ children }) => {
   const login = async (email, password) => {
     try {
       const response = await authService.login(email, password);
+      // Accounts with two-factor auth need a second step before we get tokens
+      if (response.mfa_required) {
+        return response;
+      }
       localStorage.setItem('token', response.token);
+      localStorage.setItem('refreshToken', response.refresh_token);
       setUser(response.user);
       return response;
     } catch (error) {
@@ -44,10 +50,30 @@ export const AuthProvider = ({ children }) => {
     }
   };
 
+  const verifyMfa = async (mfaToken, code) => {
+    const response = await authService.verifyMfa(mfaToken, code);
+    localStorage.setItem('token', response.token);
+    localStorage.setItem('refreshToken', response.refresh_token);
+    setUser(response.user);
+    return response;
+  };
+
+  const completeOidc = async (state, code) => {
+    const response = await authService.completeOidc(state, code);
+    if (response.mfa_required) {
+      return response;
+    }
+    localStorage.setItem('token', response.token);
+    localStorage.setItem('refreshToken', response.refresh_token);
+    setUser(response.user);
+    return response;
+  };
+
   const register = async (username, email, password) => {
     try {
       const response = await authService.register(username, email, password);
       localStorage.setItem('token', response.token);
+      localStorage.setItem('refreshToken', response.refresh_token);
       setUser(response.user);
       return response;
     } catch (error) {
@@ -55,14 +81,20 @@ export const AuthProvider = ({ children }) => {
     }
   };
 
-  const logout = () => {
-    localStorage.removeItem('token');
+  const logout = async () => {
+    try {
+      await authService.logout();
+    } catch (error) {
+      console.error('Failed to revoke session:', error);
+    }
     setUser(null);
   };
 
   const value = {
     user,
     login,
+    verifyMfa,
+    completeOidc,
     register,
     logout,
     loading
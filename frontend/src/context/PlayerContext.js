@@ -1,4 +1,5 @@
 import React, { createContext, useContext, useState, useRef, useEffect } from 'react';
+import { musicService } from '../services/musicService';
 
 const PlayerContext = createContext();
 
@@ -49,11 +50,16 @@ export const PlayerProvider = ({ children }) => {
     audioRef.current.volume = volume;
   }, [volume]);
 
-  const playSong = (song, songList = []) => {
+  const playSong = async (song, songList = []) => {
     const audio = audioRef.current;
     
     if (currentSong?.id !== song.id) {
-      audio.src = `http://localhost:8080/uploads/${song.file_path}`;
+      try {
+        audio.src = await musicService.getStreamUrl(song.id);
+      } catch (error) {
+        console.error(error.message);
+        return;
+      }
       setCurrentSong(song);
       
       if (songList.length > 0) {
@@ -1,24 +1,53 @@
-import React, { useState } from 'react';
-import { Link, useNavigate } from 'react-router-dom';
+import React, { useEffect, useState } from 'react';
+import { Link, useLocation, useNavigate } from 'react-router-dom';
 import { useAuth } from '../context/AuthContext';
+import { authService } from '../services/authService';
 import '../styles/Auth.css';
 
 const Login = () => {
   const [email, setEmail] = useState('');
   const [password, setPassword] = useState('');
+  const location = useLocation();
+  // Single sign-on lands back here when the account also has two-factor auth
+  const [mfaToken, setMfaToken] = useState(location.state?.mfaToken || null);
+  const [code, setCode] = useState('');
   const [error, setError] = useState('');
   const [loading, setLoading] = useState(false);
+  const [providers, setProviders] = useState([]);
   
-  const { login } = useAuth();
+  const { login, verifyMfa } = useAuth();
   const navigate = useNavigate();
 
+  useEffect(() => {
+    authService.getOidcProviders().then(setProviders);
+  }, []);
+
+  const handleSso = async (provider) => {
+    setError('');
+    setLoading(true);
+    try {
+      window.location.href = await authService.startOidc(provider);
+    } catch (error) {
+      setError(error.message);
+      setLoading(false);
+    }
+  };
+
   const handleSubmit = async (e) => {
     e.preventDefault();
     setError('');
     setLoading(true);
 
     try {
-      await login(email, password);
+      if (mfaToken) {
+        await verifyMfa(mfaToken, code);
+      } else {
+        const response = await login(email, password);
+        if (response.mfa_required) {
+          setMfaToken(response.mfa_token);
+          return;
+        }
+      }
       navigate('/');
     } catch (error) {
       setError(error.message);
@@ -38,6 +67,23 @@ const Login = () => {
         {error && <div className="error-message">{error}</div>}
 
         <form onSubmit={handleSubmit} className="auth-form">
+          {mfaToken ? (
+          <div className="form-group">
+            <label htmlFor="code">Authentication code</label>
+            <input
+              type="text"
+              id="code"
+              inputMode="numeric"
+              autoComplete="one-time-code"
+              placeholder="6-digit code or recovery code"
+              value={code}
+              onChange={(e) => setCode(e.target.value)}
+              required
+              disabled={loading}
+            />
+          </div>
+          ) : (
+          <>
           <div className="form-group">
             <label htmlFor="email">Email</label>
             <input
@@ -61,16 +107,30 @@ const Login = () => {
               disabled={loading}
             />
           </div>
+          </>
+          )}
 
           <button 
             type="submit" 
             className="auth-button"
             disabled={loading}
           >
-            {loading ? 'Logging in...' : 'Log In'}
+            {loading ? 'Logging in...' : mfaToken ? 'Verify' : 'Log In'}
           </button>
         </form>
 
+        {!mfaToken && providers.map((provider) => (
+          <button
+            key={provider}
+            type="button"
+            className="auth-button sso-button"
+            onClick={() => handleSso(provider)}
+            disabled={loading}
+          >
+            Continue with {provider}
+          </button>
+        ))}
+
         <div className="auth-footer">
           <p>
             Don't have an account? <Link to="/register">Sign up</Link>
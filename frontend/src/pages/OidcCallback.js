@@ -0,0 +1,61 @@
+import React, { useEffect, useRef, useState } from 'react';
+import { Link, useNavigate, useSearchParams } from 'react-router-dom';
+import { useAuth } from '../context/AuthContext';
+import '../styles/Auth.css';
+
+// The identity provider redirects here with ?code=...&state=...
+const OidcCallback = () => {
+  const [searchParams] = useSearchParams();
+  const [error, setError] = useState('');
+  const { completeOidc } = useAuth();
+  const navigate = useNavigate();
+  // Codes are single use, so don't redeem twice under StrictMode
+  const started = useRef(false);
+
+  useEffect(() => {
+    if (started.current) {
+      return;
+    }
+    started.current = true;
+
+    const providerError = searchParams.get('error_description') || searchParams.get('error');
+    const code = searchParams.get('code');
+    const state = searchParams.get('state');
+
+    if (providerError || !code || !state) {
+      setError(providerError || 'Missing sign-in response');
+      return;
+    }
+
+    completeOidc(state, code)
+      .then((response) => {
+        if (response.mfa_required) {
+          navigate('/login', { replace: true, state: { mfaToken: response.mfa_token } });
+        } else {
+          navigate('/', { replace: true });
+        }
+      })
+      .catch((error) => setError(error.message));
+  }, [searchParams, completeOidc, navigate]);
+
+  return (
+    <div className="auth-container">
+      <div className="auth-card">
+        <div className="auth-header">
+          <h1>Spotify Clone</h1>
+          <h2>{error ? 'Sign-in failed' : 'Signing you in...'}</h2>
+        </div>
+
+        {error && <div className="error-message">{error}</div>}
+
+        <div className="auth-footer">
+          <p>
+            <Link to="/login">Back to log in</Link>
+          </p>
+        </div>
+      </div>
+    </div>
+  );
+};
+
+export default OidcCallback;
@@ -23,8 +23,13 @@ const Register = () => {
       return;
     }
 
-    if (password.length < 6) {
-      setError('Password must be at least 6 characters long');
+    if (password.length < 8) {
+      setError('Password must be at least 8 characters long');
+      return;
+    }
+
+    if (!/[^a-zA-Z]/.test(password) || !/[a-zA-Z]/.test(password)) {
+      setError('Password must contain a letter and a digit or symbol');
       return;
     }
 
@@ -84,7 +89,7 @@ const Register = () => {
               onChange={(e) => setPassword(e.target.value)}
               required
               disabled={loading}
-              minLength="6"
+              minLength="8"
             />
           </div>
 
@@ -1,6 +1,6 @@
 import axios from 'axios';
 
-const API_BASE_URL = process.env.REACT_APP_API_URL
+export const API_BASE_URL = process.env.REACT_APP_API_URL
   ? `${process.env.REACT_APP_API_URL}/api`
   : 'http://localhost:8080/api';
 
@@ -21,13 +21,43 @@ api.interceptors.request.use((config) => {
   return config;
 });
 
+const clearSession = () => {
+  localStorage.removeItem('token');
+  localStorage.removeItem('refreshToken');
+};
+
+// Share a single refresh request between concurrent 401s
+let refreshPromise = null;
+
+const refreshTokens = async () => {
+  const refreshToken = localStorage.getItem('refreshToken');
+  if (!refreshToken) {
+    throw new Error('No refresh token');
+  }
+  const response = await axios.post(`${API_BASE_URL}/auth/refresh`, { refresh_token: refreshToken });
+  localStorage.setItem('token', response.data.token);
+  localStorage.setItem('refreshToken', response.data.refresh_token);
+  return response.data.token;
+};
+
 // Handle token expiration
 api.interceptors.response.use(
   (response) => response,
-  (error) => {
-    if (error.response?.status === 401) {
-      localStorage.removeItem('token');
-      window.location.href = '/login';
+  async (error) => {
+    const original = error.config;
+    if (error.response?.status === 401 && original && !original._retry && !original.url?.startsWith('/auth/')) {
+      original._retry = true;
+      try {
+        refreshPromise = refreshPromise || refreshTokens();
+        const token = await refreshPromise;
+        original.headers.Authorization = `Bearer ${token}`;
+        return api(original);
+      } catch (refreshError) {
+        clearSession();
+        window.location.href = '/login';
+      } finally {
+        refreshPromise = null;
+      }
     }
     return Promise.reject(error);
   }
@@ -43,6 +73,42 @@ export const authService = {
     }
   },
 
+  async verifyMfa(mfaToken, code) {
+    try {
+      const response = await api.post('/auth/mfa/verify', { mfa_token: mfaToken, code });
+      return response.data;
+    } catch (error) {
+      throw new Error(error.response?.data?.error || 'Verification failed');
+    }
+  },
+
+  async getOidcProviders() {
+    try {
+      const response = await api.get('/auth/oidc/providers');
+      return response.data.providers;
+    } catch (error) {
+      return [];
+    }
+  },
+
+  async startOidc(provider) {
+    try {
+      const response = await api.post(`/auth/oidc/${encodeURIComponent(provider)}/authorize`);
+      return response.data.authorization_url;
+    } catch (error) {
+      throw new Error(error.response?.data?.error || 'Single sign-on is unavailable');
+    }
+  },
+
+  async completeOidc(state, code) {
+    try {
+      const response = await api.post('/auth/oidc/callback', { state, code });
+      return response.data;
+    } catch (error) {
+      throw new Error(error.response?.data?.error || 'Single sign-on failed');
+    }
+  },
+
   async register(username, email, password) {
     try {
       const response = await api.post('/auth/register', { username, email, password });
@@ -52,6 +118,17 @@ export const authService = {
     }
   },
 
+  async logout() {
+    const refreshToken = localStorage.getItem('refreshToken');
+    try {
+      if (refreshToken) {
+        await api.post('/auth/logout', { refresh_token: refreshToken });
+      }
+    } finally {
+      clearSession();
+    }
+  },
+
   async getCurrentUser() {
     try {
       const response = await api.get('/users/me');
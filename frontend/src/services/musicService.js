@@ -1,4 +1,4 @@
-import api from './authService';
+import api, { API_BASE_URL } from './authService';
 
 export const musicService = {
   async getAllSongs() {
@@ -28,6 +28,23 @@ export const musicService = {
     }
   },
 
+  // <audio> can't send the Authorization header, so playback goes through a
+  // short-lived URL that only works for this song
+  async getStreamUrl(id) {
+    try {
+      const response = await api.post(`/songs/${id}/stream-token`);
+      return `${API_BASE_URL}/songs/${id}/stream?token=${encodeURIComponent(response.data.token)}`;
+    } catch (error) {
+      throw new Error(error.response?.data?.error || 'Failed to start playback');
+    }
+  },
+
+  // Cover URLs are paths on the API server, e.g. /api/covers/{id}/300.jpg
+  getCoverUrl(song, size = 'small') {
+    const image = song.cover?.[size];
+    return image ? `${API_BASE_URL.replace(/\/api$/, '')}${image.jpeg}` : null;
+  },
+
   async uploadSong(formData) {
     try {
       const response = await api.post('/songs/upload', formData, {